@@ -0,0 +1,126 @@
+//! Runs a JSONL transcript through the bot without connecting to Discord, printing what the bot
+//! would have sent and the stats it ends up with.
+//!
+//! Each line is a message, like
+//! `{"guild": 420, "channel": 1, "author": 10, "timestamp": "2024-04-20T20:20:05Z", "content": "weed time"}`.
+//! The transcript is read from the file given, or from stdin.
+
+use std::{
+    env,
+    error::Error,
+    fs::File,
+    io::{self, BufRead, BufReader},
+};
+
+use serenity::all::{ChannelId, CreateEmbed};
+use weedtime_bot::{Sent, Simulation, TranscriptMessage};
+
+fn print_sent(sent: &[Sent]) {
+    for sent in sent {
+        match sent {
+            Sent::Message {
+                channel_id,
+                message_id,
+                content,
+                image,
+            } => {
+                let image = image
+                    .as_ref()
+                    .map_or_else(String::new, |image| format!(" [{image}]"));
+                println!("  bot in #{channel_id} ({message_id}): {content}{image}");
+            }
+            Sent::Edit {
+                channel_id,
+                message_id,
+                content,
+            } => println!("  bot in #{channel_id} edits {message_id}: {content}"),
+            Sent::Delete {
+                channel_id,
+                message_id,
+            } => println!("  bot in #{channel_id} deletes {message_id}"),
+            Sent::Embed { channel_id, embed } => print_embed(*channel_id, embed),
+            Sent::Response { interaction_id, .. } => {
+                println!("  bot answers interaction {interaction_id}");
+            }
+            Sent::Commands(count) => println!("  bot registers {count} commands"),
+        }
+    }
+}
+
+fn print_embed(channel_id: ChannelId, embed: &CreateEmbed) {
+    // Embeds can only be read back through what they would be sent as.
+    let embed = serde_json::to_value(embed).unwrap_or_default();
+    let text = |value: &serde_json::Value| value.as_str().unwrap_or_default().to_string();
+
+    println!("  bot in #{channel_id}: {}", text(&embed["title"]));
+    if let Some(description) = embed["description"].as_str() {
+        println!("    {description}");
+    }
+    for field in embed["fields"].as_array().into_iter().flatten() {
+        println!("    {}: {}", text(&field["name"]), text(&field["value"]));
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    // Only the bot's warnings make it out, on stderr, out of the way of what it would have sent.
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::WARN)
+        .with_writer(io::stderr)
+        .init();
+
+    let reader: Box<dyn BufRead> = match env::args().nth(1) {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+
+    let mut sim = Simulation::new()?;
+    for (number, line) in (1..).zip(reader.lines()) {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: TranscriptMessage = serde_json::from_str(&line)
+            .map_err(|e| format!("line {number} isn't a transcript message: {e}"))?;
+
+        println!(
+            "[{}] #{} <@{}>: {}",
+            message.timestamp, message.channel, message.author, message.content
+        );
+        print_sent(&sim.send(message).await);
+    }
+    print_sent(&sim.finish().await);
+
+    println!();
+    println!("Guild stats:");
+    for stats in sim.guild_stats()? {
+        println!(
+            "  {}: {} weed times, {} weed crimes, longest chain {}, best streak {}",
+            stats.id(),
+            stats.weed_times,
+            stats.weed_crimes,
+            stats.longest_chain,
+            stats.best_streak
+        );
+    }
+
+    println!("User stats:");
+    for stats in sim.user_stats()? {
+        let guild = stats.guild_id().map_or_else(
+            || "direct messages".to_string(),
+            |guild_id| guild_id.to_string(),
+        );
+        println!(
+            "  <@{}> in {guild}: {} weed times, {} weed crimes, {} chains started, {} chains broken, longest chain {}, best streak {}",
+            stats.id(),
+            stats.weed_times,
+            stats.weed_crimes,
+            stats.chains_started,
+            stats.chains_broken,
+            stats.longest_chain,
+            stats.best_streak
+        );
+    }
+
+    Ok(())
+}
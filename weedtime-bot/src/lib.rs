@@ -0,0 +1,208 @@
+mod weedtime;
+
+pub use crate::weedtime::{
+    sim::{Simulation, TranscriptMessage},
+    transport::Sent,
+};
+
+use std::{env, error::Error, fs, path::Path, sync::Arc};
+
+use serenity::{
+    Client,
+    all::{ChannelId, Context, GatewayIntents, GuildId, MessageId, UserId},
+    prelude::{RwLock, TypeMap, TypeMapKey},
+};
+use tracing::info;
+use weedtime_db::data::{GuildConfig, UserConfig, WeedDatabase, backup};
+use whirlwind::ShardMap;
+
+use crate::weedtime::{
+    chain::Chain,
+    clock::{Clock, SystemClock},
+    handler::{ChannelJob, Handler},
+    scheduler::{WindowSchedule, run_scheduler},
+    transport::{SerenityTransport, Transport},
+    triggers::TriggerSet,
+};
+
+#[derive(Debug, Default)]
+struct WeedTimeMessage {
+    /// The bot's answer to the last weed time in the chain.
+    reply: Option<MessageId>,
+    chain: Option<Chain>,
+    guild_id: Option<GuildId>,
+    /// The guild's longest chain from before the running one started, which it has to beat to be
+    /// the new server record. `None` if it isn't known, when no server record is given out.
+    server_record: Option<u32>,
+}
+
+struct MessageCount;
+
+impl TypeMapKey for MessageCount {
+    type Value = Arc<ShardMap<ChannelId, WeedTimeMessage>>;
+}
+
+/// What time the bot goes by.
+struct BotClock;
+
+impl TypeMapKey for BotClock {
+    type Value = Arc<dyn Clock>;
+}
+
+/// The chains waiting for their window to close.
+struct ChainWindows;
+
+impl TypeMapKey for ChainWindows {
+    type Value = Arc<WindowSchedule>;
+}
+
+struct TriggerCache;
+
+impl TypeMapKey for TriggerCache {
+    type Value = Arc<ShardMap<GuildId, Arc<TriggerSet>>>;
+}
+
+struct GuildConfigCache;
+
+impl TypeMapKey for GuildConfigCache {
+    type Value = Arc<ShardMap<GuildId, Arc<GuildConfig>>>;
+}
+
+struct UserConfigCache;
+
+impl TypeMapKey for UserConfigCache {
+    type Value = Arc<ShardMap<UserId, Arc<UserConfig>>>;
+}
+
+/// The caches and the rest of what every handler shares, empty to start with.
+fn shared_data(clock: Arc<dyn Clock>, schedule: Arc<WindowSchedule>) -> TypeMap {
+    let mut data = TypeMap::new();
+    data.insert::<MessageCount>(Arc::new(ShardMap::new()));
+    data.insert::<BotClock>(clock);
+    data.insert::<ChainWindows>(schedule);
+    data.insert::<TriggerCache>(Arc::new(ShardMap::new()));
+    data.insert::<GuildConfigCache>(Arc::new(ShardMap::new()));
+    data.insert::<UserConfigCache>(Arc::new(ShardMap::new()));
+    data
+}
+
+/// What handling an event takes besides the event itself: the caches, and the way out to
+/// Discord.
+#[derive(Clone)]
+struct Bot {
+    data: Arc<RwLock<TypeMap>>,
+    transport: Arc<dyn Transport>,
+}
+
+impl Bot {
+    fn from_context(ctx: &Context) -> Self {
+        Self {
+            data: ctx.data.clone(),
+            transport: Arc::new(SerenityTransport::new(ctx.http.clone(), ctx.cache.clone())),
+        }
+    }
+}
+
+fn open_or_create_database() -> Result<WeedDatabase<'static>, Box<dyn Error>> {
+    let db_path = env::var("WEEDTIME_DB_PATH").unwrap_or_else(|_| "data/weedtime.db".to_string());
+    let path = Path::new(&db_path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if path.exists() {
+        let db = WeedDatabase::open(path)?;
+        if db.needs_migration()? {
+            // Keep a copy of the file as the old version left it, in case the upgrade goes wrong.
+            drop(db);
+            let backup_path = backup(path)?;
+            info!(
+                "Backed up {db_path} to {}, migrating",
+                backup_path.display()
+            );
+
+            let db = WeedDatabase::open(path)?;
+            db.migrate()?;
+            return Ok(db);
+        }
+        return Ok(db);
+    }
+
+    let db = WeedDatabase::create(path)?;
+
+    // Stats used to be kept in a separate file for users and one for guilds.
+    let user_db_path =
+        env::var("WEEDTIME_USER_DB_PATH").unwrap_or_else(|_| "data/user-stats.db".to_string());
+    let guild_db_path =
+        env::var("WEEDTIME_GUILD_DB_PATH").unwrap_or_else(|_| "data/guild-stats.db".to_string());
+
+    if Path::new(&user_db_path).exists() || Path::new(&guild_db_path).exists() {
+        info!("Importing {user_db_path} and {guild_db_path} into {db_path}");
+
+        if let Err(e) = db.import_split_databases(&user_db_path, &guild_db_path) {
+            // Don't leave a half-created database behind, or the import would be skipped on the
+            // next start.
+            drop(db);
+            fs::remove_file(path)?;
+            return Err(e.into());
+        }
+    }
+
+    Ok(db)
+}
+
+/// Logs in with the token in `DISCORD_TOKEN` and runs the bot until the client stops.
+pub async fn run() {
+    // Configure the client with your Discord bot token in the environment.
+    let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
+    let db = Arc::new(open_or_create_database().expect("Err creating database"));
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    // Set gateway intents, which decides what events the bot will be notified about. Guilds keep
+    // the cache's channels and threads up to date, for telling which channels are watched.
+    let intents = GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::DIRECT_MESSAGES
+        | GatewayIntents::MESSAGE_CONTENT;
+
+    // Create a new instance of the Client, logging in as a bot. This will be automatically prepend
+    // your bot token with "Bot ", which is a requirement by Discord for bot users.
+    let schedule = Arc::new(WindowSchedule::new());
+    let handler = Handler::new(db.clone(), clock.clone());
+    let queues = handler.queues();
+    let mut client = Client::builder(&token, intents)
+        .type_map(shared_data(clock.clone(), schedule.clone()))
+        .event_handler(handler)
+        .await
+        .expect("Err creating client");
+
+    let bot = Bot {
+        data: client.data.clone(),
+        transport: Arc::new(SerenityTransport::new(
+            client.http.clone(),
+            client.cache.clone(),
+        )),
+    };
+    // Windows are closed from the channel's queue, after the messages sent before they closed.
+    tokio::spawn(run_scheduler(
+        clock.clone(),
+        schedule,
+        move |channel_id, now| {
+            let job = ChannelJob::CloseWindow {
+                bot: bot.clone(),
+                db: db.clone(),
+                channel_id,
+                now,
+            };
+            queues.push(channel_id, now, None, clock.now(), job);
+            async {}
+        },
+    ));
+
+    // Finally, start a single shard, and start listening to events.
+    //
+    // Shards will automatically attempt to reconnect, and will preform exponential backoff until
+    // it reconnects.
+    if let Err(why) = client.start().await {
+        println!("Client error: {why:?}");
+    }
+}
@@ -0,0 +1,595 @@
+//! How weed time chains work, kept apart from Discord so every case can be tested. The engine
+//! only decides what a message does to its channel's chain and what it counts for; posting the
+//! replies and saving the stats is left to [`states`](super::states).
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serenity::all::{ChannelId, GuildId, UserId};
+use weedtime_db::data::{GuildStatsUpdate, UserStatsUpdate, WindowMatch};
+
+/// A chain running in a channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    /// Everyone in the chain, in the order they joined it.
+    pub users: Vec<UserId>,
+    /// When the window the chain is running in opened.
+    pub window_start: DateTime<Utc>,
+    /// When the window the chain is running in closes.
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Chain {
+    pub fn count(&self) -> u32 {
+        self.users.len() as u32
+    }
+
+    /// Whether the chain can still be added to at `timestamp`.
+    pub fn is_active(&self, timestamp: DateTime<Utc>) -> bool {
+        timestamp < self.expires_at
+    }
+
+    /// Whether the chain is running in `window` at `timestamp`.
+    fn runs_in(&self, window: &WindowMatch, timestamp: DateTime<Utc>) -> bool {
+        self.expires_at == window.end && self.is_active(timestamp)
+    }
+
+    /// Ends the chain at `timestamp`. A chain whose window had already closed ended then, and
+    /// wasn't broken by anyone.
+    fn end(self, broken_by: Option<UserId>, timestamp: DateTime<Utc>) -> EndedChain {
+        if self.is_active(timestamp) {
+            EndedChain {
+                chain: self,
+                broken_by,
+                ended_at: timestamp,
+            }
+        } else {
+            EndedChain {
+                ended_at: self.expires_at,
+                chain: self,
+                broken_by: None,
+            }
+        }
+    }
+}
+
+/// A chain that a message brought to an end, or found had ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndedChain {
+    pub chain: Chain,
+    /// Whoever broke the chain, or `None` if it lasted until its window closed.
+    pub broken_by: Option<UserId>,
+    pub ended_at: DateTime<Utc>,
+}
+
+/// A message as far as chains are concerned.
+#[derive(Debug, Clone)]
+pub struct ChainEvent {
+    pub channel_id: ChannelId,
+    pub guild_id: Option<GuildId>,
+    pub user_id: UserId,
+    pub timestamp: DateTime<Utc>,
+    /// The guild's timezone, which decides the day a weed time counts towards.
+    pub timezone: Tz,
+    /// The window the message was sent in, if it was weed time anywhere it's checked.
+    pub window: Option<WindowMatch>,
+    pub contains_weed_time: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// A weed time with no chain to join, which starts one.
+    Start,
+    /// A weed time by someone new to the chain.
+    Continue,
+    /// A weed time by someone already in the chain, which breaks it and starts a new one.
+    Restart,
+    /// A message during weed time without a trigger, which ends the chain.
+    Break,
+    /// A trigger outside of weed time.
+    Crime,
+    /// A message that has nothing to do with weed time.
+    Ignore,
+}
+
+/// What a message did, and what it counts for.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub decision: Decision,
+    pub user: UserStatsUpdate,
+    pub guild: GuildStatsUpdate,
+    /// Where the message landed in its chain, for weed times.
+    pub chain_position: Option<u32>,
+    /// The chain the message replaced or broke, if there was one.
+    pub ended: Option<EndedChain>,
+}
+
+/// Applies `event` to the chain running in its channel, if any, and returns what it did.
+pub fn step(chain: &mut Option<Chain>, event: &ChainEvent) -> Outcome {
+    let mut user = UserStatsUpdate::new(event.user_id, event.guild_id);
+    let mut guild = event
+        .guild_id
+        .map(GuildStatsUpdate::new)
+        .unwrap_or_default();
+
+    let window = match (&event.window, event.contains_weed_time) {
+        (Some(window), true) => window,
+        // Only a chain running in the window can be broken. Guilds that count weed time
+        // anywhere are in some window every quarter of an hour, with nothing to break.
+        (Some(window), false)
+            if chain
+                .as_ref()
+                .is_some_and(|chain| chain.runs_in(window, event.timestamp)) =>
+        {
+            let ended = chain
+                .take()
+                .map(|chain| chain.end(Some(event.user_id), event.timestamp));
+            user.chains_broken += 1;
+            return Outcome {
+                decision: Decision::Break,
+                user,
+                guild: GuildStatsUpdate::default(),
+                chain_position: None,
+                ended,
+            };
+        }
+        (None, true) => {
+            user.weed_crimes += 1;
+            guild.weed_crimes += 1;
+            return Outcome {
+                decision: Decision::Crime,
+                user,
+                guild,
+                chain_position: None,
+                ended: None,
+            };
+        }
+        (_, false) => {
+            return Outcome {
+                decision: Decision::Ignore,
+                user,
+                guild,
+                chain_position: None,
+                ended: None,
+            };
+        }
+    };
+
+    // A chain only continues with messages in the window it started in, which may be in another
+    // timezone than the guild's.
+    let running = chain
+        .as_mut()
+        .filter(|chain| chain.runs_in(window, event.timestamp));
+    let decision = match running {
+        Some(running) if !running.users.contains(&event.user_id) => {
+            running.users.push(event.user_id);
+            Decision::Continue
+        }
+        Some(_) => Decision::Restart,
+        None => Decision::Start,
+    };
+    let mut ended = None;
+    if decision != Decision::Continue {
+        let broken_by = (decision == Decision::Restart).then_some(event.user_id);
+        ended = chain
+            .replace(Chain {
+                users: vec![event.user_id],
+                window_start: window.start,
+                expires_at: window.end,
+            })
+            .map(|old| old.end(broken_by, event.timestamp));
+    }
+    let count = chain.as_ref().map_or(1, Chain::count);
+
+    // Every weed time counts towards the day's streak, whether or not it continues a chain.
+    let weed_day = event.timestamp.with_timezone(&event.timezone).date_naive();
+    user.weed_times += 1;
+    guild.weed_times += 1;
+    user.weed_day = Some(weed_day);
+    guild.weed_day = Some(weed_day);
+    user.longest_chain = Some(count);
+    guild.longest_chain = Some(count);
+    if decision != Decision::Continue {
+        user.chains_started += 1;
+    }
+    if decision == Decision::Restart {
+        user.chains_broken += 1;
+    }
+
+    Outcome {
+        decision,
+        user,
+        guild,
+        chain_position: Some(count),
+        ended,
+    }
+}
+
+/// Ends the chain once its window has closed by `now`, as lasting the window.
+pub fn close(chain: &mut Option<Chain>, now: DateTime<Utc>) -> Option<EndedChain> {
+    chain
+        .take_if(|chain| !chain.is_active(now))
+        .map(|chain| chain.end(None, now))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime, TimeZone};
+    use chrono_tz::{
+        America::New_York,
+        Asia::{Kathmandu, Kolkata},
+        Europe::London,
+    };
+    use weedtime_db::data::v4::WeedWindow;
+
+    use super::*;
+
+    const CHANNEL: ChannelId = ChannelId::new(1);
+    const GUILD: GuildId = GuildId::new(2);
+
+    fn user(id: u64) -> UserId {
+        UserId::new(id)
+    }
+
+    fn local(timezone: Tz, date: &str, time: &str) -> DateTime<Utc> {
+        let naive =
+            NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%Y-%m-%d %H:%M:%S").unwrap();
+        timezone
+            .from_local_datetime(&naive)
+            .earliest()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn event_in(
+        timezones: &[Tz],
+        user_id: UserId,
+        timestamp: DateTime<Utc>,
+        contains_weed_time: bool,
+    ) -> ChainEvent {
+        ChainEvent {
+            channel_id: CHANNEL,
+            guild_id: Some(GUILD),
+            user_id,
+            timestamp,
+            timezone: timezones[0],
+            window: WeedWindow::default().find(timestamp, timezones),
+            contains_weed_time,
+        }
+    }
+
+    fn weed_time(user_id: UserId, timestamp: DateTime<Utc>) -> ChainEvent {
+        event_in(&[New_York], user_id, timestamp, true)
+    }
+
+    fn decisions(chain: &mut Option<Chain>, events: &[ChainEvent]) -> Vec<Decision> {
+        events
+            .iter()
+            .map(|event| step(chain, event).decision)
+            .collect()
+    }
+
+    #[test]
+    fn starts_and_continues_chains() {
+        let mut chain = None;
+        let at = local(New_York, "2024-04-20", "16:20:05");
+
+        let first = step(&mut chain, &weed_time(user(1), at));
+        assert_eq!(first.decision, Decision::Start);
+        assert_eq!(first.chain_position, Some(1));
+        assert_eq!(first.user.weed_times, 1);
+        assert_eq!(first.user.chains_started, 1);
+        assert_eq!(first.user.longest_chain, Some(1));
+        assert_eq!(first.guild.guild_id, Some(GUILD));
+        assert_eq!(first.guild.weed_times, 1);
+
+        let second = step(&mut chain, &weed_time(user(2), at));
+        assert_eq!(second.decision, Decision::Continue);
+        assert_eq!(second.chain_position, Some(2));
+        assert_eq!(second.user.chains_started, 0);
+        assert_eq!(second.user.longest_chain, Some(2));
+        assert_eq!(second.guild.longest_chain, Some(2));
+
+        let chain = chain.as_ref().unwrap();
+        assert_eq!(chain.users, [user(1), user(2)]);
+        assert_eq!(chain.expires_at, local(New_York, "2024-04-20", "16:21:00"));
+    }
+
+    #[test]
+    fn restarts_on_duplicate_users() {
+        let mut chain = None;
+        let at = local(New_York, "2024-04-20", "16:20:10");
+
+        let outcomes: Vec<_> = [1, 2, 1, 2, 3]
+            .into_iter()
+            .map(|id| step(&mut chain, &weed_time(user(id), at)))
+            .collect();
+
+        let decisions: Vec<_> = outcomes.iter().map(|outcome| outcome.decision).collect();
+        assert_eq!(
+            decisions,
+            [
+                Decision::Start,
+                Decision::Continue,
+                Decision::Restart,
+                Decision::Continue,
+                Decision::Continue,
+            ]
+        );
+        let positions: Vec<_> = outcomes.iter().filter_map(|o| o.chain_position).collect();
+        assert_eq!(positions, [1, 2, 1, 2, 3]);
+
+        let restart = &outcomes[2];
+        let ended = restart.ended.as_ref().unwrap();
+        assert_eq!(ended.chain.users, [user(1), user(2)]);
+        assert_eq!(ended.broken_by, Some(user(1)));
+        assert_eq!(ended.ended_at, at);
+        assert!(outcomes[0].ended.is_none());
+        assert!(outcomes[1].ended.is_none());
+        assert_eq!(restart.user.weed_times, 1);
+        assert_eq!(restart.user.chains_started, 1);
+        assert_eq!(restart.user.chains_broken, 1);
+        assert_eq!(restart.user.longest_chain, Some(1));
+        assert_eq!(chain.as_ref().unwrap().users, [user(1), user(2), user(3)]);
+    }
+
+    #[test]
+    fn the_same_user_twice_in_a_row_restarts() {
+        let mut chain = None;
+        let at = local(New_York, "2024-04-20", "04:20:00");
+
+        assert_eq!(
+            decisions(
+                &mut chain,
+                &[weed_time(user(1), at), weed_time(user(1), at)]
+            ),
+            [Decision::Start, Decision::Restart]
+        );
+        assert_eq!(chain.as_ref().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn breaks_on_messages_without_a_trigger() {
+        let mut chain = None;
+        let at = local(New_York, "2024-04-20", "16:20:30");
+
+        step(&mut chain, &weed_time(user(1), at));
+        let broken = step(&mut chain, &event_in(&[New_York], user(2), at, false));
+        assert_eq!(broken.decision, Decision::Break);
+        assert_eq!(broken.user.chains_broken, 1);
+        assert_eq!(broken.user.weed_times, 0);
+        assert_eq!(broken.guild.guild_id, None);
+        let ended = broken.ended.unwrap();
+        assert_eq!(ended.chain.users, [user(1)]);
+        assert_eq!(ended.broken_by, Some(user(2)));
+        assert!(chain.is_none());
+
+        // The chain is gone, so the next weed time starts over even in the same window.
+        let next = step(&mut chain, &weed_time(user(1), at));
+        assert_eq!(next.decision, Decision::Start);
+        assert_eq!(next.user.chains_broken, 0);
+    }
+
+    #[test]
+    fn only_breaks_chains_running_in_the_window() {
+        let mut chain = None;
+        let anywhere = WeedWindow {
+            anywhere: true,
+            ..WeedWindow::default()
+        };
+        // It's 4:20 PM in Kathmandu, but nobody there has started a chain.
+        let at = Utc.with_ymd_and_hms(2024, 4, 20, 10, 35, 0).unwrap();
+        let event = ChainEvent {
+            window: anywhere.find(at, &[New_York]),
+            ..event_in(&[New_York], user(1), at, false)
+        };
+        assert!(event.window.is_some());
+
+        let chatter = step(&mut chain, &event);
+        assert_eq!(chatter.decision, Decision::Ignore);
+        assert_eq!(chatter.user.chains_broken, 0);
+
+        // Once someone there does, the same message breaks it.
+        step(&mut chain, &event_in(&[Kathmandu], user(2), at, true));
+        let broken = step(&mut chain, &event);
+        assert_eq!(broken.decision, Decision::Break);
+        assert_eq!(broken.user.chains_broken, 1);
+        assert_eq!(broken.ended.unwrap().chain.users, [user(2)]);
+    }
+
+    #[test]
+    fn crimes_and_other_messages_leave_the_chain_alone() {
+        let mut chain = None;
+        let at = local(New_York, "2024-04-20", "16:20:30");
+        let later = local(New_York, "2024-04-20", "16:30:00");
+
+        step(&mut chain, &weed_time(user(1), at));
+        let crime = step(&mut chain, &weed_time(user(2), later));
+        assert_eq!(crime.decision, Decision::Crime);
+        assert_eq!(crime.user.weed_crimes, 1);
+        assert_eq!(crime.guild.weed_crimes, 1);
+        assert_eq!(crime.user.weed_times, 0);
+        assert_eq!(crime.user.weed_day, None);
+        assert_eq!(crime.chain_position, None);
+
+        let chatter = step(&mut chain, &event_in(&[New_York], user(3), later, false));
+        assert_eq!(chatter.decision, Decision::Ignore);
+        assert_eq!(chatter.user.chains_broken, 0);
+
+        assert_eq!(chain.as_ref().unwrap().users, [user(1)]);
+    }
+
+    #[test]
+    fn hour_rollover_ends_the_window() {
+        let mut chain = None;
+
+        assert_eq!(
+            decisions(
+                &mut chain,
+                &[
+                    weed_time(user(1), local(New_York, "2024-04-20", "16:20:59")),
+                    weed_time(user(2), local(New_York, "2024-04-20", "16:21:00")),
+                ]
+            ),
+            [Decision::Start, Decision::Crime]
+        );
+
+        // A chain from the morning doesn't carry into the afternoon, even for its own users.
+        let morning = local(New_York, "2024-04-20", "04:20:00");
+        let afternoon = local(New_York, "2024-04-20", "16:20:00");
+        let mut chain = None;
+        step(&mut chain, &weed_time(user(1), morning));
+        step(&mut chain, &weed_time(user(2), morning));
+        let next = step(&mut chain, &weed_time(user(1), afternoon));
+        assert_eq!(next.decision, Decision::Start);
+        assert_eq!(next.user.chains_broken, 0);
+        assert_eq!(next.chain_position, Some(1));
+
+        // The morning chain is only found to be over now, but it ended when its window closed.
+        let ended = next.ended.unwrap();
+        assert_eq!(ended.chain.users, [user(1), user(2)]);
+        assert_eq!(ended.chain.window_start, morning);
+        assert_eq!(ended.broken_by, None);
+        assert_eq!(ended.ended_at, local(New_York, "2024-04-20", "04:21:00"));
+    }
+
+    #[test]
+    fn date_rollover_starts_a_new_chain_on_a_new_day() {
+        let mut chain = None;
+        let evening = local(New_York, "2024-04-19", "16:20:00");
+        let night = local(New_York, "2024-04-20", "04:20:00");
+
+        let first = step(&mut chain, &weed_time(user(1), evening));
+        let second = step(&mut chain, &weed_time(user(1), night));
+        assert_eq!(second.decision, Decision::Start);
+        assert_eq!(second.user.chains_broken, 0);
+        assert_eq!(
+            first.user.weed_day,
+            Some(NaiveDate::from_ymd_opt(2024, 4, 19).unwrap())
+        );
+        assert_eq!(
+            second.user.weed_day,
+            Some(NaiveDate::from_ymd_opt(2024, 4, 20).unwrap())
+        );
+    }
+
+    #[test]
+    fn weed_days_follow_the_guild_timezone() {
+        let mut chain = None;
+        // 4:20 PM in Kolkata is the morning of the same day in London, the guild's timezone.
+        let at = local(Kolkata, "2024-04-20", "16:20:00");
+        let outcome = step(&mut chain, &event_in(&[London, Kolkata], user(1), at, true));
+
+        assert_eq!(outcome.decision, Decision::Start);
+        assert_eq!(
+            outcome.user.weed_day,
+            Some(NaiveDate::from_ymd_opt(2024, 4, 20).unwrap())
+        );
+
+        // 4:20 AM in Kolkata is still the 19th in New York.
+        let at = local(Kolkata, "2024-04-20", "04:20:00");
+        let outcome = step(
+            &mut chain,
+            &event_in(&[New_York, Kolkata], user(2), at, true),
+        );
+        assert_eq!(outcome.decision, Decision::Start);
+        assert_eq!(
+            outcome.guild.weed_day,
+            Some(NaiveDate::from_ymd_opt(2024, 4, 19).unwrap())
+        );
+    }
+
+    #[test]
+    fn chains_follow_the_window_they_started_in() {
+        let mut chain = None;
+        let at = local(Kolkata, "2024-04-20", "16:20:00");
+
+        // Someone in Kolkata starts a chain, and someone else in the same guild going by New
+        // York time can join it because Kolkata is one of their timezones too.
+        step(&mut chain, &event_in(&[Kolkata], user(1), at, true));
+        let joined = step(
+            &mut chain,
+            &event_in(&[New_York, Kolkata], user(2), at, true),
+        );
+        assert_eq!(joined.decision, Decision::Continue);
+
+        // New York's window is a different one, so a weed time there starts over.
+        let ny = local(New_York, "2024-04-20", "04:20:00");
+        let moved = step(&mut chain, &event_in(&[New_York], user(3), ny, true));
+        assert_eq!(moved.decision, Decision::Start);
+    }
+
+    #[test]
+    fn handles_dst_changes() {
+        let mut chain = None;
+
+        // Clocks in New York went forward at 2 AM on 2024-03-10, so 4:20 AM came an hour early
+        // in UTC compared to the day before.
+        let before = local(New_York, "2024-03-09", "04:20:00");
+        let after = local(New_York, "2024-03-10", "04:20:00");
+        assert_eq!(after - before, chrono::Duration::hours(23));
+
+        let outcomes: Vec<_> = [before, after]
+            .into_iter()
+            .map(|at| step(&mut chain, &weed_time(user(1), at)))
+            .collect();
+        assert!(
+            outcomes
+                .iter()
+                .all(|outcome| outcome.decision == Decision::Start)
+        );
+        assert_eq!(
+            chain.as_ref().unwrap().expires_at,
+            local(New_York, "2024-03-10", "04:21:00")
+        );
+
+        // 4:20 AM standard time would be 5:20 AM on the day the clocks change, which isn't weed
+        // time anymore.
+        let stale = after + chrono::Duration::hours(1);
+        assert_eq!(
+            step(&mut chain, &weed_time(user(2), stale)).decision,
+            Decision::Crime
+        );
+
+        // And when they go back on 2024-11-03, 4:20 AM comes an hour later.
+        let mut chain = None;
+        let before = local(New_York, "2024-11-02", "16:20:00");
+        let after = local(New_York, "2024-11-03", "04:20:00");
+        assert_eq!(after - before, chrono::Duration::hours(13));
+        assert_eq!(
+            decisions(
+                &mut chain,
+                &[
+                    weed_time(user(1), before),
+                    weed_time(user(1), after),
+                    weed_time(user(2), after),
+                ]
+            ),
+            [Decision::Start, Decision::Start, Decision::Continue]
+        );
+    }
+
+    #[test]
+    fn closes_chains_once_their_window_has() {
+        let at = local(New_York, "2024-04-20", "16:20:10");
+        let mut chain = None;
+        step(&mut chain, &weed_time(user(1), at));
+        step(&mut chain, &weed_time(user(2), at));
+        let closes_at = chain.as_ref().unwrap().expires_at;
+
+        assert_eq!(
+            close(&mut chain, closes_at - chrono::Duration::seconds(1)),
+            None
+        );
+        assert!(chain.is_some());
+
+        let ended = close(&mut chain, closes_at + chrono::Duration::seconds(5)).unwrap();
+        assert_eq!(ended.chain.users, [user(1), user(2)]);
+        assert_eq!(ended.broken_by, None);
+        assert_eq!(ended.ended_at, closes_at);
+        assert_eq!(chain, None);
+        assert_eq!(
+            close(&mut chain, closes_at + chrono::Duration::seconds(5)),
+            None
+        );
+    }
+}
@@ -0,0 +1,66 @@
+//! What time it is, for everything that doesn't go by when a message was sent. Tests and the
+//! simulator use a [`ManualClock`] so they can jump to the end of a window instead of waiting for
+//! it.
+
+use std::{future::Future, pin::Pin};
+
+use chrono::{DateTime, Utc};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Waits until `deadline`, or returns right away if it has passed.
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// The time on the machine the bot runs on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let duration = (deadline - Utc::now()).to_std().unwrap_or_default();
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A clock that only moves when it is told to.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: std::sync::Arc<tokio::sync::watch::Sender<DateTime<Utc>>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: std::sync::Arc::new(tokio::sync::watch::Sender::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        self.now.send_replace(now);
+    }
+
+    #[cfg(test)]
+    pub fn advance(&self, by: chrono::TimeDelta) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.borrow()
+    }
+
+    fn sleep_until(&self, deadline: DateTime<Utc>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // If the clock is gone, nothing is left to move it, so there's no point waiting.
+            now.wait_for(|now| *now >= deadline).await.ok();
+        })
+    }
+}
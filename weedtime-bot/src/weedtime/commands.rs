@@ -0,0 +1,417 @@
+//! The slash commands the bot registers, and where their interactions are sent.
+
+use serenity::{
+    all::{ChannelType, CommandInteraction, CommandOptionType, Permissions},
+    builder::{
+        CreateCommand, CreateCommandOption, CreateEmbed, CreateInteractionResponse,
+        CreateInteractionResponseMessage,
+    },
+};
+use weedtime_db::data::{WeedDatabase, v2::MatchMode, v5::TimezoneSource, v6::ResponseMode};
+
+use crate::{
+    Bot,
+    weedtime::{
+        settings::{
+            LOCALES, MAX_GRACE_SECONDS, handle_channels_command, handle_config_command,
+            handle_timezone_autocomplete, handle_timezone_command, handle_trigger_command,
+            handle_user_timezone_command, handle_window_command, match_mode_label,
+            match_mode_value, response_mode_label, response_mode_value, timezone_source_label,
+            timezone_source_value,
+        },
+        stats::{
+            LEADERBOARD_METRICS, StatsScope, handle_chains_command, handle_guild_stats_command,
+            handle_leaderboard_command, handle_user_stats_command,
+        },
+    },
+};
+
+fn channel_option() -> CreateCommandOption {
+    CreateCommandOption::new(
+        CommandOptionType::Channel,
+        "channel",
+        "The channel or category. Threads go by their parent channel",
+    )
+    .required(true)
+    .channel_types(vec![
+        ChannelType::Text,
+        ChannelType::News,
+        ChannelType::Forum,
+        ChannelType::Category,
+    ])
+}
+
+pub fn stats_commands() -> Vec<CreateCommand> {
+    vec![
+        CreateCommand::new("userstats")
+            .description("Show weed stats for a user")
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::User,
+                "user",
+                "The user to show stats for",
+            ))
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "scope",
+                    "Whether to show stats for this server or everywhere",
+                )
+                .add_string_choice(StatsScope::Server.label(), StatsScope::Server.value())
+                .add_string_choice(StatsScope::Global.label(), StatsScope::Global.value()),
+            ),
+        CreateCommand::new("serverstats").description("Show weed stats for this server"),
+        CreateCommand::new("leaderboard")
+            .description("Show who has the most weed stats")
+            .add_option(LEADERBOARD_METRICS.iter().fold(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "metric",
+                    "What to rank users by",
+                ),
+                |option, &(_, value, label)| option.add_string_choice(label, value),
+            ))
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "scope",
+                    "Whether to rank users in this server or everywhere",
+                )
+                .add_string_choice(StatsScope::Server.label(), StatsScope::Server.value())
+                .add_string_choice(StatsScope::Global.label(), StatsScope::Global.value()),
+            ),
+        CreateCommand::new("chains")
+            .description("Show this server's longest and latest weed time chains"),
+        CreateCommand::new("timezone")
+            .description("Set the timezone this server uses for weed time")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    "timezone",
+                    "IANA timezone, like America/New_York",
+                )
+                .required(true)
+                .set_autocomplete(true),
+            ),
+        CreateCommand::new("mytimezone")
+            .description("Set your own timezone, for servers that go by their members' timezones")
+            .add_option(
+                CreateCommandOption::new(CommandOptionType::SubCommand, "set", "Set your timezone")
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "timezone",
+                            "IANA timezone, like America/New_York",
+                        )
+                        .required(true)
+                        .set_autocomplete(true),
+                    ),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "clear",
+                "Forget your timezone",
+            )),
+        CreateCommand::new("trigger")
+            .description("Manage what messages count as a weed time in this server")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "add",
+                    "Add a trigger phrase",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "pattern",
+                        "The phrase, matched as whole words",
+                    )
+                    .required(true),
+                )
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "regex",
+                    "Match the pattern as a regular expression instead",
+                )),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "remove",
+                    "Remove a trigger",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "pattern",
+                        "The trigger to remove, as it was added",
+                    )
+                    .required(true),
+                ),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "list",
+                "List this server's triggers",
+            ))
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "mode",
+                    "Set how closely messages have to match the triggers",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "mode",
+                        "Lenient also sees through leetspeak, lookalike letters and spacing",
+                    )
+                    .required(true)
+                    .add_string_choice(
+                        match_mode_label(MatchMode::Strict),
+                        match_mode_value(MatchMode::Strict),
+                    )
+                    .add_string_choice(
+                        match_mode_label(MatchMode::Lenient),
+                        match_mode_value(MatchMode::Lenient),
+                    ),
+                ),
+            ),
+        CreateCommand::new("window")
+            .description("Manage when messages count as a weed time in this server")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "set",
+                    "Choose which times count, and the grace period",
+                )
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "am",
+                    "Whether 4:20 AM counts",
+                ))
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "pm",
+                    "Whether 4:20 PM counts",
+                ))
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Integer,
+                        "grace",
+                        "Seconds before and after each minute that still count",
+                    )
+                    .min_int_value(0)
+                    .max_int_value(MAX_GRACE_SECONDS.into()),
+                )
+                .add_sub_option(CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    "anywhere",
+                    "Count weed time whenever it's one of the times somewhere in the world",
+                ))
+                .add_sub_option(
+                    [
+                        TimezoneSource::Guild,
+                        TimezoneSource::Author,
+                        TimezoneSource::Either,
+                    ]
+                    .into_iter()
+                    .fold(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "timezone",
+                            "Whose timezone decides when weed time is",
+                        ),
+                        |option, source| {
+                            option.add_string_choice(
+                                timezone_source_label(source),
+                                timezone_source_value(source),
+                            )
+                        },
+                    ),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "add",
+                    "Add another time of day that counts",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "time",
+                        "The time in the server's timezone, like 7:10 PM or 19:10",
+                    )
+                    .required(true),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "remove",
+                    "Remove a time added with /window add",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "time",
+                        "The time to remove",
+                    )
+                    .required(true),
+                ),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "show",
+                "Show when weed time is in this server",
+            )),
+        CreateCommand::new("channels")
+            .description("Choose which channels the bot watches in this server")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "allow",
+                    "Watch a channel or category. Once any are allowed, the rest are ignored",
+                )
+                .add_sub_option(channel_option()),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "deny",
+                    "Ignore a channel or category",
+                )
+                .add_sub_option(channel_option()),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "clear",
+                    "Forget whether a channel or category was allowed or denied",
+                )
+                .add_sub_option(channel_option()),
+            )
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "list",
+                "Show which channels the bot watches",
+            )),
+        CreateCommand::new("config")
+            .description("Show or change how the bot is set up in this server")
+            .default_member_permissions(Permissions::ADMINISTRATOR)
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "show",
+                "Show every setting for this server",
+            ))
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "response",
+                    "Choose how the bot answers a weed time or weed crime",
+                )
+                .add_sub_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        "mode",
+                        "Whether to post a picture with the answer",
+                    )
+                    .required(true)
+                    .add_string_choice(
+                        response_mode_label(ResponseMode::Image),
+                        response_mode_value(ResponseMode::Image),
+                    )
+                    .add_string_choice(
+                        response_mode_label(ResponseMode::Text),
+                        response_mode_value(ResponseMode::Text),
+                    ),
+                ),
+            )
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::SubCommand,
+                    "locale",
+                    "Choose the locale the bot uses in this server",
+                )
+                .add_sub_option(
+                    LOCALES.iter().fold(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            "locale",
+                            "The language and region",
+                        )
+                        .required(true),
+                        |option, &(value, label)| option.add_string_choice(label, value),
+                    ),
+                ),
+            ),
+    ]
+}
+
+pub async fn respond_with_embed(
+    bot: &Bot,
+    command: &CommandInteraction,
+    embed: CreateEmbed,
+) -> Result<(), serenity::Error> {
+    bot.transport
+        .create_response(
+            command.id,
+            &command.token,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new().embed(embed),
+            ),
+        )
+        .await
+}
+
+pub async fn respond_with_content(
+    bot: &Bot,
+    command: &CommandInteraction,
+    content: impl Into<String>,
+) -> Result<(), serenity::Error> {
+    bot.transport
+        .create_response(
+            command.id,
+            &command.token,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(content)
+                    .ephemeral(true),
+            ),
+        )
+        .await
+}
+
+pub async fn handle_stats_interaction(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    match command.data.name.as_str() {
+        "userstats" => handle_user_stats_command(bot, command, db).await,
+        "serverstats" => handle_guild_stats_command(bot, command, db).await,
+        "leaderboard" => handle_leaderboard_command(bot, command, db).await,
+        "chains" => handle_chains_command(bot, command, db).await,
+        "timezone" => handle_timezone_command(bot, command, db).await,
+        "mytimezone" => handle_user_timezone_command(bot, command, db).await,
+        "trigger" => handle_trigger_command(bot, command, db).await,
+        "window" => handle_window_command(bot, command, db).await,
+        "config" => handle_config_command(bot, command, db).await,
+        "channels" => handle_channels_command(bot, command, db).await,
+        _ => Ok(()),
+    }
+}
+
+pub async fn handle_autocomplete_interaction(
+    bot: &Bot,
+    command: &CommandInteraction,
+) -> Result<(), serenity::Error> {
+    match command.data.name.as_str() {
+        "timezone" | "mytimezone" => handle_timezone_autocomplete(bot, command).await,
+        _ => Ok(()),
+    }
+}
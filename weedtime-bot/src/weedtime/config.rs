@@ -0,0 +1,112 @@
+use std::sync::Arc;
+
+use serenity::all::{GuildId, UserId};
+use weedtime_db::data::{GuildConfig, UserConfig, WeedDatabase};
+use whirlwind::ShardMap;
+
+use crate::{Bot, GuildConfigCache, UserConfigCache, weedtime::triggers::forget_triggers};
+
+async fn get_guild_cache(bot: &Bot) -> Arc<ShardMap<GuildId, Arc<GuildConfig>>> {
+    let data_read = bot.data.read().await;
+    data_read
+        .get::<GuildConfigCache>()
+        .expect("GuildConfigCache not found in TypeMap")
+        .clone()
+}
+
+async fn get_user_cache(bot: &Bot) -> Arc<ShardMap<UserId, Arc<UserConfig>>> {
+    let data_read = bot.data.read().await;
+    data_read
+        .get::<UserConfigCache>()
+        .expect("UserConfigCache not found in TypeMap")
+        .clone()
+}
+
+/// A guild's settings, read from the database the first time they are asked for. If that fails,
+/// the defaults are used and it is tried again next time.
+pub async fn guild_config(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    guild_id: GuildId,
+) -> Arc<GuildConfig> {
+    let cache = get_guild_cache(bot).await;
+    if let Some(config) = cache.get(&guild_id).await {
+        return config.clone();
+    }
+
+    let config = match db.guild_config(guild_id) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            tracing::error!("Failed to fetch settings for {guild_id}: {e:?}");
+            return Arc::new(GuildConfig::new(guild_id));
+        }
+    };
+
+    cache.insert(guild_id, config.clone()).await;
+    config
+}
+
+/// Reads a guild's settings back into the cache after they were changed, and drops its
+/// compiled triggers. Every command that commits a
+/// [`GuildConfigUpdate`](weedtime_db::data::GuildConfigUpdate) should call this, or
+/// messages keep being handled with the old settings.
+pub async fn refresh_guild_config(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    guild_id: GuildId,
+) -> Option<Arc<GuildConfig>> {
+    let cache = get_guild_cache(bot).await;
+    forget_triggers(bot, guild_id).await;
+
+    match db.guild_config(guild_id) {
+        Ok(config) => {
+            let config = Arc::new(config);
+            cache.insert(guild_id, config.clone()).await;
+            Some(config)
+        }
+        Err(e) => {
+            tracing::error!("Failed to fetch settings for {guild_id}: {e:?}");
+            cache.remove(&guild_id).await;
+            None
+        }
+    }
+}
+
+/// A user's settings, read from the database the first time they are asked for.
+pub async fn user_config(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    user_id: UserId,
+) -> Arc<UserConfig> {
+    let cache = get_user_cache(bot).await;
+    if let Some(config) = cache.get(&user_id).await {
+        return config.clone();
+    }
+
+    let config = match db.user_config(user_id) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            tracing::error!("Failed to fetch settings for {user_id}: {e:?}");
+            return Arc::new(UserConfig::new(user_id));
+        }
+    };
+
+    cache.insert(user_id, config.clone()).await;
+    config
+}
+
+/// Reads a user's settings back into the cache after they were changed, see
+/// [`refresh_guild_config`].
+pub async fn refresh_user_config(bot: &Bot, db: &WeedDatabase<'static>, user_id: UserId) {
+    let cache = get_user_cache(bot).await;
+
+    match db.user_config(user_id) {
+        Ok(config) => {
+            cache.insert(user_id, Arc::new(config)).await;
+        }
+        Err(e) => {
+            tracing::error!("Failed to fetch settings for {user_id}: {e:?}");
+            cache.remove(&user_id).await;
+        }
+    }
+}
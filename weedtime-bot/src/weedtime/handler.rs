@@ -0,0 +1,1302 @@
+//! The gateway events the bot handles, and the path a message takes from one to a chain.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use chrono_tz::Tz;
+use serenity::{
+    all::{
+        ChannelId, Context, EventHandler, GuildId, Interaction, Message, MessageId,
+        MessageUpdateEvent, Ready, UserId,
+    },
+    async_trait,
+};
+use tracing::{error, info, warn};
+use weedtime_db::data::{
+    DbUpdate, GuildConfig, MessageUpdate, WeedDatabase, WindowMatch,
+    v1::WeedEventKind,
+    v4::WeedWindow,
+    v5::TimezoneSource,
+    v6::{DEFAULT_TIMEZONE, ResponseMode},
+};
+
+use crate::{
+    Bot, WeedTimeMessage,
+    weedtime::{
+        chain::{Chain, ChainEvent},
+        clock::Clock,
+        commands::{handle_autocomplete_interaction, handle_stats_interaction, stats_commands},
+        config::{guild_config, user_config},
+        queue::ChannelQueues,
+        recap::close_window,
+        recent::RecentMessages,
+        states::{handle_message, remove_from_chain},
+        stats::handle_leaderboard_button,
+        triggers::guild_triggers,
+        util::{channel_ancestry, get_map, get_schedule},
+    },
+};
+
+/// How many message ids are kept in memory to drop messages Discord delivers twice.
+const RECENT_MESSAGES: usize = 10_000;
+
+/// How long processed messages are remembered in the database. Discord only delivers a message
+/// again when a gateway session resumes, which is long over by then.
+const PROCESSED_MESSAGE_RETENTION: TimeDelta = TimeDelta::days(1);
+
+pub struct Handler {
+    db: Arc<WeedDatabase<'static>>,
+    clock: Arc<dyn Clock>,
+    queues: Arc<ChannelQueues<ChannelJob>>,
+    recent: Mutex<RecentMessages>,
+}
+
+/// Something that changes a channel's chain, done from the channel's queue so only one of them
+/// touches the chain at a time.
+pub enum ChannelJob {
+    Message(QueuedMessage),
+    Delete {
+        bot: Bot,
+        db: Arc<WeedDatabase<'static>>,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        message_ids: Vec<MessageId>,
+    },
+    CloseWindow {
+        bot: Bot,
+        db: Arc<WeedDatabase<'static>>,
+        channel_id: ChannelId,
+        now: DateTime<Utc>,
+    },
+}
+
+async fn run_job(job: ChannelJob) {
+    match job {
+        ChannelJob::Message(queued) => process_message(queued).await,
+        ChannelJob::Delete {
+            bot,
+            db,
+            channel_id,
+            guild_id,
+            message_ids,
+        } => revert_deleted_messages(&bot, db.as_ref(), channel_id, guild_id, &message_ids).await,
+        ChannelJob::CloseWindow {
+            bot,
+            db,
+            channel_id,
+            now,
+        } => close_window(&bot, db.as_ref(), channel_id, now).await,
+    }
+}
+
+/// The timezones a message is checked for weed time in, in the order they are tried. Authors
+/// who haven't set a timezone go by the guild's.
+async fn message_timezones(
+    bot: &Bot,
+    guild_timezone: Tz,
+    source: TimezoneSource,
+    author_id: UserId,
+    db: &WeedDatabase<'static>,
+) -> Vec<Tz> {
+    if source == TimezoneSource::Guild {
+        return vec![guild_timezone];
+    }
+
+    let author_timezone = user_config(bot, db, author_id).await.timezone;
+    match (source, author_timezone) {
+        (TimezoneSource::Author, Some(author_timezone)) => vec![author_timezone],
+        (TimezoneSource::Either, Some(author_timezone)) => vec![guild_timezone, author_timezone],
+        _ => vec![guild_timezone],
+    }
+}
+
+/// Whether a message is a weed time, and the settings it is handled with. Everything comes from
+/// the caches, so checking a message doesn't read the database.
+struct MessageCheck {
+    config: Option<Arc<GuildConfig>>,
+    timezone: Tz,
+    response_mode: ResponseMode,
+    window: Option<WindowMatch>,
+    contains_weed_time: bool,
+}
+
+/// Checks `msg` as if it was sent at `timestamp`.
+async fn check_message(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    msg: &Message,
+    timestamp: DateTime<Utc>,
+) -> MessageCheck {
+    let contains_weed_time = guild_triggers(bot, db, msg.guild_id)
+        .await
+        .is_match(&msg.content);
+
+    let config = match msg.guild_id {
+        Some(guild_id) => Some(guild_config(bot, db, guild_id).await),
+        None => None,
+    };
+    let timezone = config
+        .as_ref()
+        .map_or(DEFAULT_TIMEZONE, |config| config.timezone);
+    let timezone_source = config
+        .as_ref()
+        .map_or_else(TimezoneSource::default, |config| config.timezone_source);
+    let response_mode = config
+        .as_ref()
+        .map_or_else(ResponseMode::default, |config| config.response_mode);
+    let timezones = message_timezones(bot, timezone, timezone_source, msg.author.id, db).await;
+    let window = match &config {
+        Some(config) => config.window.find(timestamp, &timezones),
+        None => WeedWindow::default().find(timestamp, &timezones),
+    };
+
+    MessageCheck {
+        config,
+        timezone,
+        response_mode,
+        window,
+        contains_weed_time,
+    }
+}
+
+impl MessageCheck {
+    /// What the chain engine needs to know about `msg`, sent at `timestamp`.
+    fn into_event(self, msg: &Message, timestamp: DateTime<Utc>) -> ChainEvent {
+        ChainEvent {
+            channel_id: msg.channel_id,
+            guild_id: msg.guild_id,
+            user_id: msg.author.id,
+            timestamp,
+            timezone: self.timezone,
+            window: self.window,
+            contains_weed_time: self.contains_weed_time,
+        }
+    }
+}
+
+/// A checked message waiting its turn in its channel's queue.
+pub struct QueuedMessage {
+    bot: Bot,
+    db: Arc<WeedDatabase<'static>>,
+    message_id: MessageId,
+    event: ChainEvent,
+    response_mode: ResponseMode,
+    /// Whether the message was edited after it was counted for something other than a weed time
+    /// or a weed crime, which it is counted again for.
+    edited: bool,
+}
+
+pub async fn process_message(queued: QueuedMessage) {
+    let QueuedMessage {
+        bot,
+        db,
+        message_id,
+        event,
+        response_mode,
+        edited,
+    } = queued;
+
+    // The bot may have restarted since it last saw the message, so the database has the final
+    // say. Checking before answering keeps it from answering twice too.
+    match db.is_processed(message_id) {
+        Ok(false) => {}
+        Ok(true) if edited => {}
+        Ok(true) => {
+            info!("Skipping message {message_id}, which was already counted");
+            return;
+        }
+        Err(e) => error!("Failed to check whether message {message_id} was counted: {e:?}"),
+    }
+
+    match handle_message(&bot, db.as_ref(), message_id, event, response_mode).await {
+        Ok(Some(update)) => {
+            let update = MessageUpdate { edited, ..update };
+            if let Err(e) = update.commit(db.as_ref()) {
+                error!("Database commit error for message {message_id}: {e:?}");
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("Error handling message {message_id}: {e:?}"),
+    }
+}
+
+/// Checks a new message, and gets it ready for its channel's queue if it has anything to do
+/// with weed time.
+pub async fn accept_message(
+    bot: Bot,
+    db: Arc<WeedDatabase<'static>>,
+    msg: &Message,
+) -> Option<QueuedMessage> {
+    let check = check_message(&bot, db.as_ref(), msg, *msg.timestamp).await;
+    if check.window.is_none() && !check.contains_weed_time {
+        return None;
+    }
+
+    if !is_watched(&bot, check.config.as_deref(), msg.channel_id).await {
+        return None;
+    }
+
+    let response_mode = check.response_mode;
+    Some(QueuedMessage {
+        bot,
+        db,
+        message_id: msg.id,
+        event: check.into_event(msg, *msg.timestamp),
+        response_mode,
+        edited: false,
+    })
+}
+
+/// Whether the guild watches `channel_id`. Channels are only looked up when the guild has
+/// allowed or denied any.
+async fn is_watched(bot: &Bot, config: Option<&GuildConfig>, channel_id: ChannelId) -> bool {
+    match config {
+        Some(config) if !config.channels.is_empty() => config
+            .channels
+            .allows(&channel_ancestry(bot, config.id(), channel_id).await),
+        _ => true,
+    }
+}
+
+async fn revert_deleted_messages(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+    message_ids: &[MessageId],
+) {
+    for &message_id in message_ids {
+        let Some(revert) = remove_from_chain(bot, db, channel_id, guild_id, message_id).await
+        else {
+            continue;
+        };
+        if let Err(e) = revert.commit(db) {
+            error!("Failed to take back deleted message {message_id}: {e:?}");
+        }
+    }
+}
+
+/// Loads the chains that were running when the bot last stopped back into the channel map,
+/// dropping any whose window has passed by `now`.
+async fn restore_chains(bot: &Bot, db: &WeedDatabase<'static>, now: DateTime<Utc>) {
+    match db.prune_chain_states(now) {
+        Ok(0) => {}
+        Ok(count) => info!("Dropped {count} chains whose window has passed"),
+        Err(e) => error!("Failed to drop expired chains: {e:?}"),
+    }
+
+    let states = match db.chain_states() {
+        Ok(states) => states,
+        Err(e) => {
+            error!("Failed to load chains: {e:?}");
+            return;
+        }
+    };
+
+    let map = get_map(bot).await;
+    let schedule = get_schedule(bot).await;
+    for state in states {
+        let channel_id = state.channel_id();
+        // `ready` fires again on reconnect, when the map is already up to date.
+        if map.contains_key(&channel_id).await {
+            continue;
+        }
+
+        if let Err(e) = bot
+            .transport
+            .fetch_message(channel_id, state.message_id())
+            .await
+        {
+            warn!("Failed to fetch the chain message in {channel_id}, dropping the chain: {e:?}");
+            continue;
+        }
+
+        info!("Restoring chain in {channel_id} (Count: {})", state.count);
+        map.insert(
+            channel_id,
+            WeedTimeMessage {
+                reply: Some(state.message_id()),
+                chain: Some(Chain {
+                    users: state.users(),
+                    window_start: state.window_start,
+                    expires_at: state.expires_at,
+                }),
+                guild_id: state.guild_id(),
+                server_record: state.server_record,
+            },
+        )
+        .await;
+        schedule.add(channel_id, state.expires_at);
+    }
+}
+
+impl Handler {
+    pub fn new(db: Arc<WeedDatabase<'static>>, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            db,
+            queues: Arc::new(ChannelQueues::new(clock.clone(), run_job)),
+            clock,
+            recent: Mutex::new(RecentMessages::new(RECENT_MESSAGES)),
+        }
+    }
+
+    /// The channel queues, for closing windows from the scheduler in turn with everything else.
+    pub fn queues(&self) -> Arc<ChannelQueues<ChannelJob>> {
+        self.queues.clone()
+    }
+
+    /// Registers the commands and picks up where the bot left off, the way
+    /// [`EventHandler::ready`] does once it has a [`Bot`].
+    async fn start_up(&self, bot: &Bot, name: &str) {
+        match bot.transport.set_global_commands(stats_commands()).await {
+            Ok(commands) => {
+                tracing::info!("Registered {} slash commands for {name}", commands.len());
+            }
+            Err(e) => error!("Failed to register slash commands: {e:?}"),
+        }
+
+        let now = self.clock.now();
+        restore_chains(bot, self.db.as_ref(), now).await;
+
+        match self
+            .db
+            .prune_processed_messages(now - PROCESSED_MESSAGE_RETENTION)
+        {
+            Ok(0) => {}
+            Ok(count) => info!("Forgot {count} processed messages"),
+            Err(e) => error!("Failed to forget processed messages: {e:?}"),
+        }
+    }
+
+    /// Handles a new message, the way [`EventHandler::message`] does once it has a [`Bot`].
+    async fn receive_message(&self, bot: Bot, msg: Message) {
+        // Bots, this one included, can't have a weed time.
+        if msg.author.bot {
+            return;
+        }
+
+        // Discord can deliver a message again after resuming a gateway session.
+        if !self
+            .recent
+            .lock()
+            .expect("recent messages poisoned")
+            .insert(msg.id)
+        {
+            return;
+        }
+
+        let db = self.db.clone();
+        let queues = self.queues.clone();
+        let received_at = self.clock.now();
+
+        tokio::spawn(async move {
+            // Chains are only touched from the channel's queue, one message at a time.
+            if let Some(queued) = accept_message(bot, db, &msg).await {
+                queues.push(
+                    msg.channel_id,
+                    queued.event.timestamp,
+                    Some(msg.id),
+                    received_at,
+                    ChannelJob::Message(queued),
+                );
+            }
+        });
+    }
+
+    /// Handles an edited message, the way [`EventHandler::message_update`] does once it has a
+    /// [`Bot`]. `new` is the message from the cache, if it was there.
+    async fn receive_edit(&self, bot: Bot, new: Option<Message>, event: MessageUpdateEvent) {
+        // Only edits to the text can add a trigger.
+        if event.content.is_none() || event.author.as_ref().is_some_and(|author| author.bot) {
+            return;
+        }
+
+        let db = self.db.clone();
+        let queues = self.queues.clone();
+        let clock = self.clock.clone();
+        let received_at = clock.now();
+
+        tokio::spawn(async move {
+            let mut msg = match new {
+                Some(msg) => msg,
+                None => match bot
+                    .transport
+                    .fetch_message(event.channel_id, event.id)
+                    .await
+                {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        warn!("Failed to fetch edited message {}: {e:?}", event.id);
+                        return;
+                    }
+                },
+            };
+            if msg.author.bot {
+                return;
+            }
+            msg.guild_id = msg.guild_id.or(event.guild_id);
+
+            // The edit is what counts, so the crime is logged when it was made.
+            let edited_at = event
+                .edited_timestamp
+                .or(msg.edited_timestamp)
+                .map_or_else(|| clock.now(), |edited_at| *edited_at);
+            let check = check_message(&bot, db.as_ref(), &msg, edited_at).await;
+            if !check.contains_weed_time || check.window.is_some() {
+                return;
+            }
+
+            // Messages the bot already counted as weed times or crimes, like ones that were sent
+            // with a trigger in the first place, stay as they were. Ones that only broke a chain
+            // are counted again.
+            let edited = match db.event(msg.id) {
+                Ok(None) => false,
+                Ok(Some(logged)) => match logged.kind {
+                    WeedEventKind::WeedTime | WeedEventKind::WeedCrime => return,
+                    _ => true,
+                },
+                Err(e) => {
+                    error!(
+                        "Failed to fetch the event for edited message {}: {e:?}",
+                        msg.id
+                    );
+                    return;
+                }
+            };
+
+            if !is_watched(&bot, check.config.as_deref(), msg.channel_id).await {
+                return;
+            }
+
+            let response_mode = check.response_mode;
+            let event = check.into_event(&msg, edited_at);
+            queues.push(
+                msg.channel_id,
+                event.timestamp,
+                Some(msg.id),
+                received_at,
+                ChannelJob::Message(QueuedMessage {
+                    bot,
+                    db,
+                    message_id: msg.id,
+                    event,
+                    response_mode,
+                    edited,
+                }),
+            );
+        });
+    }
+
+    /// Handles deleted messages, the way [`EventHandler::message_delete`] and
+    /// [`EventHandler::message_delete_bulk`] do once they have a [`Bot`]. They are taken back
+    /// after anything else in the channel that came before the deletion, like the messages
+    /// themselves if they are still being handled.
+    fn receive_delete(
+        &self,
+        bot: Bot,
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        message_ids: Vec<MessageId>,
+    ) {
+        let now = self.clock.now();
+        let sent_at = message_ids
+            .iter()
+            .map(|message_id| *message_id.created_at())
+            .max();
+        self.queues.push(
+            channel_id,
+            sent_at.map_or(now, |sent_at| sent_at.max(now)),
+            None,
+            now,
+            ChannelJob::Delete {
+                bot,
+                db: self.db.clone(),
+                channel_id,
+                guild_id,
+                message_ids,
+            },
+        );
+    }
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        self.start_up(&Bot::from_context(&ctx), &ready.user.name)
+            .await;
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        let bot = Bot::from_context(&ctx);
+        match interaction {
+            Interaction::Command(command) => {
+                if let Err(e) = handle_stats_interaction(&bot, &command, self.db.as_ref()).await {
+                    warn!("Slash command error: {e:?}");
+                }
+            }
+            Interaction::Autocomplete(command) => {
+                if let Err(e) = handle_autocomplete_interaction(&bot, &command).await {
+                    warn!("Autocomplete error: {e:?}");
+                }
+            }
+            Interaction::Component(component) => {
+                if let Err(e) = handle_leaderboard_button(&bot, &component, self.db.as_ref()).await
+                {
+                    warn!("Component interaction error: {e:?}");
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Set a handler for the `message` event. This is called whenever a new message is received.
+    //
+    // Event handlers are dispatched through a threadpool, and so multiple events can be dispatched
+    // simultaneously.
+    async fn message(&self, ctx: Context, msg: Message) {
+        self.receive_message(Bot::from_context(&ctx), msg).await;
+    }
+
+    async fn message_update(
+        &self,
+        ctx: Context,
+        _old_if_available: Option<Message>,
+        new: Option<Message>,
+        event: MessageUpdateEvent,
+    ) {
+        self.receive_edit(Bot::from_context(&ctx), new, event).await;
+    }
+
+    async fn message_delete(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        deleted_message_id: MessageId,
+        guild_id: Option<GuildId>,
+    ) {
+        self.receive_delete(
+            Bot::from_context(&ctx),
+            channel_id,
+            guild_id,
+            vec![deleted_message_id],
+        );
+    }
+
+    async fn message_delete_bulk(
+        &self,
+        ctx: Context,
+        channel_id: ChannelId,
+        multiple_deleted_messages_ids: Vec<MessageId>,
+        guild_id: Option<GuildId>,
+    ) {
+        self.receive_delete(
+            Bot::from_context(&ctx),
+            channel_id,
+            guild_id,
+            multiple_deleted_messages_ids,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+    use chrono_tz::America::New_York;
+    use serenity::{
+        all::{Timestamp, User},
+        prelude::RwLock,
+    };
+    use weedtime_db::data::{
+        ChainState, ChainUpdate, GuildStats, GuildStatsUpdate, MessageUpdate, UserStats,
+    };
+
+    use super::*;
+    use crate::{
+        shared_data,
+        weedtime::{
+            clock::ManualClock,
+            queue::REORDER_DELAY,
+            recap::close_window,
+            scheduler::WindowSchedule,
+            transport::{RecordingTransport, Sent},
+            util::combo_to_emojis,
+        },
+    };
+
+    const GUILD: GuildId = GuildId::new(420);
+    const CHANNEL: ChannelId = ChannelId::new(1);
+
+    struct Harness {
+        handler: Handler,
+        bot: Bot,
+        db: Arc<WeedDatabase<'static>>,
+        transport: Arc<RecordingTransport>,
+        clock: ManualClock,
+    }
+
+    /// A handler that records what it sends, with nothing in its database, half a minute into
+    /// the 4:20 PM window on April 20th.
+    fn harness() -> Harness {
+        let db = Arc::new(WeedDatabase::create_in_memory().unwrap());
+        let clock = ManualClock::new(at(20, 16, 20, 30));
+        let transport = Arc::new(RecordingTransport::new());
+        let bot = Bot {
+            data: Arc::new(RwLock::new(shared_data(
+                Arc::new(clock.clone()),
+                Arc::new(WindowSchedule::new()),
+            ))),
+            transport: transport.clone(),
+        };
+        let handler = Handler::new(db.clone(), Arc::new(clock.clone()));
+
+        Harness {
+            handler,
+            bot,
+            db,
+            transport,
+            clock,
+        }
+    }
+
+    fn at(day: u32, hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        New_York
+            .with_ymd_and_hms(2024, 4, day, hour, minute, second)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn message(id: u64, user: u64, content: &str, timestamp: DateTime<Utc>) -> Message {
+        let mut author = User::default();
+        author.id = UserId::new(user);
+
+        let mut msg = Message::default();
+        msg.id = MessageId::new(id);
+        msg.channel_id = CHANNEL;
+        msg.guild_id = Some(GUILD);
+        msg.author = author;
+        msg.content = content.to_string();
+        msg.timestamp = Timestamp::from(timestamp);
+        msg
+    }
+
+    impl Harness {
+        /// Hands `msg` to the handler and waits until it has been counted.
+        async fn send(&self, msg: Message) {
+            let message_id = msg.id;
+            self.handler.receive_message(self.bot.clone(), msg).await;
+            self.clock.advance(REORDER_DELAY);
+            self.counted(message_id).await;
+        }
+
+        /// Waits until `count` jobs are held in the channel queues.
+        async fn queued(&self, count: usize) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.handler.queues.waiting() < count {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .expect("jobs weren't queued");
+        }
+
+        /// Lets the jobs held in the channel queues through, and waits until they are done.
+        async fn settle(&self) {
+            self.clock.advance(REORDER_DELAY);
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while self.handler.queues.waiting() > 0 {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .expect("jobs weren't done");
+        }
+
+        async fn counted(&self, message_id: MessageId) {
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while !self.db.is_processed(message_id).unwrap() {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                }
+            })
+            .await
+            .expect("message wasn't counted");
+        }
+
+        /// The bot's replies so far, with their ids.
+        fn replies(&self) -> Vec<(MessageId, String, Option<String>)> {
+            self.transport
+                .sent()
+                .into_iter()
+                .filter_map(|sent| match sent {
+                    Sent::Message {
+                        channel_id,
+                        message_id,
+                        content,
+                        image,
+                    } => {
+                        assert_eq!(channel_id, CHANNEL);
+                        Some((message_id, content, image))
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// The messages the bot has edited so far, and what it changed them to.
+        fn edits(&self) -> Vec<(MessageId, String)> {
+            self.transport
+                .sent()
+                .into_iter()
+                .filter_map(|sent| match sent {
+                    Sent::Edit {
+                        channel_id,
+                        message_id,
+                        content,
+                    } => {
+                        assert_eq!(channel_id, CHANNEL);
+                        Some((message_id, content))
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+
+        /// The bot's messages it has taken down so far.
+        fn deleted(&self) -> Vec<MessageId> {
+            self.transport
+                .sent()
+                .into_iter()
+                .filter_map(|sent| match sent {
+                    Sent::Delete {
+                        channel_id,
+                        message_id,
+                    } => {
+                        assert_eq!(channel_id, CHANNEL);
+                        Some(message_id)
+                    }
+                    _ => None,
+                })
+                .collect()
+        }
+
+        async fn delete(&self, message_id: u64) {
+            self.handler.receive_delete(
+                self.bot.clone(),
+                CHANNEL,
+                Some(GUILD),
+                vec![MessageId::new(message_id)],
+            );
+            self.settle().await;
+        }
+
+        /// Hands an edit of `msg` to `content` to the handler, as if it was made at
+        /// `edited_at` and wasn't in the cache.
+        async fn edit(&self, mut msg: Message, content: &str, edited_at: DateTime<Utc>) {
+            msg.content = content.to_string();
+            msg.edited_timestamp = Some(Timestamp::from(edited_at));
+            let event = serde_json::from_value(serde_json::json!({
+                "id": msg.id,
+                "channel_id": CHANNEL,
+                "guild_id": GUILD,
+                "content": content,
+                "edited_timestamp": Timestamp::from(edited_at),
+            }))
+            .unwrap();
+            self.transport.post(msg);
+            self.handler
+                .receive_edit(self.bot.clone(), None, event)
+                .await;
+            self.clock.advance(REORDER_DELAY);
+        }
+
+        fn user_stats(&self, user: u64) -> UserStats {
+            self.db
+                .user_stats(Some(GUILD), UserId::new(user))
+                .unwrap()
+                .unwrap()
+        }
+
+        fn guild_stats(&self) -> GuildStats {
+            self.db.guild_stats(GUILD).unwrap().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_and_counts_a_chain() {
+        let h = harness();
+        for (id, user) in [(1, 10), (2, 11), (3, 12)] {
+            h.send(message(id, user, "weed time", at(20, 16, 20, 5)))
+                .await;
+        }
+
+        let replies = h.replies();
+        assert_eq!(replies.len(), 3);
+        for (_, content, image) in &replies {
+            assert_eq!(content, "WEED TIME!");
+            assert_eq!(image.as_deref(), Some("assets/420.png"));
+        }
+
+        // Each answer but the last turns into the combo it was part of.
+        let edits = h.edits();
+        assert_eq!(edits.len(), 2);
+        for (combo, (message_id, content)) in (1..).zip(edits) {
+            assert_eq!(message_id, replies[combo as usize - 1].0);
+            assert!(content.ends_with(&combo_to_emojis(combo)));
+        }
+
+        let first = h.user_stats(10);
+        assert_eq!(first.weed_times, 1);
+        assert_eq!(first.chains_started, 1);
+        assert_eq!(h.user_stats(12).longest_chain, 3);
+        assert_eq!(h.user_stats(12).chains_started, 0);
+
+        let guild = h.guild_stats();
+        assert_eq!(guild.weed_times, 3);
+        assert_eq!(guild.longest_chain, 3);
+    }
+
+    #[tokio::test]
+    async fn answers_weed_crimes_and_breaks_chains() {
+        let h = harness();
+        h.send(message(1, 10, "weed time", at(20, 15, 0, 0))).await;
+        h.send(message(2, 10, "weed time", at(20, 16, 20, 1))).await;
+        h.send(message(3, 11, "nice", at(20, 16, 20, 2))).await;
+
+        let replies = h.replies();
+        assert_eq!(
+            replies
+                .iter()
+                .map(|(_, content, image)| (content.as_str(), image.as_deref()))
+                .collect::<Vec<_>>(),
+            [
+                ("WEED CRIME!", Some("assets/420_jail.jpg")),
+                ("WEED TIME!", Some("assets/420.png")),
+            ]
+        );
+
+        let criminal = h.user_stats(10);
+        assert_eq!(criminal.weed_crimes, 1);
+        assert_eq!(criminal.weed_times, 1);
+        assert_eq!(h.user_stats(11).chains_broken, 1);
+
+        let guild = h.guild_stats();
+        assert_eq!(guild.weed_crimes, 1);
+        assert_eq!(guild.weed_times, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn chains_simultaneous_weed_times() {
+        const PARTICIPANTS: u64 = 50;
+        let h = harness();
+
+        // Every message is checked on a task of its own, so they all reach the channel's chain
+        // at about the same time, out of the order they were sent in.
+        let sent_at =
+            |user: u64| at(20, 16, 20, 0) + TimeDelta::milliseconds((user % 7) as i64 * 100);
+        for user in 1..=PARTICIPANTS {
+            h.handler
+                .receive_message(
+                    h.bot.clone(),
+                    message(user, user, "weed time", sent_at(user)),
+                )
+                .await;
+        }
+        h.queued(PARTICIPANTS as usize).await;
+        h.settle().await;
+        for user in 1..=PARTICIPANTS {
+            h.counted(MessageId::new(user)).await;
+        }
+
+        let replies = h.replies();
+        assert_eq!(replies.len(), PARTICIPANTS as usize);
+        assert!(
+            replies
+                .iter()
+                .all(|(_, content, _)| content == "WEED TIME!")
+        );
+        // Handled one at a time, each answer is followed by the one before it turning into the
+        // combo, before the next message is answered.
+        let mut expected = vec![("answer", replies[0].0)];
+        for pair in replies.windows(2) {
+            expected.extend([("answer", pair[1].0), ("combo", pair[0].0)]);
+        }
+        let sent = h
+            .transport
+            .sent()
+            .into_iter()
+            .map(|sent| match sent {
+                Sent::Message { message_id, .. } => ("answer", message_id),
+                Sent::Edit { message_id, .. } => ("combo", message_id),
+                sent => panic!("expected only answers and combos, got {sent:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sent, expected);
+
+        let stats = (1..=PARTICIPANTS)
+            .map(|user| h.user_stats(user))
+            .collect::<Vec<_>>();
+        assert!(stats.iter().all(|stats| stats.weed_times == 1));
+        assert_eq!(
+            stats.iter().map(|stats| stats.chains_started).sum::<u32>(),
+            1
+        );
+        assert_eq!(
+            stats.iter().map(|stats| stats.chains_broken).sum::<u32>(),
+            0
+        );
+        assert_eq!(
+            stats.iter().map(|stats| stats.longest_chain).max(),
+            Some(PARTICIPANTS as u32)
+        );
+        assert_eq!(h.guild_stats().weed_times, PARTICIPANTS as u32);
+        assert_eq!(h.guild_stats().longest_chain, PARTICIPANTS as u32);
+
+        // Held for the reorder delay, they are chained in the order they were sent in.
+        let mut in_order = (1..=PARTICIPANTS).collect::<Vec<_>>();
+        in_order.sort_by_key(|&user| (sent_at(user), user));
+        let states = h.db.chain_states().unwrap();
+        assert_eq!(
+            states[0].users(),
+            in_order
+                .iter()
+                .map(|&user| UserId::new(user))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(stats[in_order[0] as usize - 1].chains_started, 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_chain_in_each_channel() {
+        let h = harness();
+        let other_channel = ChannelId::new(2);
+        h.send(message(1, 10, "weed time", at(20, 16, 20, 0))).await;
+        let mut elsewhere = message(2, 11, "weed time", at(20, 16, 20, 1));
+        elsewhere.channel_id = other_channel;
+        h.send(elsewhere).await;
+
+        // Both start a chain of their own, so neither answer turns into a combo.
+        let answered = h
+            .transport
+            .sent()
+            .into_iter()
+            .map(|sent| match sent {
+                Sent::Message { channel_id, .. } => channel_id,
+                sent => panic!("expected only answers, got {sent:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(answered, [CHANNEL, other_channel]);
+        assert_eq!(h.user_stats(10).chains_started, 1);
+        assert_eq!(h.user_stats(11).chains_started, 1);
+    }
+
+    #[tokio::test]
+    async fn counts_messages_delivered_twice_once() {
+        let h = harness();
+        let msg = message(1, 10, "weed time", at(20, 16, 20, 0));
+        h.send(msg.clone()).await;
+        h.send(msg).await;
+
+        assert_eq!(h.replies().len(), 1);
+        assert_eq!(h.user_stats(10).weed_times, 1);
+        assert_eq!(h.guild_stats().weed_times, 1);
+    }
+
+    #[tokio::test]
+    async fn counts_triggers_edited_in_as_crimes() {
+        let h = harness();
+        let sent_at = at(20, 15, 0, 0);
+        let edited_at = at(20, 15, 5, 0);
+        let msg = message(1, 10, "hello", sent_at);
+        h.handler.receive_message(h.bot.clone(), msg.clone()).await;
+        h.edit(msg, "weed time", edited_at).await;
+        h.counted(MessageId::new(1)).await;
+
+        let replies = h.replies();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1, "WEED CRIME!");
+        assert_eq!(h.user_stats(10).weed_crimes, 1);
+        assert_eq!(h.guild_stats().weed_crimes, 1);
+        assert_eq!(
+            h.db.event(MessageId::new(1)).unwrap().unwrap().timestamp,
+            edited_at
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_chain_breakers_edited_into_crimes() {
+        let h = harness();
+        h.send(message(1, 10, "weed time", at(20, 16, 20, 0))).await;
+        let msg = message(2, 11, "nice", at(20, 16, 20, 2));
+        h.send(msg.clone()).await;
+        assert_eq!(h.user_stats(11).chains_broken, 1);
+
+        h.edit(msg, "weed time", at(20, 16, 30, 0)).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while h.db.event(MessageId::new(2)).unwrap().unwrap().kind != WeedEventKind::WeedCrime {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("edit wasn't counted");
+
+        let replies = h.replies();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1].1, "WEED CRIME!");
+        assert_eq!(h.user_stats(11).weed_crimes, 1);
+        assert_eq!(h.guild_stats().weed_crimes, 1);
+
+        // Once it is a crime, editing it again changes nothing.
+        h.edit(
+            message(2, 11, "weed time", at(20, 16, 20, 2)),
+            "420",
+            at(20, 16, 31, 0),
+        )
+        .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(h.replies().len(), 2);
+        assert_eq!(h.user_stats(11).weed_crimes, 1);
+    }
+
+    #[tokio::test]
+    async fn takes_deleted_messages_out_of_their_chain() {
+        let h = harness();
+        for (id, user) in [(1, 10), (2, 11), (3, 12), (4, 13)] {
+            h.send(message(id, user, "weed time", at(20, 16, 20, id as u32)))
+                .await;
+        }
+        let replies = h.replies();
+
+        // The answers after a deleted message count down to where their messages now stand.
+        h.delete(2).await;
+        assert_eq!(h.deleted(), [replies[1].0]);
+        let edits = h.edits();
+        assert_eq!(edits.len(), 4);
+        assert_eq!(edits[3].0, replies[2].0);
+        assert!(edits[3].1.ends_with(&combo_to_emojis(2)));
+        assert_eq!(h.user_stats(11).weed_times, 0);
+        assert_eq!(h.guild_stats().weed_times, 3);
+        assert_eq!(h.db.chain_states().unwrap()[0].count, 3);
+
+        // Without the last message, the chain carries on from the answer before it.
+        h.delete(4).await;
+        assert_eq!(h.deleted(), [replies[1].0, replies[3].0]);
+        assert_eq!(h.edits().len(), 4);
+        h.send(message(5, 14, "weed time", at(20, 16, 20, 5))).await;
+        let edits = h.edits();
+        assert_eq!(edits.len(), 5);
+        assert_eq!(edits[4].0, replies[2].0);
+        assert_eq!(h.db.chain_states().unwrap()[0].count, 3);
+
+        // Messages that aren't in the chain keep what they were counted for.
+        h.delete(2).await;
+        assert_eq!(h.deleted().len(), 2);
+        assert_eq!(h.guild_stats().weed_times, 3);
+    }
+
+    #[tokio::test]
+    async fn takes_back_messages_deleted_while_they_are_handled() {
+        let h = harness();
+        for (id, user) in [(1, 10), (2, 11)] {
+            h.handler
+                .receive_message(
+                    h.bot.clone(),
+                    message(id, user, "weed time", at(20, 16, 20, id as u32)),
+                )
+                .await;
+        }
+        h.queued(2).await;
+        h.handler
+            .receive_delete(h.bot.clone(), CHANNEL, Some(GUILD), vec![MessageId::new(2)]);
+        h.settle().await;
+
+        // The message is counted first, and then taken back.
+        let replies = h.replies();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(h.deleted(), [replies[1].0]);
+        assert_eq!(h.user_stats(11).weed_times, 0);
+        assert_eq!(h.guild_stats().weed_times, 1);
+        let states = h.db.chain_states().unwrap();
+        assert_eq!(states[0].count, 1);
+        assert_eq!(states[0].message_id(), replies[0].0);
+    }
+
+    #[tokio::test]
+    async fn takes_deleted_messages_back_after_a_restart() {
+        let h = harness();
+        for (id, user) in [(1, 10), (2, 11), (3, 12)] {
+            h.send(message(id, user, "weed time", at(20, 16, 20, id as u32)))
+                .await;
+        }
+        let replies = h.replies();
+
+        // The bot forgets the chain, and picks it back up from the database.
+        *h.bot.data.write().await =
+            shared_data(h.handler.clock.clone(), Arc::new(WindowSchedule::new()));
+        h.handler.start_up(&h.bot, "weedtime").await;
+
+        h.delete(1).await;
+        assert_eq!(h.deleted(), [replies[0].0]);
+        let edits = h.edits();
+        assert_eq!(edits.len(), 3);
+        assert_eq!(edits[2].0, replies[1].0);
+        assert!(edits[2].1.ends_with(&combo_to_emojis(1)));
+
+        let starter = h.user_stats(10);
+        assert_eq!(starter.weed_times, 0);
+        assert_eq!(starter.chains_started, 0);
+        assert_eq!(h.guild_stats().weed_times, 2);
+        let states = h.db.chain_states().unwrap();
+        assert_eq!(states[0].count, 2);
+        assert_eq!(states[0].message_id(), replies[2].0);
+    }
+
+    #[tokio::test]
+    async fn recaps_chains_when_their_window_closes() {
+        let h = harness();
+        let recaps = |h: &Harness| {
+            h.transport
+                .sent()
+                .into_iter()
+                .filter_map(|sent| match sent {
+                    Sent::Embed {
+                        channel_id, embed, ..
+                    } => Some((channel_id, format!("{embed:?}"))),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // With nothing before it, the first chain to end sets the record.
+        h.send(message(1, 10, "weed time", at(20, 16, 20, 0))).await;
+        close_window(&h.bot, &h.db, CHANNEL, at(20, 16, 21, 0)).await;
+        let recap = &recaps(&h)[0];
+        assert_eq!(recap.0, CHANNEL);
+        assert!(recap.1.contains("New server record!"));
+
+        // A window can only be closed once.
+        close_window(&h.bot, &h.db, CHANNEL, at(20, 16, 21, 0)).await;
+        assert_eq!(recaps(&h).len(), 1);
+
+        h.send(message(2, 10, "weed time", at(21, 16, 20, 0))).await;
+        h.send(message(3, 11, "weed time", at(21, 16, 20, 1))).await;
+        // Nothing happens before the window closes.
+        close_window(&h.bot, &h.db, CHANNEL, at(21, 16, 20, 59)).await;
+        assert_eq!(recaps(&h).len(), 1);
+        close_window(&h.bot, &h.db, CHANNEL, at(21, 16, 21, 0)).await;
+        assert!(recaps(&h)[1].1.contains("New server record!"));
+
+        let history = h.db.recent_chains(Some(GUILD), 5).unwrap();
+        assert_eq!(
+            history.iter().map(|chain| chain.length).collect::<Vec<_>>(),
+            [2, 1]
+        );
+        assert!(h.db.chain_states().unwrap().is_empty());
+
+        // A chain as long as the last one isn't a record.
+        h.send(message(4, 10, "weed time", at(22, 16, 20, 0))).await;
+        h.send(message(5, 11, "weed time", at(22, 16, 20, 1))).await;
+        close_window(&h.bot, &h.db, CHANNEL, at(22, 16, 21, 0)).await;
+        assert!(!recaps(&h)[2].1.contains("record"));
+    }
+
+    #[tokio::test]
+    async fn recaps_records_from_before_the_chain_history() {
+        let h = harness();
+        let recap = |h: &Harness| match h.transport.sent().pop() {
+            Some(Sent::Embed { embed, .. }) => format!("{embed:?}"),
+            sent => panic!("expected a recap, got {sent:?}"),
+        };
+
+        // The guild had a chain of 4 before chains were kept.
+        MessageUpdate {
+            guild: GuildStatsUpdate {
+                longest_chain: Some(4),
+                ..GuildStatsUpdate::new(GUILD)
+            },
+            ..Default::default()
+        }
+        .commit(h.db.as_ref())
+        .unwrap();
+
+        for (id, user) in [(1, 10), (2, 11)] {
+            h.send(message(id, user, "weed time", at(20, 16, 20, 0)))
+                .await;
+        }
+        close_window(&h.bot, &h.db, CHANNEL, at(20, 16, 21, 0)).await;
+        // Still a first for the channel.
+        assert!(recap(&h).contains("New channel record!"));
+
+        for (id, user) in [(3, 10), (4, 11), (5, 12)] {
+            h.send(message(id, user, "weed time", at(21, 16, 20, 0)))
+                .await;
+        }
+        close_window(&h.bot, &h.db, CHANNEL, at(21, 16, 21, 0)).await;
+        let three = recap(&h);
+        assert!(three.contains("New channel record!"));
+        assert!(!three.contains("New server record!"));
+
+        // Tying the guild's record isn't enough.
+        for (id, user) in [(6, 10), (7, 11), (8, 12), (9, 13)] {
+            h.send(message(id, user, "weed time", at(22, 16, 20, 0)))
+                .await;
+        }
+        close_window(&h.bot, &h.db, CHANNEL, at(22, 16, 21, 0)).await;
+        let four = recap(&h);
+        assert!(four.contains("New channel record!"));
+        assert!(!four.contains("New server record!"));
+
+        for (id, user) in [(10, 10), (11, 11), (12, 12), (13, 13), (14, 14)] {
+            h.send(message(id, user, "weed time", at(23, 16, 20, 0)))
+                .await;
+        }
+        close_window(&h.bot, &h.db, CHANNEL, at(23, 16, 21, 0)).await;
+        assert!(recap(&h).contains("New server record!"));
+    }
+
+    #[tokio::test]
+    async fn picks_up_running_chains_after_a_restart() {
+        let h = harness();
+        let other_channel = ChannelId::new(2);
+        let save = |channel_id, message_id, window_start: DateTime<Utc>| MessageUpdate {
+            chain: Some(ChainUpdate::Save(ChainState::new(
+                channel_id,
+                Some(GUILD),
+                MessageId::new(message_id),
+                [UserId::new(10)],
+                1,
+                window_start,
+                window_start + TimeDelta::minutes(1),
+            ))),
+            ..Default::default()
+        };
+        // One chain is still running, the other's window closed while the bot was away.
+        save(CHANNEL, 500, at(20, 16, 20, 0)).commit(&h.db).unwrap();
+        save(other_channel, 501, at(20, 4, 20, 0))
+            .commit(&h.db)
+            .unwrap();
+
+        h.handler.start_up(&h.bot, "weedtime").await;
+
+        assert!(
+            h.transport.sent().iter().any(
+                |sent| matches!(sent, Sent::Commands(count) if *count == stats_commands().len())
+            )
+        );
+        let states = h.db.chain_states().unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].channel_id(), CHANNEL);
+        let history = h.db.recent_chains(Some(GUILD), 5).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].channel_id(), other_channel);
+
+        // The chain carries on from the answer the bot gave before it restarted.
+        h.send(message(1, 11, "weed time", at(20, 16, 20, 40)))
+            .await;
+        let edits = h.edits();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].0, MessageId::new(500));
+        assert!(edits[0].1.ends_with(&combo_to_emojis(1)));
+        assert_eq!(h.user_stats(11).longest_chain, 2);
+    }
+}
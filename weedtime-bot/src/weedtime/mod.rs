@@ -1,2 +1,17 @@
+pub mod chain;
+pub mod clock;
+pub mod commands;
+pub mod config;
+pub mod handler;
+pub mod normalize;
+pub mod queue;
+pub mod recap;
+pub mod recent;
+pub mod scheduler;
+pub mod settings;
+pub mod sim;
 pub mod states;
+pub mod stats;
+pub mod transport;
+pub mod triggers;
 pub mod util;
@@ -0,0 +1,151 @@
+use weedtime_db::data::v2::MatchMode;
+
+/// Rewrites message text so it reads the way it looks, before it is checked against triggers.
+///
+/// Both modes fold Unicode letters that only differ in how they are displayed, like fullwidth
+/// or bold ones, and lowercase everything. Lenient mode also strips markdown and invisible
+/// characters, and folds accents, lookalike letters from other scripts and leetspeak. Runs of
+/// whitespace are collapsed to a single space.
+pub fn normalize(text: &str, mode: MatchMode) -> String {
+    let folded = text
+        .chars()
+        .filter(|&c| mode == MatchMode::Strict || !is_ignored(c))
+        .map(fold_width)
+        .flat_map(char::to_lowercase)
+        .map(|c| match mode {
+            MatchMode::Strict => c,
+            MatchMode::Lenient => fold_lookalike(c),
+        })
+        .collect::<String>();
+
+    folded
+        .split_whitespace()
+        .map(|word| match mode {
+            MatchMode::Strict => word.to_string(),
+            MatchMode::Lenient => fold_leetspeak(word),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Markdown syntax, invisible characters and combining marks, which can be slipped into the
+/// middle of a word without changing how it reads.
+fn is_ignored(c: char) -> bool {
+    matches!(
+        c,
+        '*' | '_' | '~' | '|' | '`' | '\\'
+            | '\u{00AD}'
+            | '\u{200B}'..='\u{200D}'
+            | '\u{2060}'
+            | '\u{FEFF}'
+            | '\u{0300}'..='\u{036F}'
+    )
+}
+
+/// Folds the fullwidth, mathematical, circled and regional indicator forms of ASCII letters and
+/// digits back to ASCII.
+fn fold_width(c: char) -> char {
+    const LETTERS: &[u8; 52] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let code = c as u32;
+    let folded = match code {
+        0x3000 => Some(' '),
+        0xFF01..=0xFF5E => char::from_u32(code - 0xFF01 + 0x21),
+        // Bold, italic, script and the other styles each repeat A-Z and a-z.
+        0x1D400..=0x1D6A3 => Some(LETTERS[((code - 0x1D400) % 52) as usize] as char),
+        0x1D7CE..=0x1D7FF => char::from_digit((code - 0x1D7CE) % 10, 10),
+        0x24B6..=0x24CF => char::from_u32(code - 0x24B6 + 'A' as u32),
+        0x24D0..=0x24E9 => char::from_u32(code - 0x24D0 + 'a' as u32),
+        0x1F130..=0x1F149 => char::from_u32(code - 0x1F130 + 'A' as u32),
+        0x1F150..=0x1F169 => char::from_u32(code - 0x1F150 + 'A' as u32),
+        0x1F170..=0x1F189 => char::from_u32(code - 0x1F170 + 'A' as u32),
+        0x1F1E6..=0x1F1FF => char::from_u32(code - 0x1F1E6 + 'A' as u32),
+        _ => None,
+    };
+
+    folded.unwrap_or(c)
+}
+
+/// Folds lowercase accented letters, and letters from other scripts that look like Latin ones,
+/// to the Latin letter they pass for.
+fn fold_lookalike(c: char) -> char {
+    match c {
+        'à'..='å' | 'ā' | 'ă' | 'ą' | 'а' | 'α' => 'a',
+        'в' | 'β' => 'b',
+        'ç' | 'ć' | 'č' | 'с' | 'ϲ' => 'c',
+        'ď' | 'đ' | 'ԁ' => 'd',
+        'è'..='ë' | 'ē' | 'ė' | 'ę' | 'ě' | 'е' | 'ё' | 'є' | 'ε' => 'e',
+        'ğ' | 'ġ' | 'ɡ' => 'g',
+        'һ' | 'н' => 'h',
+        'ì'..='ï' | 'ī' | 'į' | 'ı' | 'і' | 'ї' | 'ι' => 'i',
+        'ј' => 'j',
+        'к' | 'κ' => 'k',
+        'ł' | 'ӏ' => 'l',
+        'м' => 'm',
+        'ñ' | 'ń' | 'ň' | 'п' | 'η' => 'n',
+        'ò'..='ö' | 'ø' | 'ō' | 'ő' | 'о' | 'ο' | 'σ' => 'o',
+        'р' | 'ρ' => 'p',
+        'ԛ' => 'q',
+        'ř' | 'г' => 'r',
+        'ś' | 'š' | 'ş' | 'ѕ' => 's',
+        'ť' | 'т' | 'τ' => 't',
+        'ù'..='ü' | 'ū' | 'ů' | 'ű' | 'υ' => 'u',
+        'ν' | 'ѵ' => 'v',
+        'ԝ' | 'ω' | 'ш' => 'w',
+        'х' | 'χ' => 'x',
+        'ý' | 'ÿ' | 'у' | 'γ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        _ => c,
+    }
+}
+
+/// Reads digits and symbols in a word as the letters they stand in for. Words without any
+/// letters, like `4:20`, are left alone.
+fn fold_leetspeak(word: &str) -> String {
+    if !word.chars().any(char::is_alphabetic) {
+        return word.to_string();
+    }
+
+    word.chars()
+        .map(|c| match c {
+            '0' => 'o',
+            '1' => 'i',
+            '3' => 'e',
+            '4' | '@' => 'a',
+            '5' | '$' => 's',
+            '7' => 't',
+            '8' => 'b',
+            '9' => 'g',
+            _ => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_text() {
+        for (text, strict, lenient) in [
+            ("Weed Time", "weed time", "weed time"),
+            ("  weed \n\t time  ", "weed time", "weed time"),
+            ("ｗｅｅｄ　ｔｉｍｅ", "weed time", "weed time"),
+            ("𝐖𝐞𝐞𝐝 𝓽𝓲𝓶𝓮", "weed time", "weed time"),
+            ("ⓦⓔⓔⓓ 🇹🇮🇲🇪", "weed time", "weed time"),
+            ("**we**ed __time__", "**we**ed __time__", "weed time"),
+            ("||weed|| ~~time~~", "||weed|| ~~time~~", "weed time"),
+            ("we\u{200B}ed time", "we\u{200B}ed time", "weed time"),
+            ("wéèd tïmé", "wéèd tïmé", "weed time"),
+            ("we\u{0301}ed time", "we\u{0301}ed time", "weed time"),
+            ("wееd tіmе", "wееd tіmе", "weed time"),
+            ("W33D T1ME", "w33d t1me", "weed time"),
+            ("$m0k3 w33d", "$m0k3 w33d", "smoke weed"),
+            ("it's 4:20 now", "it's 4:20 now", "it's 4:20 now"),
+            ("", "", ""),
+        ] {
+            assert_eq!(normalize(text, MatchMode::Strict), strict, "{text:?}");
+            assert_eq!(normalize(text, MatchMode::Lenient), lenient, "{text:?}");
+        }
+    }
+}
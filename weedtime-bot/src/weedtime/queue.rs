@@ -0,0 +1,416 @@
+//! Serenity hands every event to its own task, so two weed times sent in the same second can
+//! be handled at the same time and both start a chain. Messages are queued per channel instead,
+//! and each channel's queue is worked through one job at a time, oldest first. A job is held for
+//! a moment after it comes in, so messages Discord delivers a little out of order can still be
+//! taken in the order they were sent. A channel's task ends once it has been idle for a while,
+//! and the next job starts a new one.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{self, AtomicUsize},
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serenity::all::{ChannelId, MessageId};
+use tokio::sync::mpsc;
+
+use crate::weedtime::clock::Clock;
+
+type Handle<T> = Arc<dyn Fn(T) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+type Senders<T> = Arc<Mutex<HashMap<ChannelId, mpsc::UnboundedSender<Job<T>>>>>;
+
+/// How long a channel's task waits for another job before it ends.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a job is held after it comes in, for anything that happened before it to catch up.
+pub const REORDER_DELAY: TimeDelta = TimeDelta::milliseconds(500);
+
+struct Job<T> {
+    timestamp: DateTime<Utc>,
+    /// The message the job is for. Jobs that aren't for a message go after the messages sent at
+    /// the same time.
+    message_id: Option<MessageId>,
+    received_at: DateTime<Utc>,
+    item: T,
+}
+
+impl<T> Job<T> {
+    fn key(&self) -> (DateTime<Utc>, u64) {
+        (
+            self.timestamp,
+            self.message_id
+                .map_or(u64::MAX, |message_id| message_id.get()),
+        )
+    }
+}
+
+impl<T> PartialEq for Job<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<T> Eq for Job<T> {}
+
+impl<T> PartialOrd for Job<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Job<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// What every channel's task shares.
+struct Worker<T> {
+    handle: Handle<T>,
+    senders: Senders<T>,
+    clock: Arc<dyn Clock>,
+    idle_timeout: Duration,
+    reorder_delay: TimeDelta,
+    /// How many jobs have been queued but not handled yet, across every channel.
+    waiting: Arc<AtomicUsize>,
+}
+
+impl<T> Clone for Worker<T> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            senders: self.senders.clone(),
+            clock: self.clock.clone(),
+            idle_timeout: self.idle_timeout,
+            reorder_delay: self.reorder_delay,
+            waiting: self.waiting.clone(),
+        }
+    }
+}
+
+/// A queue for every channel that has had a job lately, each worked through by its own task.
+pub struct ChannelQueues<T> {
+    worker: Worker<T>,
+}
+
+impl<T: Send + 'static> ChannelQueues<T> {
+    /// Queues that run `handle` on every item, one at a time per channel, holding each for the
+    /// reorder delay on `clock`.
+    pub fn new<F, Fut>(clock: Arc<dyn Clock>, handle: F) -> Self
+    where
+        F: Fn(T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            worker: Worker {
+                handle: Arc::new(move |item| Box::pin(handle(item))),
+                senders: Arc::new(Mutex::new(HashMap::new())),
+                clock,
+                idle_timeout: IDLE_TIMEOUT,
+                reorder_delay: REORDER_DELAY,
+                waiting: Arc::new(AtomicUsize::new(0)),
+            },
+        }
+    }
+
+    /// Queues `item` in `channel_id`, to be handled in the order of `timestamp` and then
+    /// `message_id`. It is held until the reorder delay has passed since `received_at`.
+    pub fn push(
+        &self,
+        channel_id: ChannelId,
+        timestamp: DateTime<Utc>,
+        message_id: Option<MessageId>,
+        received_at: DateTime<Utc>,
+        item: T,
+    ) {
+        let job = Job {
+            timestamp,
+            message_id,
+            received_at,
+            item,
+        };
+
+        let mut senders = self.worker.senders.lock().expect("channel queues poisoned");
+        self.worker.waiting.fetch_add(1, atomic::Ordering::SeqCst);
+        let job = match senders.get(&channel_id) {
+            Some(sender) => match sender.send(job) {
+                Ok(()) => return,
+                // The channel's task is gone, so it gets a new one.
+                Err(mpsc::error::SendError(job)) => job,
+            },
+            None => job,
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        sender.send(job).ok();
+        senders.insert(channel_id, sender);
+        tokio::spawn(run(channel_id, receiver, self.worker.clone()));
+    }
+
+    /// How many jobs are queued and not handled yet, across every channel.
+    #[cfg(test)]
+    pub fn waiting(&self) -> usize {
+        self.worker.waiting.load(atomic::Ordering::SeqCst)
+    }
+}
+
+/// Works through one channel's queue. Jobs that came in while the last one was handled, or
+/// while the next one was held, are taken in the order they happened rather than the order they
+/// arrived in.
+async fn run<T>(
+    channel_id: ChannelId,
+    mut receiver: mpsc::UnboundedReceiver<Job<T>>,
+    worker: Worker<T>,
+) {
+    let mut pending = BinaryHeap::new();
+
+    loop {
+        let job = match tokio::time::timeout(worker.idle_timeout, receiver.recv()).await {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            // Jobs are only sent while the lock is held, so once the queue is found empty under
+            // it, nothing else can come in before the channel is forgotten.
+            Err(_) => {
+                let mut senders = worker.senders.lock().expect("channel queues poisoned");
+                match receiver.try_recv() {
+                    Ok(job) => job,
+                    Err(_) => {
+                        senders.remove(&channel_id);
+                        return;
+                    }
+                }
+            }
+        };
+        pending.push(Reverse(job));
+
+        loop {
+            while let Ok(job) = receiver.try_recv() {
+                pending.push(Reverse(job));
+            }
+            let Some(Reverse(next)) = pending.peek() else {
+                break;
+            };
+
+            // Anything that comes in while the oldest job is held may go before it.
+            let ready_at = next.received_at + worker.reorder_delay;
+            if worker.clock.now() < ready_at {
+                tokio::select! {
+                    _ = worker.clock.sleep_until(ready_at) => {}
+                    Some(job) = receiver.recv() => pending.push(Reverse(job)),
+                }
+                continue;
+            }
+
+            let Some(Reverse(job)) = pending.pop() else {
+                break;
+            };
+            (worker.handle)(job.item).await;
+            worker.waiting.fetch_sub(1, atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::TimeZone;
+    use chrono_tz::America::New_York;
+    use serenity::all::{GuildId, UserId};
+    use tokio::sync::{Notify, mpsc::UnboundedSender};
+    use weedtime_db::data::v4::WeedWindow;
+
+    use super::*;
+    use crate::weedtime::{
+        chain::{Chain, ChainEvent, Outcome, step},
+        clock::{ManualClock, SystemClock},
+    };
+
+    const CHANNEL: ChannelId = ChannelId::new(1);
+
+    fn weed_time(user: u64, millis: i64) -> ChainEvent {
+        let timestamp = New_York
+            .with_ymd_and_hms(2024, 4, 20, 16, 20, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+            + chrono::Duration::milliseconds(millis);
+
+        ChainEvent {
+            channel_id: CHANNEL,
+            guild_id: Some(GuildId::new(2)),
+            user_id: UserId::new(user),
+            timestamp,
+            timezone: New_York,
+            window: WeedWindow::default().find(timestamp, &[New_York]),
+            contains_weed_time: true,
+        }
+    }
+
+    /// Queues that hand what they handle to `sender`, taking jobs as soon as they come in.
+    fn sending_queues<T: Send + 'static>(sender: UnboundedSender<T>) -> ChannelQueues<T> {
+        let mut queues = ChannelQueues::new(Arc::new(SystemClock), move |item| {
+            let sender = sender.clone();
+            async move {
+                sender.send(item).unwrap();
+            }
+        });
+        queues.worker.reorder_delay = TimeDelta::zero();
+        queues
+    }
+
+    /// Handles events the way the bot does, reading the chain, waiting on something, and only
+    /// then writing it back, which loses chains when two run at once.
+    fn chain_queues(
+        outcomes: UnboundedSender<(ChainEvent, Outcome)>,
+        gate: Arc<Notify>,
+    ) -> ChannelQueues<ChainEvent> {
+        let chain = Arc::new(Mutex::new(None::<Chain>));
+        let mut queues = ChannelQueues::new(Arc::new(SystemClock), move |event: ChainEvent| {
+            let chain = chain.clone();
+            let outcomes = outcomes.clone();
+            let gate = gate.clone();
+            async move {
+                let mut current = chain.lock().unwrap().clone();
+                if event.user_id == UserId::new(1) {
+                    gate.notified().await;
+                }
+                tokio::task::yield_now().await;
+                let outcome = step(&mut current, &event);
+                *chain.lock().unwrap() = current;
+                outcomes.send((event, outcome)).unwrap();
+            }
+        });
+        queues.worker.reorder_delay = TimeDelta::zero();
+        queues
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn takes_waiting_messages_oldest_first() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let gate = Arc::new(Notify::new());
+        let queues = chain_queues(sender, gate.clone());
+
+        // The first message holds the queue up while the rest arrive newest first.
+        let first = weed_time(1, 0);
+        queues.push(
+            CHANNEL,
+            first.timestamp,
+            Some(MessageId::new(1)),
+            Utc::now(),
+            first,
+        );
+        for user in (2..10).rev() {
+            let event = weed_time(user, user as i64 * 10);
+            queues.push(
+                CHANNEL,
+                event.timestamp,
+                Some(MessageId::new(user)),
+                Utc::now(),
+                event,
+            );
+        }
+        gate.notify_one();
+
+        let mut order = Vec::new();
+        for _ in 1..10 {
+            let (event, _) = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            order.push(event.user_id.get());
+        }
+        assert_eq!(order, (1..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn channels_run_independently() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let queues = sending_queues(sender);
+
+        for channel in 1..=3 {
+            let channel_id = ChannelId::new(channel);
+            queues.push(channel_id, Utc::now(), None, Utc::now(), channel_id);
+        }
+
+        let mut channels = Vec::new();
+        for _ in 0..3 {
+            channels.push(receiver.recv().await.unwrap().get());
+        }
+        channels.sort();
+        assert_eq!(channels, [1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn forgets_idle_channels() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut queues = sending_queues(sender);
+        queues.worker.idle_timeout = Duration::from_millis(20);
+        let is_queued = |queues: &ChannelQueues<MessageId>| {
+            queues.worker.senders.lock().unwrap().contains_key(&CHANNEL)
+        };
+
+        let push = |queues: &ChannelQueues<MessageId>, id| {
+            let message_id = MessageId::new(id);
+            queues.push(
+                CHANNEL,
+                Utc::now(),
+                Some(message_id),
+                Utc::now(),
+                message_id,
+            );
+        };
+        push(&queues, 1);
+        assert_eq!(receiver.recv().await.unwrap().get(), 1);
+        assert!(is_queued(&queues));
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!is_queued(&queues));
+
+        // The channel picks up again with a new task.
+        push(&queues, 2);
+        assert_eq!(receiver.recv().await.unwrap().get(), 2);
+    }
+
+    #[tokio::test]
+    async fn holds_jobs_until_the_reorder_delay_passes() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let received_at = weed_time(1, 0).timestamp;
+        let clock = ManualClock::new(received_at);
+        let queues = ChannelQueues::new(Arc::new(clock.clone()), move |event: ChainEvent| {
+            let sender = sender.clone();
+            async move {
+                sender.send(event.user_id.get()).unwrap();
+            }
+        });
+
+        // The newest message comes in first, and nothing is handled while it is held.
+        for user in [3, 1, 2] {
+            let event = weed_time(user, user as i64 * 10);
+            queues.push(
+                CHANNEL,
+                event.timestamp,
+                Some(MessageId::new(user)),
+                received_at,
+                event,
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(queues.waiting(), 3);
+
+        clock.advance(REORDER_DELAY);
+        let mut order = Vec::new();
+        for _ in 0..3 {
+            order.push(receiver.recv().await.unwrap());
+        }
+        assert_eq!(order, [1, 2, 3]);
+    }
+}
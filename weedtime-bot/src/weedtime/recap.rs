@@ -0,0 +1,110 @@
+//! Recaps of the chains a window closed on, and the records they beat.
+
+use chrono::{DateTime, Utc};
+use serenity::{
+    all::{ChannelId, GuildId},
+    builder::CreateEmbed,
+    model::colour::Colour,
+};
+use tracing::{error, warn};
+use weedtime_db::data::{Chain as ChainRecord, ChainUpdate, DbUpdate, MessageUpdate, WeedDatabase};
+
+use crate::{Bot, weedtime::states::close_chain};
+
+/// Which records a chain beat when its window closed.
+#[derive(Debug, Clone, Copy)]
+struct RecordsBeaten {
+    channel: bool,
+    server: bool,
+}
+
+fn recap_embed(chain: &ChainRecord, beaten: RecordsBeaten) -> CreateEmbed {
+    let participants = chain
+        .users()
+        .iter()
+        .map(|user_id| format!("<@{user_id}>"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let embed = CreateEmbed::new()
+        .title("Weed Time Is Over")
+        .colour(Colour::DARK_GREEN)
+        .field("Chain", chain.length.to_string(), true)
+        .field("Participants", participants, false);
+
+    if beaten.server {
+        embed.description("New server record!")
+    } else if beaten.channel {
+        embed.description("New channel record!")
+    } else {
+        embed
+    }
+}
+
+/// How long the longest chain in `best` was, or 0 if none has ended yet. `None` if it couldn't be
+/// fetched, when no record is given out.
+fn record_length<E: std::fmt::Debug>(best: Result<Option<ChainRecord>, E>) -> Option<u32> {
+    match best {
+        Ok(best) => Some(best.map_or(0, |best| best.length)),
+        Err(e) => {
+            error!("Failed to fetch the chain record: {e:?}");
+            None
+        }
+    }
+}
+
+/// The longest chain the guild's stats know of, which goes back further than the chain history.
+/// `None` if it couldn't be fetched.
+pub fn stats_record_length(db: &WeedDatabase<'static>, guild_id: Option<GuildId>) -> Option<u32> {
+    let Some(guild_id) = guild_id else {
+        return Some(0);
+    };
+    match db.guild_stats(guild_id) {
+        Ok(stats) => Some(stats.map_or(0, |stats| stats.longest_chain)),
+        Err(e) => {
+            error!("Failed to fetch the stats of guild {guild_id}: {e:?}");
+            None
+        }
+    }
+}
+
+/// Wraps up the chain in `channel_id` if its window has closed by `now`: posts how it went and
+/// moves it into the history.
+pub async fn close_window(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    channel_id: ChannelId,
+    now: DateTime<Utc>,
+) {
+    let Some((chain, server_record)) = close_chain(bot, channel_id, now).await else {
+        return;
+    };
+
+    // Records are checked before the chain joins the history, or it would only tie itself.
+    let guild_id = chain.guild_id();
+    let channel_best = record_length(db.longest_channel_chain(guild_id, channel_id));
+    let server_best = record_length(
+        db.longest_chains(guild_id, 1)
+            .map(|chains| chains.into_iter().next()),
+    );
+    // The stats already count the chain itself, so it is held up against their record from
+    // before it started.
+    let beaten = RecordsBeaten {
+        channel: channel_best.is_some_and(|best| chain.length > best),
+        server: server_best
+            .zip(server_record)
+            .is_some_and(|(best, record)| chain.length > best && chain.length > record),
+    };
+    let recap = recap_embed(&chain, beaten);
+    if let Err(e) = bot.transport.send_embed(channel_id, recap).await {
+        warn!("Failed to post the chain recap in {channel_id}: {e:?}");
+    }
+
+    let update = MessageUpdate {
+        chain: Some(ChainUpdate::Clear(channel_id)),
+        ended_chain: Some(chain),
+        ..Default::default()
+    };
+    if let Err(e) = update.commit(db) {
+        error!("Failed to save the chain that ended in {channel_id}: {e:?}");
+    }
+}
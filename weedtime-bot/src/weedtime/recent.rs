@@ -0,0 +1,64 @@
+use std::collections::{HashSet, VecDeque};
+
+use serenity::all::MessageId;
+
+/// The messages the bot has seen most recently, so one Discord delivers again after a gateway
+/// resume can be dropped before it is handled. Once full, the message seen longest ago is
+/// forgotten; the database still catches it if it comes back after that.
+#[derive(Debug)]
+pub struct RecentMessages {
+    capacity: usize,
+    order: VecDeque<MessageId>,
+    seen: HashSet<MessageId>,
+}
+
+impl RecentMessages {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            order: VecDeque::with_capacity(capacity),
+            seen: HashSet::with_capacity(capacity),
+        }
+    }
+
+    /// Remembers `message_id`, and returns whether it is new.
+    pub fn insert(&mut self, message_id: MessageId) -> bool {
+        if self.seen.contains(&message_id) {
+            if let Some(index) = self.order.iter().position(|&id| id == message_id) {
+                self.order.remove(index);
+            }
+            self.order.push_back(message_id);
+            return false;
+        }
+
+        if self.order.len() >= self.capacity
+            && let Some(oldest) = self.order.pop_front()
+        {
+            self.seen.remove(&oldest);
+        }
+        self.order.push_back(message_id);
+        self.seen.insert(message_id);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_the_least_recently_seen_message() {
+        let mut recent = RecentMessages::new(2);
+        let id = MessageId::new;
+
+        assert!(recent.insert(id(1)));
+        assert!(recent.insert(id(2)));
+        assert!(!recent.insert(id(1)));
+
+        // 2 was seen longest ago, so it makes room for 3.
+        assert!(recent.insert(id(3)));
+        assert!(!recent.insert(id(1)));
+        assert!(!recent.insert(id(3)));
+        assert!(recent.insert(id(2)));
+    }
+}
@@ -0,0 +1,238 @@
+//! Chains used to just stop once their window closed, with nobody told how they went. Every
+//! chain that starts is put on a [`WindowSchedule`], and [`run_scheduler`] wakes when its window
+//! closes so the chain can be wrapped up.
+
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serenity::all::ChannelId;
+use tokio::sync::Notify;
+
+use crate::weedtime::clock::Clock;
+
+/// The channels with a chain running, by when the chain's window closes.
+#[derive(Debug, Default)]
+pub struct WindowSchedule {
+    pending: Mutex<BTreeSet<(DateTime<Utc>, ChannelId)>>,
+    changed: Notify,
+}
+
+impl WindowSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Has the chain in `channel_id` wrapped up at `closes_at`.
+    pub fn add(&self, channel_id: ChannelId, closes_at: DateTime<Utc>) {
+        self.pending
+            .lock()
+            .expect("window schedule poisoned")
+            .insert((closes_at, channel_id));
+        // The scheduler may be asleep until a later window.
+        self.changed.notify_one();
+    }
+
+    /// When the next window closes, if any chain is running.
+    pub fn next(&self) -> Option<DateTime<Utc>> {
+        self.pending
+            .lock()
+            .expect("window schedule poisoned")
+            .first()
+            .map(|&(closes_at, _)| closes_at)
+    }
+
+    /// Takes every channel whose window has closed by `now`, soonest first.
+    pub fn take_due(&self, now: DateTime<Utc>) -> Vec<ChannelId> {
+        let mut pending = self.pending.lock().expect("window schedule poisoned");
+        let mut due = Vec::new();
+        while let Some(&(closes_at, channel_id)) = pending.first() {
+            if closes_at > now {
+                break;
+            }
+            pending.pop_first();
+            due.push(channel_id);
+        }
+        due
+    }
+}
+
+/// Calls `close` for every channel on `schedule` once its window has closed by `clock`, and
+/// never returns.
+pub async fn run_scheduler<F, Fut>(clock: Arc<dyn Clock>, schedule: Arc<WindowSchedule>, close: F)
+where
+    F: Fn(ChannelId, DateTime<Utc>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    loop {
+        // A chain added while the loop isn't waiting leaves a permit behind, so the next wait
+        // returns right away and it is still seen.
+        let now = clock.now();
+        match schedule.next() {
+            Some(closes_at) if closes_at <= now => {
+                for channel_id in schedule.take_due(now) {
+                    close(channel_id, now).await;
+                }
+            }
+            Some(closes_at) => {
+                tokio::select! {
+                    _ = clock.sleep_until(closes_at) => {}
+                    _ = schedule.changed.notified() => {}
+                }
+            }
+            None => schedule.changed.notified().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{NaiveDate, NaiveTime, TimeDelta, TimeZone};
+    use chrono_tz::{America::New_York, Australia::Sydney, Europe::London, Tz};
+    use serenity::all::UserId;
+    use tokio::sync::mpsc;
+    use weedtime_db::data::v4::WeedWindow;
+
+    use super::*;
+    use crate::weedtime::{
+        chain::{ChainEvent, step},
+        clock::ManualClock,
+    };
+
+    fn at(minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 4, 20, 20, minute, second)
+            .unwrap()
+    }
+
+    /// Runs the scheduler on `clock`, sending every channel it closes.
+    fn start(
+        clock: &ManualClock,
+        schedule: &Arc<WindowSchedule>,
+    ) -> mpsc::UnboundedReceiver<(ChannelId, DateTime<Utc>)> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(run_scheduler(
+            Arc::new(clock.clone()),
+            schedule.clone(),
+            move |channel_id, now| {
+                sender.send((channel_id, now)).unwrap();
+                async {}
+            },
+        ));
+        receiver
+    }
+
+    async fn closed(
+        receiver: &mut mpsc::UnboundedReceiver<(ChannelId, DateTime<Utc>)>,
+    ) -> (u64, DateTime<Utc>) {
+        let (channel_id, now) = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        (channel_id.get(), now)
+    }
+
+    /// Gives the scheduler a chance to run, to check that it hasn't closed anything.
+    async fn nothing_closed(receiver: &mut mpsc::UnboundedReceiver<(ChannelId, DateTime<Utc>)>) {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_windows_when_they_end() {
+        let clock = ManualClock::new(at(20, 0));
+        let schedule = Arc::new(WindowSchedule::new());
+        schedule.add(ChannelId::new(1), at(21, 0));
+        schedule.add(ChannelId::new(2), at(21, 5));
+        let mut receiver = start(&clock, &schedule);
+
+        nothing_closed(&mut receiver).await;
+
+        clock.advance(TimeDelta::seconds(60));
+        assert_eq!(closed(&mut receiver).await, (1, at(21, 0)));
+        nothing_closed(&mut receiver).await;
+
+        // Windows that both closed while it was waiting are closed together, soonest first.
+        schedule.add(ChannelId::new(3), at(21, 3));
+        clock.advance(TimeDelta::seconds(10));
+        assert_eq!(closed(&mut receiver).await, (3, at(21, 10)));
+        assert_eq!(closed(&mut receiver).await, (2, at(21, 10)));
+        nothing_closed(&mut receiver).await;
+    }
+
+    #[tokio::test]
+    async fn wakes_for_a_window_closing_sooner() {
+        let clock = ManualClock::new(at(20, 0));
+        let schedule = Arc::new(WindowSchedule::new());
+        let mut receiver = start(&clock, &schedule);
+
+        // The scheduler is asleep until 4:21 PM when a window closing at 4:20:30 comes in.
+        schedule.add(ChannelId::new(1), at(21, 0));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        schedule.add(ChannelId::new(2), at(20, 30));
+
+        clock.advance(TimeDelta::seconds(30));
+        assert_eq!(closed(&mut receiver).await, (2, at(20, 30)));
+        nothing_closed(&mut receiver).await;
+
+        clock.advance(TimeDelta::seconds(30));
+        assert_eq!(closed(&mut receiver).await, (1, at(21, 0)));
+    }
+
+    /// Starts a chain at 4:20 PM in `timezone` on each of `days`, the way the bot does, and
+    /// checks it is closed a minute later on that day's clock, whatever the offset was.
+    async fn closes_at_the_local_window_end(timezone: Tz, days: &[&str]) {
+        let start_of = |day: &str| {
+            let date = NaiveDate::parse_from_str(day, "%Y-%m-%d").unwrap();
+            timezone
+                .from_local_datetime(&date.and_hms_opt(16, 20, 10).unwrap())
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        let clock = ManualClock::new(start_of(days[0]) - TimeDelta::days(1));
+        let schedule = Arc::new(WindowSchedule::new());
+        let mut receiver = start(&clock, &schedule);
+
+        for (channel, day) in (1..).zip(days) {
+            clock.set(start_of(day));
+            let now = clock.now();
+            let mut chain = None;
+            step(
+                &mut chain,
+                &ChainEvent {
+                    channel_id: ChannelId::new(channel),
+                    guild_id: None,
+                    user_id: UserId::new(1),
+                    timestamp: now,
+                    timezone,
+                    window: WeedWindow::default().find(now, &[timezone]),
+                    contains_weed_time: true,
+                },
+            );
+            let closes_at = chain.unwrap().expires_at;
+            assert_eq!(
+                closes_at.with_timezone(&timezone).time(),
+                NaiveTime::from_hms_opt(16, 21, 0).unwrap(),
+                "{day} in {timezone}"
+            );
+            schedule.add(ChannelId::new(channel), closes_at);
+
+            clock.advance(TimeDelta::seconds(49));
+            nothing_closed(&mut receiver).await;
+            clock.advance(TimeDelta::seconds(1));
+            assert_eq!(closed(&mut receiver).await, (channel, closes_at));
+        }
+    }
+
+    #[tokio::test]
+    async fn closes_windows_on_the_days_around_dst_changes() {
+        closes_at_the_local_window_end(New_York, &["2024-03-09", "2024-03-10", "2024-03-11"]).await;
+        closes_at_the_local_window_end(New_York, &["2024-11-02", "2024-11-03", "2024-11-04"]).await;
+        closes_at_the_local_window_end(London, &["2024-03-30", "2024-03-31", "2024-04-01"]).await;
+        closes_at_the_local_window_end(Sydney, &["2024-04-06", "2024-04-07", "2024-04-08"]).await;
+    }
+}
@@ -0,0 +1,816 @@
+//! The commands that change how a guild, or someone in it, is handled.
+
+use chrono::NaiveTime;
+use chrono_tz::{TZ_VARIANTS, Tz};
+use serenity::{
+    all::{ChannelId, CommandInteraction, ResolvedOption, ResolvedValue},
+    builder::{
+        AutocompleteChoice, CreateAutocompleteResponse, CreateEmbed, CreateInteractionResponse,
+    },
+    model::colour::Colour,
+};
+use tracing::error;
+use weedtime_db::data::{
+    DbUpdate, GuildConfig, GuildConfigUpdate, UserConfigUpdate, WeedDatabase,
+    v1::{Trigger, TriggerKind},
+    v2::MatchMode,
+    v4::WeedWindow,
+    v5::TimezoneSource,
+    v6::{DEFAULT_TIMEZONE, ResponseMode},
+    v7::ChannelFilter,
+};
+
+use crate::{
+    Bot,
+    weedtime::{
+        commands::{respond_with_content, respond_with_embed},
+        config::{guild_config, refresh_guild_config, refresh_user_config},
+        triggers::{compile_regex, words},
+    },
+};
+
+fn timezone_choices(input: &str) -> Vec<AutocompleteChoice> {
+    let needle = input.trim().to_lowercase().replace(' ', "_");
+    let mut starts_with = Vec::new();
+    let mut contains = Vec::new();
+
+    for timezone in TZ_VARIANTS {
+        let timezone = timezone.to_string();
+        let comparable = timezone.to_lowercase();
+
+        if needle.is_empty() || comparable.starts_with(&needle) {
+            starts_with.push(timezone);
+        } else if comparable.contains(&needle) {
+            contains.push(timezone);
+        }
+    }
+
+    starts_with
+        .into_iter()
+        .chain(contains)
+        .take(25)
+        .map(AutocompleteChoice::from)
+        .collect()
+}
+
+pub async fn handle_timezone_autocomplete(
+    bot: &Bot,
+    command: &CommandInteraction,
+) -> Result<(), serenity::Error> {
+    let input = command
+        .data
+        .autocomplete()
+        .map(|option| option.value)
+        .unwrap_or_default();
+
+    bot.transport
+        .create_response(
+            command.id,
+            &command.token,
+            CreateInteractionResponse::Autocomplete(
+                CreateAutocompleteResponse::new().set_choices(timezone_choices(input)),
+            ),
+        )
+        .await
+}
+
+pub async fn guild_timezone(
+    bot: &Bot,
+    guild_id: Option<serenity::all::GuildId>,
+    db: &WeedDatabase<'static>,
+) -> Tz {
+    match guild_id {
+        Some(guild_id) => guild_config(bot, db, guild_id).await.timezone,
+        None => DEFAULT_TIMEZONE,
+    }
+}
+
+pub async fn handle_timezone_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some(guild_id) = command.guild_id else {
+        return respond_with_content(bot, command, "Timezone can only be set in a server.").await;
+    };
+
+    let Some(timezone_input) =
+        command
+            .data
+            .options()
+            .into_iter()
+            .find_map(|option| match option.value {
+                ResolvedValue::String(value) if option.name == "timezone" => Some(value),
+                _ => None,
+            })
+    else {
+        return respond_with_content(bot, command, "Missing timezone.").await;
+    };
+
+    let Ok(timezone) = timezone_input.parse::<Tz>() else {
+        return respond_with_content(
+            bot,
+            command,
+            format!("`{timezone_input}` is not a valid IANA timezone."),
+        )
+        .await;
+    };
+
+    let update = GuildConfigUpdate {
+        timezone: Some(timezone),
+        ..GuildConfigUpdate::new(guild_id)
+    };
+
+    if let Err(e) = update.commit(db) {
+        error!("Failed to update timezone for {guild_id}: {e:?}");
+        return respond_with_content(bot, command, "Failed to save the server timezone.").await;
+    }
+    refresh_guild_config(bot, db, guild_id).await;
+
+    respond_with_content(
+        bot,
+        command,
+        format!("Server timezone set to `{timezone}`."),
+    )
+    .await
+}
+
+pub async fn handle_user_timezone_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let user_id = command.user.id;
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
+        ..
+    }) = options.first()
+    else {
+        return respond_with_content(bot, command, "Missing subcommand.").await;
+    };
+
+    let (update, reply) = match *subcommand {
+        "set" => {
+            let Some(timezone_input) = options.iter().find_map(|option| match option.value {
+                ResolvedValue::String(value) if option.name == "timezone" => Some(value),
+                _ => None,
+            }) else {
+                return respond_with_content(bot, command, "Missing timezone.").await;
+            };
+
+            let Ok(timezone) = timezone_input.parse::<Tz>() else {
+                return respond_with_content(
+                    bot,
+                    command,
+                    format!("`{timezone_input}` is not a valid IANA timezone."),
+                )
+                .await;
+            };
+
+            let update = UserConfigUpdate {
+                timezone: Some(timezone),
+                ..UserConfigUpdate::new(user_id)
+            };
+            (update, format!("Your timezone is set to `{timezone}`."))
+        }
+        "clear" => {
+            let update = UserConfigUpdate {
+                clear_timezone: true,
+                ..UserConfigUpdate::new(user_id)
+            };
+            (update, "Your timezone has been cleared.".to_string())
+        }
+        _ => return respond_with_content(bot, command, "Missing subcommand.").await,
+    };
+
+    if let Err(e) = update.commit(db) {
+        error!("Failed to update timezone for {user_id}: {e:?}");
+        return respond_with_content(bot, command, "Failed to save your timezone.").await;
+    }
+    refresh_user_config(bot, db, user_id).await;
+
+    respond_with_content(bot, command, reply).await
+}
+
+const MAX_TRIGGERS: usize = 25;
+
+pub fn match_mode_value(mode: MatchMode) -> &'static str {
+    match mode {
+        MatchMode::Strict => "strict",
+        MatchMode::Lenient => "lenient",
+    }
+}
+
+pub fn match_mode_label(mode: MatchMode) -> &'static str {
+    match mode {
+        MatchMode::Strict => "Strict",
+        MatchMode::Lenient => "Lenient",
+    }
+}
+
+fn parse_match_mode(value: &str) -> Option<MatchMode> {
+    [MatchMode::Strict, MatchMode::Lenient]
+        .into_iter()
+        .find(|&mode| match_mode_value(mode) == value)
+}
+
+fn format_trigger(trigger: &Trigger) -> String {
+    match trigger.kind {
+        TriggerKind::Phrase => format!("`{}`", trigger.pattern),
+        TriggerKind::Regex => format!("`{}` (regex)", trigger.pattern),
+    }
+}
+
+pub async fn handle_trigger_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some(guild_id) = command.guild_id else {
+        return respond_with_content(bot, command, "Triggers can only be set in a server.").await;
+    };
+
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
+        ..
+    }) = options.first()
+    else {
+        return respond_with_content(bot, command, "Missing subcommand.").await;
+    };
+
+    let pattern = options.iter().find_map(|option| match option.value {
+        ResolvedValue::String(value) if option.name == "pattern" => Some(value.trim()),
+        _ => None,
+    });
+    let is_regex = options.iter().any(|option| {
+        matches!(option.value, ResolvedValue::Boolean(true)) && option.name == "regex"
+    });
+    let match_mode = options.iter().find_map(|option| match option.value {
+        ResolvedValue::String(value) if option.name == "mode" => parse_match_mode(value),
+        _ => None,
+    });
+
+    let config = match db.guild_config(guild_id) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to fetch triggers for {guild_id}: {e:?}");
+            return respond_with_content(bot, command, "Failed to load the server's triggers.")
+                .await;
+        }
+    };
+
+    let (update, reply) = match (*subcommand, pattern) {
+        ("add", Some(pattern)) => {
+            if config.triggers.len() >= MAX_TRIGGERS {
+                return respond_with_content(
+                    bot,
+                    command,
+                    format!("A server can have at most {MAX_TRIGGERS} triggers."),
+                )
+                .await;
+            }
+
+            let trigger = if is_regex {
+                if let Err(e) = compile_regex(pattern) {
+                    return respond_with_content(
+                        bot,
+                        command,
+                        format!("`{pattern}` is not a valid regex:\n```\n{e}\n```"),
+                    )
+                    .await;
+                }
+                Trigger::regex(pattern)
+            } else {
+                if words(pattern).is_empty() {
+                    return respond_with_content(
+                        bot,
+                        command,
+                        "A trigger phrase needs at least one word.",
+                    )
+                    .await;
+                }
+                Trigger::phrase(pattern)
+            };
+
+            let reply = format!("Added {}.", format_trigger(&trigger));
+            let update = GuildConfigUpdate {
+                add_trigger: Some(trigger),
+                ..GuildConfigUpdate::new(guild_id)
+            };
+            (update, reply)
+        }
+        ("remove", Some(pattern)) => {
+            if !config
+                .triggers
+                .iter()
+                .any(|trigger| trigger.pattern == pattern)
+            {
+                return respond_with_content(
+                    bot,
+                    command,
+                    format!("`{pattern}` is not one of this server's triggers."),
+                )
+                .await;
+            }
+
+            let update = GuildConfigUpdate {
+                remove_trigger: Some(pattern.to_string()),
+                ..GuildConfigUpdate::new(guild_id)
+            };
+            (update, format!("Removed `{pattern}`."))
+        }
+        ("list", _) => {
+            let content = if config.triggers.is_empty() {
+                "This server has no triggers, so nothing counts as a weed time.".to_string()
+            } else {
+                let triggers = config
+                    .triggers
+                    .iter()
+                    .map(|trigger| format!("- {}", format_trigger(trigger)))
+                    .collect::<Vec<_>>();
+                format!(
+                    "This server's triggers ({} mode):\n{}",
+                    match_mode_value(config.match_mode),
+                    triggers.join("\n")
+                )
+            };
+            return respond_with_content(bot, command, content).await;
+        }
+        ("mode", _) => {
+            let Some(match_mode) = match_mode else {
+                return respond_with_content(bot, command, "Missing match mode.").await;
+            };
+
+            let update = GuildConfigUpdate {
+                match_mode: Some(match_mode),
+                ..GuildConfigUpdate::new(guild_id)
+            };
+            let reply = format!(
+                "Triggers now match in {} mode.",
+                match_mode_value(match_mode)
+            );
+            (update, reply)
+        }
+        _ => return respond_with_content(bot, command, "Missing trigger pattern.").await,
+    };
+
+    if let Err(e) = update.commit(db) {
+        error!("Failed to update triggers for {guild_id}: {e:?}");
+        return respond_with_content(bot, command, "Failed to save the server's triggers.").await;
+    }
+    refresh_guild_config(bot, db, guild_id).await;
+
+    respond_with_content(bot, command, reply).await
+}
+
+const MAX_EXTRA_TIMES: usize = 10;
+pub const MAX_GRACE_SECONDS: u32 = 300;
+
+fn parse_time_of_day(input: &str) -> Option<NaiveTime> {
+    let input = input.trim().to_uppercase();
+    ["%H:%M", "%I:%M %p", "%I:%M%p"]
+        .into_iter()
+        .find_map(|format| NaiveTime::parse_from_str(&input, format).ok())
+}
+
+fn format_time_of_day(time: NaiveTime) -> String {
+    time.format("%-I:%M %p").to_string()
+}
+
+pub fn timezone_source_value(source: TimezoneSource) -> &'static str {
+    match source {
+        TimezoneSource::Guild => "server",
+        TimezoneSource::Author => "author",
+        TimezoneSource::Either => "either",
+    }
+}
+
+pub fn timezone_source_label(source: TimezoneSource) -> &'static str {
+    match source {
+        TimezoneSource::Guild => "The server's",
+        TimezoneSource::Author => "The author's",
+        TimezoneSource::Either => "Either the server's or the author's",
+    }
+}
+
+fn parse_timezone_source(value: &str) -> Option<TimezoneSource> {
+    [
+        TimezoneSource::Guild,
+        TimezoneSource::Author,
+        TimezoneSource::Either,
+    ]
+    .into_iter()
+    .find(|&source| timezone_source_value(source) == value)
+}
+
+fn format_window(window: &WeedWindow, timezone_source: TimezoneSource) -> String {
+    let times = window.times();
+    if times.is_empty() {
+        return "No times count as weed time in this server.".to_string();
+    }
+
+    let times = times
+        .into_iter()
+        .map(|time| format!("`{}`", format_time_of_day(time)))
+        .collect::<Vec<_>>();
+    let grace = match window.grace_seconds {
+        0 => String::new(),
+        1 => " with a 1 second grace period".to_string(),
+        seconds => format!(" with a {seconds} second grace period"),
+    };
+    let place = match (window.anywhere, timezone_source) {
+        (true, _) => "anywhere in the world",
+        (false, TimezoneSource::Guild) => "this server's timezone",
+        (false, TimezoneSource::Author) => "each member's own timezone",
+        (false, TimezoneSource::Either) => "this server's timezone or each member's own",
+    };
+    format!("Weed time is at {} in {place}{grace}.", times.join(", "))
+}
+
+pub async fn handle_window_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some(guild_id) = command.guild_id else {
+        return respond_with_content(bot, command, "Weed time can only be set in a server.").await;
+    };
+
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
+        ..
+    }) = options.first()
+    else {
+        return respond_with_content(bot, command, "Missing subcommand.").await;
+    };
+
+    let flag = |name: &str| {
+        options.iter().find_map(|option| match option.value {
+            ResolvedValue::Boolean(value) if option.name == name => Some(value),
+            _ => None,
+        })
+    };
+    let grace_seconds = options.iter().find_map(|option| match option.value {
+        ResolvedValue::Integer(value) if option.name == "grace" => u32::try_from(value).ok(),
+        _ => None,
+    });
+    let time_input = options.iter().find_map(|option| match option.value {
+        ResolvedValue::String(value) if option.name == "time" => Some(value),
+        _ => None,
+    });
+    let timezone_source = options.iter().find_map(|option| match option.value {
+        ResolvedValue::String(value) if option.name == "timezone" => parse_timezone_source(value),
+        _ => None,
+    });
+
+    let config = match db.guild_config(guild_id) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Failed to fetch weed time window for {guild_id}: {e:?}");
+            return respond_with_content(bot, command, "Failed to load the server's weed time.")
+                .await;
+        }
+    };
+    let window = config.window;
+
+    let update = match (*subcommand, time_input) {
+        ("show", _) => {
+            let content = format_window(&window, config.timezone_source);
+            return respond_with_content(bot, command, content).await;
+        }
+        ("set", _) => {
+            let update = GuildConfigUpdate {
+                am: flag("am"),
+                pm: flag("pm"),
+                grace_seconds: grace_seconds.map(|seconds| seconds.min(MAX_GRACE_SECONDS)),
+                anywhere: flag("anywhere"),
+                timezone_source,
+                ..GuildConfigUpdate::new(guild_id)
+            };
+            if update.am.is_none()
+                && update.pm.is_none()
+                && update.grace_seconds.is_none()
+                && update.anywhere.is_none()
+                && update.timezone_source.is_none()
+            {
+                return respond_with_content(bot, command, "Nothing to change.").await;
+            }
+            update
+        }
+        (subcommand @ ("add" | "remove"), Some(time_input)) => {
+            let Some(time) = parse_time_of_day(time_input) else {
+                return respond_with_content(
+                    bot,
+                    command,
+                    format!("`{time_input}` is not a time of day, like 7:10 PM or 19:10."),
+                )
+                .await;
+            };
+
+            if subcommand == "add" {
+                if window.extra_times.len() >= MAX_EXTRA_TIMES {
+                    return respond_with_content(
+                        bot,
+                        command,
+                        format!("A server can have at most {MAX_EXTRA_TIMES} extra times."),
+                    )
+                    .await;
+                }
+                GuildConfigUpdate {
+                    add_time: Some(time),
+                    ..GuildConfigUpdate::new(guild_id)
+                }
+            } else {
+                if !window.extra_times.contains(&time) {
+                    return respond_with_content(
+                        bot,
+                        command,
+                        format!(
+                            "`{}` is not one of this server's extra times.",
+                            format_time_of_day(time)
+                        ),
+                    )
+                    .await;
+                }
+                GuildConfigUpdate {
+                    remove_time: Some(time),
+                    ..GuildConfigUpdate::new(guild_id)
+                }
+            }
+        }
+        _ => return respond_with_content(bot, command, "Missing time.").await,
+    };
+
+    if let Err(e) = update.commit(db) {
+        error!("Failed to update weed time window for {guild_id}: {e:?}");
+        return respond_with_content(bot, command, "Failed to save the server's weed time.").await;
+    }
+
+    let content = match refresh_guild_config(bot, db, guild_id).await {
+        Some(config) => format_window(&config.window, config.timezone_source),
+        None => "Saved the server's weed time.".to_string(),
+    };
+    respond_with_content(bot, command, content).await
+}
+
+pub fn response_mode_value(mode: ResponseMode) -> &'static str {
+    match mode {
+        ResponseMode::Image => "image",
+        ResponseMode::Text => "text",
+    }
+}
+
+pub fn response_mode_label(mode: ResponseMode) -> &'static str {
+    match mode {
+        ResponseMode::Image => "With a picture",
+        ResponseMode::Text => "Text only",
+    }
+}
+
+fn parse_response_mode(value: &str) -> Option<ResponseMode> {
+    [ResponseMode::Image, ResponseMode::Text]
+        .into_iter()
+        .find(|&mode| response_mode_value(mode) == value)
+}
+
+/// The locales a guild can pick, by the names Discord gives them, with what they are shown as.
+pub const LOCALES: [(&str, &str); 12] = [
+    ("en-US", "English (US)"),
+    ("en-GB", "English (UK)"),
+    ("de", "German"),
+    ("es-ES", "Spanish"),
+    ("fr", "French"),
+    ("it", "Italian"),
+    ("nl", "Dutch"),
+    ("pl", "Polish"),
+    ("pt-BR", "Portuguese (Brazil)"),
+    ("sv-SE", "Swedish"),
+    ("ja", "Japanese"),
+    ("ko", "Korean"),
+];
+
+/// What a locale is shown as. Ones that are no longer offered are shown as they are stored.
+fn locale_label(locale: &str) -> &str {
+    LOCALES
+        .iter()
+        .find_map(|&(value, label)| (value == locale).then_some(label))
+        .unwrap_or(locale)
+}
+
+fn format_channels(filter: &ChannelFilter) -> String {
+    let mention = |channels: Vec<ChannelId>| {
+        channels
+            .into_iter()
+            .map(|channel_id| format!("<#{channel_id}>"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let allowed = filter.allowed();
+    let denied = filter.denied();
+    let watched = if allowed.is_empty() {
+        "Watching every channel".to_string()
+    } else {
+        format!("Only watching {}", mention(allowed))
+    };
+    if denied.is_empty() {
+        format!("{watched}.")
+    } else {
+        format!("{watched}, except {}.", mention(denied))
+    }
+}
+
+pub async fn handle_channels_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some(guild_id) = command.guild_id else {
+        return respond_with_content(bot, command, "Channels can only be chosen in a server.")
+            .await;
+    };
+
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
+        ..
+    }) = options.first()
+    else {
+        return respond_with_content(bot, command, "Missing subcommand.").await;
+    };
+
+    let channel_id = options.iter().find_map(|option| match option.value {
+        ResolvedValue::Channel(channel) if option.name == "channel" => Some(channel.id),
+        _ => None,
+    });
+
+    let update = match (*subcommand, channel_id) {
+        ("list", _) => {
+            let content = match db.guild_config(guild_id) {
+                Ok(config) => format_channels(&config.channels),
+                Err(e) => {
+                    error!("Failed to fetch channels for {guild_id}: {e:?}");
+                    "Failed to load the server's channels.".to_string()
+                }
+            };
+            return respond_with_content(bot, command, content).await;
+        }
+        ("allow", Some(channel_id)) => GuildConfigUpdate {
+            allow_channel: Some(channel_id),
+            ..GuildConfigUpdate::new(guild_id)
+        },
+        ("deny", Some(channel_id)) => GuildConfigUpdate {
+            deny_channel: Some(channel_id),
+            ..GuildConfigUpdate::new(guild_id)
+        },
+        ("clear", Some(channel_id)) => GuildConfigUpdate {
+            clear_channel: Some(channel_id),
+            ..GuildConfigUpdate::new(guild_id)
+        },
+        _ => return respond_with_content(bot, command, "Missing channel.").await,
+    };
+
+    if let Err(e) = update.commit(db) {
+        error!("Failed to update channels for {guild_id}: {e:?}");
+        return respond_with_content(bot, command, "Failed to save the server's channels.").await;
+    }
+
+    let content = match refresh_guild_config(bot, db, guild_id).await {
+        Some(config) => format_channels(&config.channels),
+        None => "Saved the server's channels.".to_string(),
+    };
+    respond_with_content(bot, command, content).await
+}
+
+fn config_embed(name: String, config: &GuildConfig) -> CreateEmbed {
+    let triggers = config
+        .triggers
+        .iter()
+        .map(format_trigger)
+        .collect::<Vec<_>>();
+    let triggers = if triggers.is_empty() {
+        "None".to_string()
+    } else {
+        triggers.join(", ")
+    };
+
+    CreateEmbed::new()
+        .title(format!("{name} Weed Time Settings"))
+        .colour(Colour::DARK_GREEN)
+        .field("Timezone", format!("`{}`", config.timezone), true)
+        .field(
+            "Whose timezone",
+            timezone_source_label(config.timezone_source),
+            true,
+        )
+        .field("Responses", response_mode_label(config.response_mode), true)
+        .field("Locale", locale_label(&config.locale), true)
+        .field(
+            "Weed time",
+            format_window(&config.window, config.timezone_source),
+            false,
+        )
+        .field("Channels", format_channels(&config.channels), false)
+        .field("Triggers", triggers, false)
+        .field("Matching", match_mode_label(config.match_mode), true)
+}
+
+pub async fn handle_config_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some(guild_id) = command.guild_id else {
+        return respond_with_content(bot, command, "Settings are only available in a server.")
+            .await;
+    };
+
+    let options = command.data.options();
+    let Some(ResolvedOption {
+        name: subcommand,
+        value: ResolvedValue::SubCommand(options),
+        ..
+    }) = options.first()
+    else {
+        return respond_with_content(bot, command, "Missing subcommand.").await;
+    };
+
+    match *subcommand {
+        "show" => {
+            let config = match db.guild_config(guild_id) {
+                Ok(config) => config,
+                Err(e) => {
+                    error!("Failed to fetch settings for {guild_id}: {e:?}");
+                    return respond_with_content(
+                        bot,
+                        command,
+                        "Failed to load the server's settings.",
+                    )
+                    .await;
+                }
+            };
+
+            let guild = bot.transport.partial_guild(guild_id).await?;
+            respond_with_embed(bot, command, config_embed(guild.name, &config)).await
+        }
+        "response" => {
+            let Some(response_mode) = options.iter().find_map(|option| match option.value {
+                ResolvedValue::String(value) if option.name == "mode" => parse_response_mode(value),
+                _ => None,
+            }) else {
+                return respond_with_content(bot, command, "Missing response mode.").await;
+            };
+
+            let update = GuildConfigUpdate {
+                response_mode: Some(response_mode),
+                ..GuildConfigUpdate::new(guild_id)
+            };
+            if let Err(e) = update.commit(db) {
+                error!("Failed to update response mode for {guild_id}: {e:?}");
+                return respond_with_content(bot, command, "Failed to save the response mode.")
+                    .await;
+            }
+            refresh_guild_config(bot, db, guild_id).await;
+
+            respond_with_content(
+                bot,
+                command,
+                format!(
+                    "Responses set to {}.",
+                    response_mode_label(response_mode).to_lowercase()
+                ),
+            )
+            .await
+        }
+        "locale" => {
+            let Some(&(locale, label)) = options.iter().find_map(|option| match option.value {
+                ResolvedValue::String(value) if option.name == "locale" => {
+                    LOCALES.iter().find(|&&(locale, _)| locale == value)
+                }
+                _ => None,
+            }) else {
+                return respond_with_content(bot, command, "Missing locale.").await;
+            };
+
+            let update = GuildConfigUpdate {
+                locale: Some(locale.to_string()),
+                ..GuildConfigUpdate::new(guild_id)
+            };
+            if let Err(e) = update.commit(db) {
+                error!("Failed to update locale for {guild_id}: {e:?}");
+                return respond_with_content(bot, command, "Failed to save the locale.").await;
+            }
+            refresh_guild_config(bot, db, guild_id).await;
+
+            respond_with_content(bot, command, format!("Locale set to {label}.")).await
+        }
+        _ => respond_with_content(bot, command, "Missing subcommand.").await,
+    }
+}
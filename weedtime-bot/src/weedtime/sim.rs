@@ -0,0 +1,218 @@
+//! Replays a transcript of messages through the same checks and chains as the bot, against a
+//! database in memory, to see what the bot would have said without connecting to Discord.
+
+use std::{collections::BTreeSet, error::Error, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serenity::{
+    all::{ChannelId, GuildId, Message, MessageId, Timestamp, User, UserId},
+    prelude::RwLock,
+};
+use weedtime_db::data::{GuildStats, UserStats, WeedDatabase};
+
+use crate::{
+    Bot, shared_data,
+    weedtime::{
+        clock::{Clock, ManualClock},
+        handler::{accept_message, process_message},
+        recap::close_window,
+        scheduler::WindowSchedule,
+        transport::{RecordingTransport, Sent},
+    },
+};
+
+/// The first millisecond of 2015, which Discord counts message ids from.
+const DISCORD_EPOCH: i64 = 1_420_070_400_000;
+
+/// One line of a transcript. `guild` is left out for direct messages.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TranscriptMessage {
+    pub guild: Option<GuildId>,
+    pub channel: ChannelId,
+    pub author: UserId,
+    pub timestamp: Timestamp,
+    pub content: String,
+}
+
+/// A bot with nothing in its database, fed one message at a time. Messages are handled in the
+/// order they are sent, and windows close as the transcript passes them.
+pub struct Simulation {
+    bot: Bot,
+    db: Arc<WeedDatabase<'static>>,
+    clock: ManualClock,
+    schedule: Arc<WindowSchedule>,
+    transport: Arc<RecordingTransport>,
+    /// How much of what the transport recorded has been handed back already.
+    reported: usize,
+    messages: u64,
+    guilds: BTreeSet<GuildId>,
+    users: BTreeSet<(Option<GuildId>, UserId)>,
+}
+
+impl Simulation {
+    pub fn new() -> Result<Self, Box<dyn Error>> {
+        let db = Arc::new(WeedDatabase::create_in_memory()?);
+        let clock = ManualClock::new(DateTime::UNIX_EPOCH);
+        let schedule = Arc::new(WindowSchedule::new());
+        let transport = Arc::new(RecordingTransport::new());
+        let bot = Bot {
+            data: Arc::new(RwLock::new(shared_data(
+                Arc::new(clock.clone()),
+                schedule.clone(),
+            ))),
+            transport: transport.clone(),
+        };
+
+        Ok(Self {
+            bot,
+            db,
+            clock,
+            schedule,
+            transport,
+            reported: 0,
+            messages: 0,
+            guilds: BTreeSet::new(),
+            users: BTreeSet::new(),
+        })
+    }
+
+    /// Handles `message` the way the bot would have when it was sent. Returns what the bot sent
+    /// since the last message: the recaps of windows that closed in between, then its answers.
+    pub async fn send(&mut self, message: TranscriptMessage) -> Vec<Sent> {
+        let timestamp = *message.timestamp;
+        self.close_windows(timestamp).await;
+        self.move_clock(timestamp);
+
+        if let Some(guild_id) = message.guild {
+            self.guilds.insert(guild_id);
+        }
+        self.users.insert((message.guild, message.author));
+
+        let msg = self.message(message);
+        if let Some(queued) = accept_message(self.bot.clone(), self.db.clone(), &msg).await {
+            process_message(queued).await;
+        }
+        self.take_sent()
+    }
+
+    /// Closes the windows still open after the last message, and returns their recaps.
+    pub async fn finish(&mut self) -> Vec<Sent> {
+        self.close_windows(DateTime::<Utc>::MAX_UTC).await;
+        self.take_sent()
+    }
+
+    /// The stats of every guild a message was sent in.
+    pub fn guild_stats(&self) -> Result<Vec<GuildStats>, Box<dyn Error>> {
+        let mut stats = Vec::new();
+        for &guild_id in &self.guilds {
+            stats.extend(self.db.guild_stats(guild_id)?);
+        }
+        Ok(stats)
+    }
+
+    /// The stats of everyone who sent a message, in each guild they sent one in.
+    pub fn user_stats(&self) -> Result<Vec<UserStats>, Box<dyn Error>> {
+        let mut stats = Vec::new();
+        for &(guild_id, user_id) in &self.users {
+            stats.extend(self.db.user_stats(guild_id, user_id)?);
+        }
+        Ok(stats)
+    }
+
+    /// Closes every window that closes by `until`, when it closes, the way the scheduler would.
+    async fn close_windows(&self, until: DateTime<Utc>) {
+        while let Some(closes_at) = self.schedule.next().filter(|&closes_at| closes_at <= until) {
+            self.move_clock(closes_at);
+            for channel_id in self.schedule.take_due(closes_at) {
+                close_window(&self.bot, self.db.as_ref(), channel_id, closes_at).await;
+            }
+        }
+    }
+
+    /// Moves the clock up to `now`. A transcript slightly out of order doesn't turn it back.
+    fn move_clock(&self, now: DateTime<Utc>) {
+        if now > self.clock.now() {
+            self.clock.set(now);
+        }
+    }
+
+    fn message(&mut self, message: TranscriptMessage) -> Message {
+        // Ids are made the way Discord makes them, from when the message was sent, so they
+        // never run into the ids the transport gives the bot's replies.
+        self.messages += 1;
+        let since_epoch = (message.timestamp.timestamp_millis() - DISCORD_EPOCH).max(0) as u64;
+
+        let mut author = User::default();
+        author.id = message.author;
+
+        let mut msg = Message::default();
+        msg.id = MessageId::new((since_epoch << 22) | (self.messages & 0x3f_ffff));
+        msg.channel_id = message.channel;
+        msg.guild_id = message.guild;
+        msg.author = author;
+        msg.content = message.content;
+        msg.timestamp = message.timestamp;
+        msg
+    }
+
+    fn take_sent(&mut self) -> Vec<Sent> {
+        let sent = self.transport.sent().split_off(self.reported);
+        self.reported += sent.len();
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript(lines: &str) -> Vec<TranscriptMessage> {
+        lines
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replays_a_transcript() {
+        // 4:20 PM in New York, the default timezone, is 20:20 UTC in April.
+        let messages = transcript(
+            r#"{"guild": 420, "channel": 1, "author": 10, "timestamp": "2024-04-20T20:20:05Z", "content": "weed time"}
+{"guild": "420", "channel": "1", "author": "11", "timestamp": "2024-04-20T20:20:20Z", "content": "WEED TIME"}
+{"guild": 420, "channel": 1, "author": 12, "timestamp": "2024-04-20T20:25:00Z", "content": "nice"}
+{"guild": 420, "channel": 1, "author": 12, "timestamp": "2024-04-21T12:00:00Z", "content": "weed time"}"#,
+        );
+
+        let mut sim = Simulation::new().unwrap();
+        let mut sent = Vec::new();
+        for message in messages {
+            sent.push(sim.send(message).await);
+        }
+        sent.push(sim.finish().await);
+
+        assert!(matches!(&sent[0][..], [Sent::Message { content, .. }] if content == "WEED TIME!"));
+        assert!(matches!(
+            &sent[1][..],
+            [Sent::Message { .. }, Sent::Edit { .. }]
+        ));
+        // The window closed before the third message, which is nothing to the bot.
+        assert!(matches!(&sent[2][..], [Sent::Embed { .. }]));
+        assert!(matches!(&sent[3][..], [Sent::Message { content, .. }] if content != "WEED TIME!"));
+        assert!(sent[4].is_empty());
+
+        let guilds = sim.guild_stats().unwrap();
+        assert_eq!(guilds.len(), 1);
+        assert_eq!(guilds[0].weed_times, 2);
+        assert_eq!(guilds[0].weed_crimes, 1);
+        assert_eq!(guilds[0].longest_chain, 2);
+
+        let users = sim
+            .user_stats()
+            .unwrap()
+            .into_iter()
+            .map(|stats| (stats.id().get(), stats.weed_times, stats.weed_crimes))
+            .collect::<Vec<_>>();
+        assert_eq!(users, [(10, 1, 0), (11, 1, 0), (12, 0, 1)]);
+    }
+}
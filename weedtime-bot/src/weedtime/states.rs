@@ -1,191 +1,417 @@
-use chrono::Timelike;
+use chrono::{DateTime, Utc};
 use chrono_tz::Tz;
-use serenity::all::{Context, CreateAttachment, CreateMessage, EditMessage, Message, UserId};
-use weedtime_db::data::v1::{GuildStatsUpdate, UserStatsUpdate};
+use serenity::all::{ChannelId, GuildId, MessageId};
+use weedtime_db::data::{
+    Chain as ChainRecord, ChainState, ChainUpdate, GuildStatsUpdate, MessageRevert, MessageUpdate,
+    UserStatsUpdate, WeedDatabase, WeedEvent, WindowMatch, v1::WeedEventKind, v6::ResponseMode,
+};
 
 use crate::{
-    WeedTimeMessage,
-    weedtime::util::{combo_to_emojis, get_map, has_unique_elements},
+    Bot, WeedTimeMessage,
+    weedtime::{
+        chain::{self, Chain, ChainEvent, Decision, Outcome},
+        recap::stats_record_length,
+        util::{city_name, combo_to_emojis, get_clock, get_map, get_schedule},
+    },
 };
 
-pub trait MapUpdate {
-    async fn update(
-        ctx: &Context,
-        msg: &Message,
-        timezone: Tz,
-    ) -> Result<Option<(UserStatsUpdate, GuildStatsUpdate)>, serenity::Error>;
+/// Posts the bot's answer in `channel_id`, with the picture at `image` attached unless the guild
+/// only wants text.
+async fn respond(
+    bot: &Bot,
+    channel_id: ChannelId,
+    content: impl Into<String>,
+    image: &str,
+    response_mode: ResponseMode,
+) -> Result<MessageId, serenity::Error> {
+    let image = match response_mode {
+        ResponseMode::Image => Some(image),
+        ResponseMode::Text => None,
+    };
+    bot.transport
+        .send_message(channel_id, content.into(), image)
+        .await
+}
+
+/// What the bot's answer at `position` in a chain turns into once someone else joins the chain.
+fn combo_content(position: u32) -> String {
+    format!(
+        "<:4_:1083068784404865136><:2_:1083068782764900412><:0_:1083068785436672010> <:x_:1083098032268120075>{}",
+        combo_to_emojis(position)
+    )
+}
+
+fn weed_time_content(window: &WindowMatch, timezone: Tz) -> String {
+    if window.timezone == timezone {
+        return "WEED TIME!".to_string();
+    }
+
+    format!(
+        "WEED TIME! It's {} in {}!",
+        window.time.format("%-I:%M"),
+        city_name(window.timezone)
+    )
 }
 
-pub struct WeedTime;
-
-impl MapUpdate for WeedTime {
-    async fn update(
-        ctx: &Context,
-        msg: &Message,
-        timezone: Tz,
-    ) -> Result<Option<(UserStatsUpdate, GuildStatsUpdate)>, serenity::Error> {
-        let map = get_map(ctx).await.clone();
-        let channel_id = msg.channel(&ctx.http).await?.id();
-        let mut user_stats = UserStatsUpdate::new(msg.author.id);
-        let mut guild_stats = msg.guild_id.map(GuildStatsUpdate::new).unwrap_or_default();
-        let new_msg = channel_id
-            .send_files(
-                &ctx.http,
-                vec![CreateAttachment::path("assets/420.png").await?],
-                CreateMessage::new().content("WEED TIME!"),
+/// Runs the message through the engine and keeps the channel's entry in step with it. Returns
+/// the bot's previous answer when the chain continued, so it can be turned into the combo, and
+/// the chain that ended, if any, for the history.
+fn apply(
+    entry: &mut WeedTimeMessage,
+    event: &ChainEvent,
+    reply: Option<MessageId>,
+    server_record: Option<u32>,
+) -> (Outcome, Option<MessageId>, Option<ChainRecord>) {
+    let last_reply = entry.reply;
+    let outcome = chain::step(&mut entry.chain, event);
+    entry.guild_id = event.guild_id;
+    let mut previous = None;
+
+    // A chain is looked back on through the bot's last answer in it, which shows how long it got.
+    let ended = outcome
+        .ended
+        .clone()
+        .zip(last_reply)
+        .map(|(ended, reply_id)| {
+            ChainRecord::new(
+                reply_id,
+                event.guild_id,
+                event.channel_id,
+                ended.chain.window_start,
+                ended.chain.users,
+                ended.broken_by,
+                ended.ended_at,
             )
-            .await?;
+        });
 
-        enum WeedTimeState {
-            Edit {
-                msg: Message,
-                count: u32,
-            },
-            Insert {
-                msg: Message,
-                users: Vec<UserId>,
-                count: u32,
-            },
+    match outcome.decision {
+        Decision::Start | Decision::Restart => {
+            tracing::info!("New weed time or non-unique user. Restarting channel entry here.");
+            entry.reply = reply;
+            entry.server_record = server_record;
         }
+        Decision::Continue => {
+            tracing::info!(
+                "Weed time chain continuing (Count: {})",
+                outcome.chain_position.unwrap_or_default()
+            );
+            previous = std::mem::replace(&mut entry.reply, reply);
+        }
+        Decision::Break => {
+            tracing::info!("Chain broken, resetting channel entry.");
+            entry.reply = None;
+        }
+        Decision::Crime | Decision::Ignore => {}
+    }
 
-        let mut state: Option<WeedTimeState> = None;
-
-        match map.get_mut(&channel_id).await {
-            Some(mut weed_time_message) => {
-                weed_time_message.users.push(msg.author.id);
-
-                let has_unique_users = has_unique_elements(weed_time_message.users.iter());
-
-                if weed_time_message.msg.clone().is_some_and(|m| {
-                    let weed_time_timestamp = m.timestamp.with_timezone(&timezone);
-                    let timestamp = msg.timestamp.with_timezone(&timezone);
-
-                    timestamp.date_naive() == weed_time_timestamp.date_naive()
-                        && timestamp.hour() == weed_time_timestamp.hour()
-                        && has_unique_users
-                }) {
-                    state = Some(WeedTimeState::Edit {
-                        msg: weed_time_message.msg.clone().unwrap(),
-                        count: weed_time_message.count,
-                    });
-
-                    weed_time_message.msg = Some(new_msg);
-                    weed_time_message.count += 1;
-
-                    tracing::info!(
-                        "Weed time chain continuing (Count: {})",
-                        weed_time_message.count
-                    );
-
-                    user_stats.weed_times += 1;
-                    guild_stats.weed_times += 1;
-                    guild_stats.longest_chain = Some(weed_time_message.count);
-                } else {
-                    // Chain broken or new weed time
-                    weed_time_message.msg = Some(new_msg);
-                    weed_time_message.users = vec![msg.author.id];
-                    weed_time_message.count = 1;
-
-                    tracing::info!(
-                        "Non-unique user or new weed time. Restarting channel entry here."
-                    );
-
-                    user_stats.weed_times += 1;
-                    user_stats.chains_started += 1;
-                    if !has_unique_users {
-                        user_stats.chains_broken += 1;
-                    }
-                    guild_stats.weed_times += 1;
-                    guild_stats.longest_chain = Some(weed_time_message.count);
-                }
-            }
-            None => {
-                state = Some(WeedTimeState::Insert {
-                    msg: new_msg,
-                    users: vec![msg.author.id],
-                    count: 1,
-                });
-                tracing::info!("Inserting channel entry.");
-
-                user_stats.weed_times += 1;
-                user_stats.chains_started += 1;
-                guild_stats.weed_times += 1;
-                guild_stats.longest_chain = Some(1);
-            }
+    (outcome, previous, ended)
+}
+
+/// Handles a message the way the engine decides: answers weed times and weed crimes, turns the
+/// previous answer into the combo when a chain continues, and returns what to save.
+pub async fn handle_message(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    message_id: MessageId,
+    event: ChainEvent,
+    response_mode: ResponseMode,
+) -> Result<Option<MessageUpdate>, serenity::Error> {
+    let channel_id = event.channel_id;
+
+    // The answer doesn't depend on the chain, so it is sent first and nothing is counted if that
+    // fails.
+    let reply = match (&event.window, event.contains_weed_time) {
+        (Some(window), true) => {
+            let content = weed_time_content(window, event.timezone);
+            Some(respond(bot, channel_id, content, "assets/420.png", response_mode).await?)
+        }
+        (None, true) => {
+            respond(
+                bot,
+                channel_id,
+                "WEED CRIME!",
+                "assets/420_jail.jpg",
+                response_mode,
+            )
+            .await?;
+            None
         }
+        _ => None,
+    };
 
-        // This is done because `weed_time_message` needs to be dropped before an `await` is used
-        if let Some(state) = state {
-            match state {
-                WeedTimeState::Edit { mut msg, count } => msg.edit(&ctx.http, EditMessage::new().content(format!(
-                    "<:4_:1083068784404865136><:2_:1083068782764900412><:0_:1083068785436672010> <:x_:1083098032268120075>{}",
-                    combo_to_emojis(count)
-                )).remove_all_attachments()).await?,
-                WeedTimeState::Insert { msg, users, count } => {
-                    map.insert(channel_id, WeedTimeMessage {
-                        msg: Some(msg),
-                        users,
-                        count
-                    }).await;
-                }
-            }
+    // A chain this starts is held up against the guild's record from before it, which its own
+    // update is about to raise.
+    let server_record = reply.and_then(|_| stats_record_length(db, event.guild_id));
+
+    let map = get_map(bot).await;
+    let mut inserted = None;
+    let (outcome, previous, ended_chain, chain, server_record) = match map
+        .get_mut(&channel_id)
+        .await
+    {
+        Some(mut entry) => {
+            let (outcome, previous, ended_chain) = apply(&mut entry, &event, reply, server_record);
+            (
+                outcome,
+                previous,
+                ended_chain,
+                entry.chain.clone(),
+                entry.server_record,
+            )
         }
+        None => {
+            tracing::info!("Inserting channel entry.");
+            let mut entry = WeedTimeMessage::default();
+            let (outcome, previous, ended_chain) = apply(&mut entry, &event, reply, server_record);
+            let chain = entry.chain.clone();
+            let server_record = entry.server_record;
+            inserted = chain.is_some().then_some(entry);
+            (outcome, previous, ended_chain, chain, server_record)
+        }
+    };
+    // The entry has to be released before anything else is awaited.
+    if let Some(entry) = inserted {
+        map.insert(channel_id, entry).await;
+    }
 
-        Ok(Some((user_stats, guild_stats)))
+    // A new chain is wrapped up once its window closes.
+    if let (Decision::Start | Decision::Restart, Some(chain)) = (outcome.decision, &chain) {
+        get_schedule(bot).await.add(channel_id, chain.expires_at);
     }
-}
 
-pub struct WeedCrime;
-
-impl MapUpdate for WeedCrime {
-    async fn update(
-        ctx: &Context,
-        msg: &Message,
-        _timezone: Tz,
-    ) -> Result<Option<(UserStatsUpdate, GuildStatsUpdate)>, serenity::Error> {
-        let channel_id = msg.channel(&ctx.http).await?.id();
-        let mut user_stats = UserStatsUpdate::new(msg.author.id);
-        let mut guild_stats = msg.guild_id.map(GuildStatsUpdate::new).unwrap_or_default();
-
-        channel_id
-            .send_files(
-                &ctx.http,
-                vec![CreateAttachment::path("assets/420_jail.jpg").await?],
-                CreateMessage::new().content("WEED CRIME!"),
-            )
+    if let (Some(previous), Some(position)) = (previous, outcome.chain_position) {
+        bot.transport
+            .edit_message(channel_id, previous, combo_content(position - 1))
             .await?;
+    }
+
+    let (kind, chain) = match outcome.decision {
+        Decision::Start | Decision::Continue | Decision::Restart => {
+            let chain = chain
+                .zip(reply)
+                .map(|(chain, reply_id)| save_chain(&event, reply_id, &chain, server_record));
+            (WeedEventKind::WeedTime, chain)
+        }
+        Decision::Break => (
+            WeedEventKind::BrokenChain,
+            Some(ChainUpdate::Clear(channel_id)),
+        ),
+        Decision::Crime => (WeedEventKind::WeedCrime, None),
+        Decision::Ignore => return Ok(None),
+    };
+
+    let mut weed_event = WeedEvent::new(
+        kind,
+        event.guild_id,
+        channel_id,
+        event.user_id,
+        message_id,
+        event.timestamp,
+    );
+    weed_event.chain_position = outcome.chain_position;
+    weed_event.set_reply_id(reply);
+
+    Ok(Some(MessageUpdate {
+        message_id: Some(message_id),
+        user: outcome.user,
+        guild: outcome.guild,
+        event: Some(weed_event),
+        chain,
+        ended_chain,
+        edited: false,
+    }))
+}
 
-        user_stats.weed_crimes += 1;
-        guild_stats.weed_crimes += 1;
+/// Takes the chain in `channel_id` out of the channel map if its window has closed by `now`,
+/// and returns it for the history, along with the server record it had to beat.
+pub async fn close_chain(
+    bot: &Bot,
+    channel_id: ChannelId,
+    now: DateTime<Utc>,
+) -> Option<(ChainRecord, Option<u32>)> {
+    let map = get_map(bot).await;
+    let mut entry = map.get_mut(&channel_id).await?;
+    let ended = chain::close(&mut entry.chain, now)?;
+    let reply_id = entry.reply.take()?;
+    tracing::info!(
+        "Window closed in {channel_id} (Count: {})",
+        ended.chain.count()
+    );
 
-        Ok(Some((user_stats, guild_stats)))
+    let record = ChainRecord::new(
+        reply_id,
+        entry.guild_id,
+        channel_id,
+        ended.chain.window_start,
+        ended.chain.users,
+        ended.broken_by,
+        ended.ended_at,
+    );
+    Some((record, entry.server_record.take()))
+}
+
+fn save_chain(
+    event: &ChainEvent,
+    message_id: MessageId,
+    chain: &Chain,
+    server_record: Option<u32>,
+) -> ChainUpdate {
+    let mut state = ChainState::new(
+        event.channel_id,
+        event.guild_id,
+        message_id,
+        chain.users.iter().copied(),
+        chain.count(),
+        chain.window_start,
+        chain.expires_at,
+    );
+    state.server_record = server_record;
+    ChainUpdate::Save(state)
+}
+
+/// Picks the events of the messages in `chain` out of the `events` logged in its channel since its
+/// window opened, oldest first, along with whether the first of them broke a chain of its own.
+/// They are the latest weed times, back to one by someone who isn't in the chain or is in it
+/// already.
+fn chain_events(mut events: Vec<WeedEvent>, chain: &Chain) -> (Vec<WeedEvent>, bool) {
+    let mut in_chain: Vec<WeedEvent> = Vec::new();
+    while in_chain.len() < chain.users.len()
+        && let Some(event) = events.pop_if(|event| {
+            event.kind == WeedEventKind::WeedTime
+                && chain.users.contains(&event.user_id())
+                && !in_chain
+                    .iter()
+                    .any(|other| other.user_id() == event.user_id())
+        })
+    {
+        in_chain.push(event);
     }
+    in_chain.reverse();
+
+    // A chain only starts right after another weed time by taking its place.
+    let restarted = events
+        .last()
+        .is_some_and(|event| event.kind == WeedEventKind::WeedTime);
+    (in_chain, restarted)
 }
 
-pub struct BrokenChain;
+/// Takes a deleted message out of the chain running in its channel, and returns what it was
+/// counted for so it can be taken back. That is worked out from the event the message logged, so
+/// chains picked back up after a restart can be taken out of too. Messages that aren't part of a
+/// running chain keep what they were counted for.
+///
+/// The bot's answer to the message is taken down, and the combos the answers after it turned
+/// into are counted down to where their messages now stand.
+pub async fn remove_from_chain(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+    message_id: MessageId,
+) -> Option<MessageRevert> {
+    let event = match db.event(message_id) {
+        Ok(event) => event.filter(|event| event.kind == WeedEventKind::WeedTime)?,
+        Err(e) => {
+            tracing::error!("Failed to fetch what deleted message {message_id} counted for: {e:?}");
+            return None;
+        }
+    };
+    let now = get_clock(bot).await.now();
+    let map = get_map(bot).await;
+
+    let (revert, answer, combos) = {
+        let mut guard = map.get_mut(&channel_id).await?;
+        let weed_time_message = &mut *guard;
+        let running = weed_time_message
+            .chain
+            .as_mut()
+            .filter(|chain| chain.is_active(now))?;
 
-impl MapUpdate for BrokenChain {
-    async fn update(
-        ctx: &Context,
-        msg: &Message,
-        _timezone: Tz,
-    ) -> Result<Option<(UserStatsUpdate, GuildStatsUpdate)>, serenity::Error> {
-        let map = get_map(ctx).await;
-        let mut user_stats = UserStatsUpdate::new(msg.author.id);
+        let mut events = match db.guild_events(guild_id, running.window_start..) {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::error!("Failed to fetch the chain in {channel_id}: {e:?}");
+                return None;
+            }
+        };
+        events.retain(|event| event.channel_id() == channel_id);
+        let (events, restarted) = chain_events(events, running);
+        let index = events
+            .iter()
+            .position(|event| event.message_id() == message_id)?;
+        running.users.retain(|&user| user != event.user_id());
+        tracing::info!("Message removed from chain (Count: {})", running.count());
 
-        if let Some(mut weed_time_message) = map.get_mut(&msg.channel(&ctx.http).await?.id()).await
-        {
-            weed_time_message.msg = None;
-            weed_time_message.users = Vec::new();
-            weed_time_message.count = 0;
-            tracing::info!("Chain broken, resetting channel entry.");
+        // The answer before the last one takes its place, unless which one it was is unknown.
+        let mut answer = event.reply_id();
+        if index + 1 == events.len() && index > 0 {
+            match events[index - 1].reply_id() {
+                Some(previous) => weed_time_message.reply = Some(previous),
+                None => answer = None,
+            }
         }
+        // Every answer after it but the last one is a combo, which now stands one lower.
+        let combos = (index + 1..events.len().saturating_sub(1))
+            .filter_map(|position| Some((events[position].reply_id()?, position as u32)))
+            .collect::<Vec<_>>();
 
-        user_stats.chains_broken += 1;
+        let chain = match weed_time_message.reply {
+            Some(bot_msg_id) if !running.users.is_empty() => {
+                let mut state = ChainState::new(
+                    channel_id,
+                    guild_id,
+                    bot_msg_id,
+                    running.users.iter().copied(),
+                    running.count(),
+                    running.window_start,
+                    running.expires_at,
+                );
+                state.server_record = weed_time_message.server_record;
+                ChainUpdate::Save(state)
+            }
+            _ => {
+                weed_time_message.chain = None;
+                weed_time_message.reply = None;
+                ChainUpdate::Clear(channel_id)
+            }
+        };
 
-        Ok(Some((user_stats, GuildStatsUpdate::default())))
+        let started = event.chain_position == Some(1);
+        let user = UserStatsUpdate {
+            weed_times: 1,
+            chains_started: u32::from(started),
+            chains_broken: u32::from(started && restarted),
+            ..UserStatsUpdate::new(event.user_id(), guild_id)
+        };
+        let guild = guild_id
+            .map(|guild_id| GuildStatsUpdate {
+                weed_times: 1,
+                ..GuildStatsUpdate::new(guild_id)
+            })
+            .unwrap_or_default();
+        let revert = MessageRevert {
+            message_id: Some(message_id),
+            user,
+            guild,
+            chain: Some(chain),
+        };
+        (revert, answer, combos)
+    };
+
+    if let Some(answer) = answer
+        && let Err(e) = bot.transport.delete_message(channel_id, answer).await
+    {
+        tracing::error!("Failed to take down the answer to deleted message {message_id}: {e:?}");
+    }
+    for (reply_id, position) in combos {
+        if let Err(e) = bot
+            .transport
+            .edit_message(channel_id, reply_id, combo_content(position))
+            .await
+        {
+            tracing::error!("Failed to count down the combo on {reply_id}: {e:?}");
+        }
     }
-}
 
-// WeedTime::update(&msg, &ctx)?.await -> Result<(UserStatsUpdate, GuildStatsUpdate), serenity::Error>
-//     .commit(&db) -> Result<(), db_type::Error>
+    Some(revert)
+}
@@ -0,0 +1,484 @@
+//! The stats commands: personal and server stats, leaderboards and chains.
+
+use chrono::NaiveDate;
+use serenity::{
+    all::{ButtonStyle, CommandInteraction, ComponentInteraction, ResolvedValue, User},
+    builder::{
+        CreateActionRow, CreateButton, CreateEmbed, CreateEmbedAuthor, CreateEmbedFooter,
+        CreateInteractionResponse, CreateInteractionResponseMessage,
+    },
+    model::colour::Colour,
+};
+use tracing::error;
+use weedtime_db::data::{
+    Chain as ChainRecord, GuildStats, LeaderboardEntry, LeaderboardMetric, UserStats, WeedDatabase,
+};
+
+use crate::{
+    Bot,
+    weedtime::{
+        commands::{respond_with_content, respond_with_embed},
+        settings::guild_timezone,
+        util::get_clock,
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsScope {
+    Server,
+    Global,
+}
+
+impl StatsScope {
+    pub fn value(self) -> &'static str {
+        match self {
+            StatsScope::Server => "server",
+            StatsScope::Global => "global",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            StatsScope::Server => "This server",
+            StatsScope::Global => "Everywhere",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [StatsScope::Server, StatsScope::Global]
+            .into_iter()
+            .find(|scope| scope.value() == value)
+    }
+}
+
+const LEADERBOARD_PAGE_SIZE: usize = 10;
+
+/// The metrics `/leaderboard` can rank by, with their option values and labels.
+pub const LEADERBOARD_METRICS: [(LeaderboardMetric, &str, &str); 4] = [
+    (LeaderboardMetric::WeedTimes, "weed_times", "Weed times"),
+    (LeaderboardMetric::WeedCrimes, "weed_crimes", "Weed crimes"),
+    (
+        LeaderboardMetric::ChainsStarted,
+        "chains_started",
+        "Chains started",
+    ),
+    (
+        LeaderboardMetric::ChainsBroken,
+        "chains_broken",
+        "Chains broken",
+    ),
+];
+
+fn metric_value(metric: LeaderboardMetric) -> &'static str {
+    LEADERBOARD_METRICS
+        .iter()
+        .find_map(|&(m, value, _)| (m == metric).then_some(value))
+        .unwrap_or_default()
+}
+
+fn metric_label(metric: LeaderboardMetric) -> &'static str {
+    LEADERBOARD_METRICS
+        .iter()
+        .find_map(|&(m, _, label)| (m == metric).then_some(label))
+        .unwrap_or_default()
+}
+
+fn parse_metric(value: &str) -> Option<LeaderboardMetric> {
+    LEADERBOARD_METRICS
+        .iter()
+        .find_map(|&(metric, v, _)| (v == value).then_some(metric))
+}
+
+/// `today` is the date in the timezone the stats were counted in, which decides whether the
+/// current streak is still alive.
+fn user_stats_embed(
+    user: &User,
+    scope: StatsScope,
+    stats: Option<UserStats>,
+    today: NaiveDate,
+) -> CreateEmbed {
+    let embed = CreateEmbed::new()
+        .title(format!("{}'s Weed Stats", user.name))
+        .author(CreateEmbedAuthor::new(user.name.clone()).icon_url(user.face()))
+        .thumbnail(user.face())
+        .footer(CreateEmbedFooter::new(scope.label()))
+        .colour(Colour::DARK_GREEN);
+
+    if let Some(stats) = stats {
+        let embed = embed
+            .field("Weed times", stats.weed_times.to_string(), true)
+            .field("Weed crimes", stats.weed_crimes.to_string(), true)
+            .field("Chains started", stats.chains_started.to_string(), true)
+            .field("Chains broken", stats.chains_broken.to_string(), true)
+            .field("Longest chain", stats.longest_chain.to_string(), true);
+
+        // Streaks are counted per server, so only the best one adds up to anything globally.
+        let embed = match scope {
+            StatsScope::Server => embed.field("Current streak", days(stats.streak_on(today)), true),
+            StatsScope::Global => embed,
+        };
+        embed.field("Best streak", days(stats.best_streak), true)
+    } else {
+        embed.description("No weed stats yet.")
+    }
+}
+
+fn leaderboard_embed(
+    metric: LeaderboardMetric,
+    scope: StatsScope,
+    page: usize,
+    entries: &[LeaderboardEntry],
+) -> CreateEmbed {
+    let embed = CreateEmbed::new()
+        .title(format!("{} Leaderboard", metric_label(metric)))
+        .footer(CreateEmbedFooter::new(format!(
+            "{} · Page {}",
+            scope.label(),
+            page + 1
+        )))
+        .colour(Colour::DARK_GREEN);
+
+    if entries.is_empty() {
+        return embed.description("No one is on this page yet.");
+    }
+
+    let lines = entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "**{}.** <@{}> — {}",
+                entry.rank,
+                entry.stats.id(),
+                metric.value(&entry.stats)
+            )
+        })
+        .collect::<Vec<_>>();
+    embed.description(lines.join("\n"))
+}
+
+/// The Previous/Next buttons under a leaderboard. Each button's id carries everything needed to
+/// render the page it leads to.
+fn leaderboard_buttons(
+    metric: LeaderboardMetric,
+    scope: StatsScope,
+    page: usize,
+    has_next: bool,
+) -> CreateActionRow {
+    let custom_id = |page: usize| {
+        format!(
+            "leaderboard:{}:{}:{page}",
+            metric_value(metric),
+            scope.value()
+        )
+    };
+
+    CreateActionRow::Buttons(vec![
+        CreateButton::new(custom_id(page.saturating_sub(1)))
+            .label("Previous")
+            .style(ButtonStyle::Secondary)
+            .disabled(page == 0),
+        CreateButton::new(custom_id(page + 1))
+            .label("Next")
+            .style(ButtonStyle::Secondary)
+            .disabled(!has_next),
+    ])
+}
+
+fn parse_leaderboard_button(custom_id: &str) -> Option<(LeaderboardMetric, StatsScope, usize)> {
+    let mut parts = custom_id.strip_prefix("leaderboard:")?.split(':');
+    let metric = parse_metric(parts.next()?)?;
+    let scope = StatsScope::parse(parts.next()?)?;
+    let page = parts.next()?.parse().ok()?;
+    Some((metric, scope, page))
+}
+
+fn leaderboard_message(
+    db: &WeedDatabase<'static>,
+    metric: LeaderboardMetric,
+    scope: StatsScope,
+    guild_id: Option<serenity::all::GuildId>,
+    page: usize,
+) -> CreateInteractionResponseMessage {
+    // Fetch one extra row to know whether there is a next page.
+    let offset = page * LEADERBOARD_PAGE_SIZE;
+    let entries = match scope {
+        StatsScope::Server => db.top_n(metric, guild_id, LEADERBOARD_PAGE_SIZE + 1, offset),
+        StatsScope::Global => db.top_n_global(metric, LEADERBOARD_PAGE_SIZE + 1, offset),
+    };
+
+    let mut entries = match entries {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Failed to fetch the {metric:?} leaderboard: {e:?}");
+            Vec::new()
+        }
+    };
+    let has_next = entries.len() > LEADERBOARD_PAGE_SIZE;
+    entries.truncate(LEADERBOARD_PAGE_SIZE);
+
+    CreateInteractionResponseMessage::new()
+        .embed(leaderboard_embed(metric, scope, page, &entries))
+        .components(vec![leaderboard_buttons(metric, scope, page, has_next)])
+}
+
+fn days(count: u32) -> String {
+    match count {
+        1 => "1 day".to_string(),
+        count => format!("{count} days"),
+    }
+}
+
+fn guild_stats_embed(
+    name: String,
+    icon_url: Option<String>,
+    stats: Option<GuildStats>,
+    today: NaiveDate,
+) -> CreateEmbed {
+    let mut author = CreateEmbedAuthor::new(name.clone());
+    if let Some(icon_url) = icon_url.clone() {
+        author = author.icon_url(icon_url);
+    }
+
+    let mut embed = CreateEmbed::new()
+        .title(format!("{name} Weed Stats"))
+        .author(author)
+        .colour(Colour::DARK_GREEN);
+
+    if let Some(icon_url) = icon_url {
+        embed = embed.thumbnail(icon_url);
+    }
+
+    if let Some(stats) = stats {
+        embed
+            .field("Weed times", stats.weed_times.to_string(), true)
+            .field("Weed crimes", stats.weed_crimes.to_string(), true)
+            .field("Longest chain", stats.longest_chain.to_string(), true)
+            .field("Current streak", days(stats.streak_on(today)), true)
+            .field("Best streak", days(stats.best_streak), true)
+    } else {
+        embed.description("No weed stats yet.")
+    }
+}
+
+/// How many chains `/chains` shows in each list.
+const CHAINS_SHOWN: usize = 5;
+
+fn chain_line(chain: &ChainRecord) -> String {
+    let link = chain
+        .message_id()
+        .link(chain.channel_id(), chain.guild_id());
+    let ended = match chain.broken_by() {
+        Some(user_id) => format!("broken by <@{user_id}>"),
+        None => "lasted the window".to_string(),
+    };
+
+    format!(
+        "**{}** in <#{}> <t:{}:R>, {ended} · [Jump]({link})",
+        chain.length,
+        chain.channel_id(),
+        chain.window_start.timestamp()
+    )
+}
+
+fn chains_embed(name: String, longest: &[ChainRecord], recent: &[ChainRecord]) -> CreateEmbed {
+    let embed = CreateEmbed::new()
+        .title(format!("{name} Weed Time Chains"))
+        .colour(Colour::DARK_GREEN);
+
+    if longest.is_empty() {
+        return embed.description("No chains have ended yet.");
+    }
+
+    let lines = |chains: &[ChainRecord]| chains.iter().map(chain_line).collect::<Vec<_>>();
+    embed
+        .field("Longest", lines(longest).join("\n"), false)
+        .field("Latest", lines(recent).join("\n"), false)
+}
+
+pub async fn handle_chains_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some(guild_id) = command.guild_id else {
+        return respond_with_content(bot, command, "Chains are only kept in a server.").await;
+    };
+
+    let chains = (
+        db.longest_chains(Some(guild_id), CHAINS_SHOWN),
+        db.recent_chains(Some(guild_id), CHAINS_SHOWN),
+    );
+    let (longest, recent) = match chains {
+        (Ok(longest), Ok(recent)) => (longest, recent),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to fetch chains for {guild_id}: {e:?}");
+            return respond_with_content(bot, command, "Failed to load this server's chains.")
+                .await;
+        }
+    };
+
+    let guild = bot.transport.partial_guild(guild_id).await?;
+    respond_with_embed(bot, command, chains_embed(guild.name, &longest, &recent)).await
+}
+
+pub async fn handle_user_stats_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let options = command.data.options();
+    let target = options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::User(user, _) if option.name == "user" => Some(user.clone()),
+            _ => None,
+        })
+        .unwrap_or_else(|| command.user.clone());
+
+    let scope = options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::String(value) if option.name == "scope" => StatsScope::parse(value),
+            _ => None,
+        })
+        .unwrap_or(if command.guild_id.is_some() {
+            StatsScope::Server
+        } else {
+            StatsScope::Global
+        });
+
+    let stats = match (scope, command.guild_id) {
+        (StatsScope::Server, Some(guild_id)) => db.user_stats(Some(guild_id), target.id),
+        (StatsScope::Server, None) => {
+            return respond_with_content(
+                bot,
+                command,
+                "Server stats are only available in a server.",
+            )
+            .await;
+        }
+        (StatsScope::Global, _) => db.user_totals(target.id),
+    };
+
+    let stats = match stats {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Failed to fetch user stats for {}: {e:?}", target.id);
+            None
+        }
+    };
+
+    let today = get_clock(bot)
+        .await
+        .now()
+        .with_timezone(&guild_timezone(bot, command.guild_id, db).await)
+        .date_naive();
+    respond_with_embed(bot, command, user_stats_embed(&target, scope, stats, today)).await
+}
+
+pub async fn handle_leaderboard_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let options = command.data.options();
+    let metric = options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::String(value) if option.name == "metric" => parse_metric(value),
+            _ => None,
+        })
+        .unwrap_or(LeaderboardMetric::WeedTimes);
+
+    let scope = options
+        .iter()
+        .find_map(|option| match option.value {
+            ResolvedValue::String(value) if option.name == "scope" => StatsScope::parse(value),
+            _ => None,
+        })
+        .unwrap_or(if command.guild_id.is_some() {
+            StatsScope::Server
+        } else {
+            StatsScope::Global
+        });
+
+    if scope == StatsScope::Server && command.guild_id.is_none() {
+        return respond_with_content(
+            bot,
+            command,
+            "Server leaderboards are only available in a server.",
+        )
+        .await;
+    }
+
+    bot.transport
+        .create_response(
+            command.id,
+            &command.token,
+            CreateInteractionResponse::Message(leaderboard_message(
+                db,
+                metric,
+                scope,
+                command.guild_id,
+                0,
+            )),
+        )
+        .await
+}
+
+pub async fn handle_leaderboard_button(
+    bot: &Bot,
+    component: &ComponentInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some((metric, scope, page)) = parse_leaderboard_button(&component.data.custom_id) else {
+        return Ok(());
+    };
+
+    bot.transport
+        .create_response(
+            component.id,
+            &component.token,
+            CreateInteractionResponse::UpdateMessage(leaderboard_message(
+                db,
+                metric,
+                scope,
+                component.guild_id,
+                page,
+            )),
+        )
+        .await
+}
+
+pub async fn handle_guild_stats_command(
+    bot: &Bot,
+    command: &CommandInteraction,
+    db: &WeedDatabase<'static>,
+) -> Result<(), serenity::Error> {
+    let Some(guild_id) = command.guild_id else {
+        return respond_with_content(bot, command, "Server stats are only available in a server.")
+            .await;
+    };
+
+    let guild = bot.transport.partial_guild(guild_id).await?;
+    let stats = match db.guild_stats(guild_id) {
+        Ok(stats) => stats,
+        Err(e) => {
+            error!("Failed to fetch guild stats for {guild_id}: {e:?}");
+            None
+        }
+    };
+
+    let icon_url = guild.icon_url();
+    let today = get_clock(bot)
+        .await
+        .now()
+        .with_timezone(&guild_timezone(bot, Some(guild_id), db).await)
+        .date_naive();
+    respond_with_embed(
+        bot,
+        command,
+        guild_stats_embed(guild.name, icon_url, stats, today),
+    )
+    .await
+}
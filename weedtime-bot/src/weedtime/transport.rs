@@ -0,0 +1,372 @@
+//! Everything the bot asks of Discord's API goes through a [`Transport`], so the handlers can be
+//! run without a connection. The bot uses [`SerenityTransport`], and tests and the simulator a
+//! [`RecordingTransport`] that keeps what would have been sent.
+
+use std::{collections::HashMap, sync::Arc};
+
+use serenity::{
+    all::{
+        Cache, Channel, ChannelId, Command, CreateAttachment, CreateCommand, CreateEmbed,
+        CreateInteractionResponse, CreateMessage, EditMessage, GuildId, Http, InteractionId,
+        Message, MessageId, PartialGuild,
+    },
+    async_trait,
+    builder::Builder,
+};
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Posts `content` in `channel_id`, with the file at `image` attached if there is one.
+    async fn send_message(
+        &self,
+        channel_id: ChannelId,
+        content: String,
+        image: Option<&str>,
+    ) -> Result<MessageId, serenity::Error>;
+
+    async fn send_embed(
+        &self,
+        channel_id: ChannelId,
+        embed: CreateEmbed,
+    ) -> Result<MessageId, serenity::Error>;
+
+    async fn fetch_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<Message, serenity::Error>;
+
+    /// Replaces the text of one of the bot's messages, dropping its attachments.
+    async fn edit_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        content: String,
+    ) -> Result<(), serenity::Error>;
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<(), serenity::Error>;
+
+    async fn create_response(
+        &self,
+        interaction_id: InteractionId,
+        token: &str,
+        response: CreateInteractionResponse,
+    ) -> Result<(), serenity::Error>;
+
+    async fn partial_guild(&self, guild_id: GuildId) -> Result<PartialGuild, serenity::Error>;
+
+    async fn set_global_commands(
+        &self,
+        commands: Vec<CreateCommand>,
+    ) -> Result<Vec<Command>, serenity::Error>;
+
+    /// The channel or category `channel_id`, in `guild_id`, sits in, if any.
+    async fn parent_channel(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Result<Option<ChannelId>, serenity::Error>;
+}
+
+pub struct SerenityTransport {
+    http: Arc<Http>,
+    cache: Arc<Cache>,
+}
+
+impl SerenityTransport {
+    pub fn new(http: Arc<Http>, cache: Arc<Cache>) -> Self {
+        Self { http, cache }
+    }
+
+    /// What `channel_id` sits in according to the gateway cache, or `None` if the cache doesn't
+    /// have the channel.
+    fn cached_parent(&self, guild_id: GuildId, channel_id: ChannelId) -> Option<Option<ChannelId>> {
+        let guild = self.cache.guild(guild_id)?;
+        guild
+            .channels
+            .get(&channel_id)
+            .or_else(|| guild.threads.iter().find(|thread| thread.id == channel_id))
+            .map(|channel| channel.parent_id)
+    }
+}
+
+#[async_trait]
+impl Transport for SerenityTransport {
+    async fn send_message(
+        &self,
+        channel_id: ChannelId,
+        content: String,
+        image: Option<&str>,
+    ) -> Result<MessageId, serenity::Error> {
+        let builder = CreateMessage::new().content(content);
+        let message = match image {
+            Some(image) => {
+                channel_id
+                    .send_files(
+                        &self.http,
+                        vec![CreateAttachment::path(image).await?],
+                        builder,
+                    )
+                    .await?
+            }
+            None => channel_id.send_message(&self.http, builder).await?,
+        };
+        Ok(message.id)
+    }
+
+    async fn send_embed(
+        &self,
+        channel_id: ChannelId,
+        embed: CreateEmbed,
+    ) -> Result<MessageId, serenity::Error> {
+        let message = channel_id
+            .send_message(&self.http, CreateMessage::new().embed(embed))
+            .await?;
+        Ok(message.id)
+    }
+
+    async fn fetch_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<Message, serenity::Error> {
+        self.http.get_message(channel_id, message_id).await
+    }
+
+    async fn edit_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        content: String,
+    ) -> Result<(), serenity::Error> {
+        channel_id
+            .edit_message(
+                &self.http,
+                message_id,
+                EditMessage::new().content(content).remove_all_attachments(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<(), serenity::Error> {
+        channel_id.delete_message(&self.http, message_id).await
+    }
+
+    async fn create_response(
+        &self,
+        interaction_id: InteractionId,
+        token: &str,
+        response: CreateInteractionResponse,
+    ) -> Result<(), serenity::Error> {
+        response.execute(&self.http, (interaction_id, token)).await
+    }
+
+    async fn partial_guild(&self, guild_id: GuildId) -> Result<PartialGuild, serenity::Error> {
+        guild_id.to_partial_guild(&self.http).await
+    }
+
+    async fn set_global_commands(
+        &self,
+        commands: Vec<CreateCommand>,
+    ) -> Result<Vec<Command>, serenity::Error> {
+        Command::set_global_commands(&self.http, commands).await
+    }
+
+    async fn parent_channel(
+        &self,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+    ) -> Result<Option<ChannelId>, serenity::Error> {
+        // Every message in a guild that allows or denies channels gets here, so Discord is only
+        // asked about channels the cache hasn't seen.
+        if let Some(parent_id) = self.cached_parent(guild_id, channel_id) {
+            return Ok(parent_id);
+        }
+        match channel_id.to_channel(&self.http).await? {
+            Channel::Guild(channel) => Ok(channel.parent_id),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Something the bot would have sent to Discord.
+#[derive(Debug, Clone)]
+pub enum Sent {
+    Message {
+        channel_id: ChannelId,
+        message_id: MessageId,
+        content: String,
+        image: Option<String>,
+    },
+    Embed {
+        channel_id: ChannelId,
+        embed: Box<CreateEmbed>,
+    },
+    Edit {
+        channel_id: ChannelId,
+        message_id: MessageId,
+        content: String,
+    },
+    Delete {
+        channel_id: ChannelId,
+        message_id: MessageId,
+    },
+    Response {
+        interaction_id: InteractionId,
+        response: Box<CreateInteractionResponse>,
+    },
+    Commands(usize),
+}
+
+/// Keeps everything it is asked to send instead of sending it. Every message is still there,
+/// empty unless it was [`post`](Self::post)ed, every channel is at the top level, and guilds
+/// can't be fetched.
+#[derive(Debug, Default)]
+pub struct RecordingTransport {
+    sent: std::sync::Mutex<Vec<Sent>>,
+    messages: std::sync::Mutex<HashMap<MessageId, Message>>,
+}
+
+impl RecordingTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Has `message` be what fetching it returns, as if someone had posted it.
+    #[cfg(test)]
+    pub fn post(&self, message: Message) {
+        self.messages.lock().unwrap().insert(message.id, message);
+    }
+
+    /// Everything sent so far, oldest first.
+    pub fn sent(&self) -> Vec<Sent> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    async fn record(&self, sent: impl FnOnce(MessageId) -> Sent) -> MessageId {
+        // Other tasks get to run in the meantime, the way they would while Discord answers.
+        tokio::task::yield_now().await;
+        let mut all = self.sent.lock().unwrap();
+        // Replies get ids of their own, well clear of the ids tests give messages.
+        let message_id = MessageId::new(1_000_000 + all.len() as u64);
+        all.push(sent(message_id));
+        message_id
+    }
+}
+
+#[async_trait]
+impl Transport for RecordingTransport {
+    async fn send_message(
+        &self,
+        channel_id: ChannelId,
+        content: String,
+        image: Option<&str>,
+    ) -> Result<MessageId, serenity::Error> {
+        Ok(self
+            .record(|message_id| Sent::Message {
+                channel_id,
+                message_id,
+                content,
+                image: image.map(str::to_string),
+            })
+            .await)
+    }
+
+    async fn send_embed(
+        &self,
+        channel_id: ChannelId,
+        embed: CreateEmbed,
+    ) -> Result<MessageId, serenity::Error> {
+        Ok(self
+            .record(|_| Sent::Embed {
+                channel_id,
+                embed: Box::new(embed),
+            })
+            .await)
+    }
+
+    async fn fetch_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<Message, serenity::Error> {
+        let posted = self.messages.lock().unwrap().get(&message_id).cloned();
+        Ok(posted.unwrap_or_else(|| {
+            let mut message = Message::default();
+            message.id = message_id;
+            message.channel_id = channel_id;
+            message
+        }))
+    }
+
+    async fn edit_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+        content: String,
+    ) -> Result<(), serenity::Error> {
+        self.record(|_| Sent::Edit {
+            channel_id,
+            message_id,
+            content,
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn delete_message(
+        &self,
+        channel_id: ChannelId,
+        message_id: MessageId,
+    ) -> Result<(), serenity::Error> {
+        self.record(|_| Sent::Delete {
+            channel_id,
+            message_id,
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn create_response(
+        &self,
+        interaction_id: InteractionId,
+        _token: &str,
+        response: CreateInteractionResponse,
+    ) -> Result<(), serenity::Error> {
+        self.record(|_| Sent::Response {
+            interaction_id,
+            response: Box::new(response),
+        })
+        .await;
+        Ok(())
+    }
+
+    async fn partial_guild(&self, _guild_id: GuildId) -> Result<PartialGuild, serenity::Error> {
+        Err(serenity::Error::Other("guilds can't be fetched offline"))
+    }
+
+    async fn set_global_commands(
+        &self,
+        commands: Vec<CreateCommand>,
+    ) -> Result<Vec<Command>, serenity::Error> {
+        self.record(|_| Sent::Commands(commands.len())).await;
+        Ok(Vec::new())
+    }
+
+    async fn parent_channel(
+        &self,
+        _guild_id: GuildId,
+        _channel_id: ChannelId,
+    ) -> Result<Option<ChannelId>, serenity::Error> {
+        Ok(None)
+    }
+}
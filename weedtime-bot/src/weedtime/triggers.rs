@@ -0,0 +1,250 @@
+use std::sync::{Arc, LazyLock};
+
+use regex::{Regex, RegexBuilder, RegexSet, RegexSetBuilder};
+use serenity::all::GuildId;
+use weedtime_db::data::{
+    WeedDatabase,
+    v1::{Trigger, TriggerKind},
+    v2::MatchMode,
+};
+use whirlwind::ShardMap;
+
+use crate::{Bot, TriggerCache, weedtime::normalize::normalize};
+
+// Patterns come from server admins, so keep what one can cost to compile in check.
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// The triggers of a guild that hasn't set its own, compiled once for every message that uses
+/// them.
+static DEFAULT_TRIGGERS: LazyLock<Arc<TriggerSet>> =
+    LazyLock::new(|| Arc::new(TriggerSet::new(&Trigger::defaults(), MatchMode::default())));
+
+/// A guild's triggers, compiled once so every message can be checked against them cheaply.
+pub struct TriggerSet {
+    mode: MatchMode,
+    /// Each phrase trigger as its normalized words.
+    phrases: Vec<Vec<String>>,
+    regexes: RegexSet,
+}
+
+impl TriggerSet {
+    pub fn new(triggers: &[Trigger], mode: MatchMode) -> Self {
+        let phrases = triggers
+            .iter()
+            .filter(|trigger| trigger.kind == TriggerKind::Phrase)
+            .map(|trigger| words(&normalize(&trigger.pattern, mode)))
+            .filter(|words| !words.is_empty())
+            .collect();
+
+        // Patterns are checked before they are saved, so this only drops ones a newer regex
+        // version stopped accepting.
+        let patterns = triggers
+            .iter()
+            .filter(|trigger| {
+                trigger.kind == TriggerKind::Regex && compile_regex(&trigger.pattern).is_ok()
+            })
+            .map(|trigger| trigger.pattern.as_str());
+        let regexes = RegexSetBuilder::new(patterns)
+            .case_insensitive(true)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()
+            .unwrap_or_else(|e| {
+                tracing::warn!("Failed to compile trigger patterns: {e}");
+                RegexSet::empty()
+            });
+
+        Self {
+            mode,
+            phrases,
+            regexes,
+        }
+    }
+
+    /// Whether a message says any of the triggers. Regexes are tried on the message both as it
+    /// was sent and normalized, so ones written against punctuation or markdown still work.
+    pub fn is_match(&self, content: &str) -> bool {
+        let normalized = normalize(content, self.mode);
+        let words = words(&normalized);
+        let has_phrase = self.phrases.iter().any(|phrase| match self.mode {
+            MatchMode::Strict => words
+                .windows(phrase.len())
+                .any(|window| window == phrase.as_slice()),
+            MatchMode::Lenient => contains_joined(&words, &phrase.concat()),
+        });
+
+        has_phrase || self.regexes.is_match(content) || self.regexes.is_match(&normalized)
+    }
+}
+
+/// Whether some run of consecutive `words` spells out `phrase` when joined together, which
+/// catches phrases that are spaced out letter by letter or written as one word.
+fn contains_joined(words: &[String], phrase: &str) -> bool {
+    (0..words.len()).any(|start| {
+        let mut joined = String::new();
+        for word in &words[start..] {
+            joined.push_str(word);
+            if !phrase.starts_with(&joined) {
+                return false;
+            }
+            if joined.len() == phrase.len() {
+                return true;
+            }
+        }
+        false
+    })
+}
+
+/// Compiles a regex trigger the way it will be matched, to check it before it is saved.
+pub fn compile_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .build()
+}
+
+/// Splits text into lowercase words, dropping punctuation and whitespace.
+pub fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+async fn get_cache(bot: &Bot) -> Arc<ShardMap<GuildId, Arc<TriggerSet>>> {
+    let data_read = bot.data.read().await;
+    data_read
+        .get::<TriggerCache>()
+        .expect("TriggerCache not found in TypeMap")
+        .clone()
+}
+
+/// The triggers messages in a guild are matched against, compiling them the first time they are
+/// needed. Messages outside of a guild use the defaults.
+pub async fn guild_triggers(
+    bot: &Bot,
+    db: &WeedDatabase<'static>,
+    guild_id: Option<GuildId>,
+) -> Arc<TriggerSet> {
+    let Some(guild_id) = guild_id else {
+        return DEFAULT_TRIGGERS.clone();
+    };
+
+    let cache = get_cache(bot).await;
+    if let Some(triggers) = cache.get(&guild_id).await {
+        return triggers.clone();
+    }
+
+    let config = match db.guild_config(guild_id) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Failed to fetch triggers for {guild_id}: {e:?}");
+            // Don't cache the defaults, so the guild's own triggers are tried again next time.
+            return DEFAULT_TRIGGERS.clone();
+        }
+    };
+
+    let triggers = Arc::new(TriggerSet::new(&config.triggers, config.match_mode));
+    cache.insert(guild_id, triggers.clone()).await;
+    triggers
+}
+
+/// Drops a guild's compiled triggers, after they have been changed.
+pub async fn forget_triggers(bot: &Bot, guild_id: GuildId) {
+    get_cache(bot).await.remove(&guild_id).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_phrases_as_whole_words() {
+        let triggers = TriggerSet::new(
+            &[Trigger::phrase("weed time"), Trigger::phrase("Blaze")],
+            MatchMode::Strict,
+        );
+
+        for (content, expected) in [
+            ("weed time", true),
+            ("WEED TIME!!", true),
+            ("it's weed time, everyone", true),
+            ("weed\ntime", true),
+            ("blaze", true),
+            ("weed timer", false),
+            ("tweed time", false),
+            ("weedtime", false),
+            ("time weed", false),
+            ("blazes", false),
+            ("**weed** time", true),
+            ("ｗｅｅｄ ｔｉｍｅ", true),
+            ("𝐰𝐞𝐞𝐝 𝐭𝐢𝐦𝐞", true),
+            ("w e e d  t i m e", false),
+            ("W33D T1ME", false),
+            ("", false),
+        ] {
+            assert_eq!(triggers.is_match(content), expected, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn matches_obfuscated_phrases_in_lenient_mode() {
+        let triggers = TriggerSet::new(&[Trigger::phrase("weed time")], MatchMode::Lenient);
+
+        for (content, expected) in [
+            ("weed time", true),
+            ("weedtime", true),
+            ("WeedTime!", true),
+            ("w e e d  t i m e", true),
+            ("w.e.e.d t.i.m.e", true),
+            ("W33D T1ME", true),
+            ("w33dt1m3", true),
+            ("ｗｅｅｄ　ｔｉｍｅ", true),
+            ("𝓌𝑒𝑒𝒹 𝓉𝒾𝓂𝑒", true),
+            ("🇼🇪🇪🇩 🇹🇮🇲🇪", true),
+            ("wееd tіmе", true),
+            ("wéèd tímé", true),
+            ("**weed** time", true),
+            ("we**ed** ti__me__", true),
+            ("||weed time||", true),
+            ("we\u{200B}ed\u{200B}time", true),
+            ("it's weed time at 4:20", true),
+            ("tweed time", false),
+            ("weed timer", false),
+            ("time weed", false),
+            ("weed", false),
+            ("4:20", false),
+            ("", false),
+        ] {
+            assert_eq!(triggers.is_match(content), expected, "{content:?}");
+        }
+    }
+
+    #[test]
+    fn matches_regexes_before_and_after_normalizing() {
+        for mode in [MatchMode::Strict, MatchMode::Lenient] {
+            let triggers = TriggerSet::new(&[Trigger::regex(r"4:20|blaze\s+it")], mode);
+            assert!(triggers.is_match("4:20"), "{mode:?}");
+            assert!(triggers.is_match("BLAZE  IT"), "{mode:?}");
+            assert!(triggers.is_match("ｂｌａｚｅ ｉｔ"), "{mode:?}");
+            assert!(!triggers.is_match("weed time"), "{mode:?}");
+        }
+
+        let triggers = TriggerSet::new(&[Trigger::regex(r"blaze it")], MatchMode::Lenient);
+        assert!(triggers.is_match("bl4z3 **it**"));
+    }
+
+    #[test]
+    fn ignores_empty_phrases() {
+        for mode in [MatchMode::Strict, MatchMode::Lenient] {
+            let triggers = TriggerSet::new(&[Trigger::phrase("  !! ")], mode);
+            assert!(!triggers.is_match("anything"), "{mode:?}");
+            assert!(!triggers.is_match(""), "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_regexes() {
+        assert!(compile_regex("weed (time").is_err());
+        assert!(compile_regex("weed time").is_ok());
+    }
+}
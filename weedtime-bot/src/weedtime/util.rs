@@ -1,11 +1,13 @@
 use std::sync::Arc;
 
-use chrono::{DateTime, Timelike};
 use chrono_tz::Tz;
-use serenity::all::{ChannelId, Context};
+use serenity::all::{ChannelId, GuildId};
 use whirlwind::ShardMap;
 
-use crate::{MessageCount, WeedTimeMessage};
+use crate::{
+    Bot, BotClock, ChainWindows, MessageCount, WeedTimeMessage,
+    weedtime::{clock::Clock, scheduler::WindowSchedule},
+};
 
 pub fn combo_to_emojis(combo: u32) -> String {
     // Get the amount of times a number can be divided by 10 without going under 10
@@ -35,26 +37,61 @@ pub fn combo_to_emojis(combo: u32) -> String {
     str.to_string()
 }
 
-pub fn is_420(timestamp: DateTime<Tz>) -> bool {
-    let (_, hour) = timestamp.hour12();
-    let minute = timestamp.minute();
-
-    hour == 4 && minute == 20
+/// The city a timezone is named after, like `Kathmandu` for `Asia/Kathmandu`.
+pub fn city_name(timezone: Tz) -> String {
+    let name = timezone.name();
+    name.rsplit('/').next().unwrap_or(name).replace('_', " ")
 }
 
-pub fn has_unique_elements<T>(iter: T) -> bool
-where
-    T: IntoIterator,
-    T::Item: Eq + std::hash::Hash,
-{
-    let mut uniq = std::collections::HashSet::new();
-    iter.into_iter().all(move |x| uniq.insert(x))
+/// `channel_id`, in `guild_id`, followed by the channels it sits in, closest first: a thread's
+/// parent channel, then that channel's category.
+pub async fn channel_ancestry(
+    bot: &Bot,
+    guild_id: GuildId,
+    channel_id: ChannelId,
+) -> Vec<ChannelId> {
+    let mut channels = vec![channel_id];
+
+    // Threads are as deep as channels go, two levels below a category.
+    for _ in 0..2 {
+        let current = channels[channels.len() - 1];
+        let parent_id = match bot.transport.parent_channel(guild_id, current).await {
+            Ok(parent_id) => parent_id,
+            Err(e) => {
+                tracing::warn!("Failed to fetch channel {current}: {e:?}");
+                None
+            }
+        };
+
+        match parent_id {
+            Some(parent_id) => channels.push(parent_id),
+            None => break,
+        }
+    }
+
+    channels
 }
 
-pub async fn get_map(ctx: &Context) -> Arc<ShardMap<ChannelId, WeedTimeMessage>> {
-    let data_read = ctx.data.read().await;
+pub async fn get_map(bot: &Bot) -> Arc<ShardMap<ChannelId, WeedTimeMessage>> {
+    let data_read = bot.data.read().await;
     data_read
         .get::<MessageCount>()
         .expect("MessageCount not found in TypeMap")
         .clone()
 }
+
+pub async fn get_schedule(bot: &Bot) -> Arc<WindowSchedule> {
+    let data_read = bot.data.read().await;
+    data_read
+        .get::<ChainWindows>()
+        .expect("ChainWindows not found in TypeMap")
+        .clone()
+}
+
+pub async fn get_clock(bot: &Bot) -> Arc<dyn Clock> {
+    let data_read = bot.data.read().await;
+    data_read
+        .get::<BotClock>()
+        .expect("BotClock not found in TypeMap")
+        .clone()
+}
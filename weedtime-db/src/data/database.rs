@@ -0,0 +1,1850 @@
+use std::{ops::RangeBounds, path::Path};
+
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use native_db::{Builder, Database, db_type, transaction::RwTransaction};
+
+use super::{
+    Chain, ChainState, GuildConfig, GuildStats, ProcessedMessage, UserConfig, UserStats,
+    UserTotals, WeedEvent,
+    leaderboard::{Board, rank_values, recount, totals_rank_values},
+    migration::migrate_models,
+    streak,
+    v1::{
+        self, ChainKey, ChannelId, GuildId, GuildUserId, MessageId, Trigger, UserId, WeedEventKind,
+        guild_key, time_key,
+    },
+    v2::{self, MatchMode, WeedEventKey},
+    v5::{TimezoneSource, UserStatsKey},
+    v6::ResponseMode,
+};
+
+pub trait WeedTimeDatabase {}
+
+/// The bot's database. Every model lives in this one file, so an update that touches several of
+/// them can be applied in a single transaction.
+pub struct WeedDatabase<'a>(pub(crate) Database<'a>);
+
+impl WeedDatabase<'static> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, db_type::Error> {
+        Ok(Self(Builder::new().create(&crate::MODELS, path)?))
+    }
+
+    /// Opens an existing database. Data written by an older version of the bot isn't visible
+    /// until the database has been migrated, see [`WeedDatabase::migrate`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, db_type::Error> {
+        Ok(Self(Builder::new().open(&crate::MODELS, path)?))
+    }
+
+    pub fn create_in_memory() -> Result<Self, db_type::Error> {
+        Ok(Self(Builder::new().create_in_memory(&crate::MODELS)?))
+    }
+}
+
+impl<'a> WeedDatabase<'a> {
+    /// Copies everything out of the separate user and guild stats files the bot used to keep,
+    /// and upgrades it to the latest models, in one transaction. Paths that don't exist are
+    /// skipped, and the data in the old files is left as it is.
+    pub fn import_split_databases(
+        &self,
+        user_db_path: impl AsRef<Path>,
+        guild_db_path: impl AsRef<Path>,
+    ) -> Result<(), db_type::Error> {
+        let rw = self.0.rw_transaction()?;
+
+        if user_db_path.as_ref().exists() {
+            let user_db = Builder::new().open(&crate::USER_MODELS, user_db_path)?;
+
+            let r = user_db.r_transaction()?;
+            for stats in r.scan().primary::<v1::UserStats>()?.all()? {
+                rw.insert(stats?)?;
+            }
+            for stats in r.scan().primary::<v2::UserStats>()?.all()? {
+                rw.insert(stats?)?;
+            }
+            for event in r.scan().primary::<v1::WeedEvent>()?.all()? {
+                rw.insert(event?)?;
+            }
+        }
+
+        if guild_db_path.as_ref().exists() {
+            let guild_db = Builder::new().open(&crate::GUILD_MODELS, guild_db_path)?;
+
+            let r = guild_db.r_transaction()?;
+            for stats in r.scan().primary::<v1::GuildStats>()?.all()? {
+                rw.insert(stats?)?;
+            }
+        }
+
+        migrate_models(&rw)?;
+        rw.commit()
+    }
+
+    /// Stats for a user in one guild, or outside of any guild when `guild_id` is `None`.
+    pub fn user_stats(
+        &self,
+        guild_id: Option<serenity::all::GuildId>,
+        user_id: serenity::all::UserId,
+    ) -> Result<Option<UserStats>, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        r.get()
+            .primary::<UserStats>(GuildUserId::new(guild_id, user_id))
+    }
+
+    /// A user's stats summed over every guild they have stats in. The returned record has no
+    /// guild, and no current streak.
+    pub fn user_totals(
+        &self,
+        user_id: serenity::all::UserId,
+    ) -> Result<Option<UserStats>, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        let Some(mut totals) = r
+            .get()
+            .primary::<UserTotals>(UserId::from(user_id))?
+            .map(UserStats::from)
+        else {
+            return Ok(None);
+        };
+
+        // Streaks are counted in each guild's own timezone, so they can't be added up. The best
+        // one anywhere is the best one overall.
+        for stats in r
+            .scan()
+            .secondary::<UserStats>(UserStatsKey::user_key)?
+            .start_with(UserId::from(user_id))?
+        {
+            totals.best_streak = totals.best_streak.max(stats?.best_streak);
+        }
+
+        Ok(Some(totals))
+    }
+
+    pub fn guild_stats(
+        &self,
+        guild_id: serenity::all::GuildId,
+    ) -> Result<Option<GuildStats>, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        r.get().primary::<GuildStats>(GuildId::from(guild_id))
+    }
+
+    /// A guild's settings, or the defaults if it hasn't changed any.
+    pub fn guild_config(
+        &self,
+        guild_id: serenity::all::GuildId,
+    ) -> Result<GuildConfig, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        Ok(r.get()
+            .primary::<GuildConfig>(GuildId::from(guild_id))?
+            .unwrap_or_else(|| GuildConfig::new(guild_id)))
+    }
+
+    /// A user's settings, or the defaults if they haven't changed any.
+    pub fn user_config(
+        &self,
+        user_id: serenity::all::UserId,
+    ) -> Result<UserConfig, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        Ok(r.get()
+            .primary::<UserConfig>(UserId::from(user_id))?
+            .unwrap_or_else(|| UserConfig::new(user_id)))
+    }
+
+    /// The event a message was logged as, if it was counted for anything.
+    pub fn event(
+        &self,
+        message_id: serenity::all::MessageId,
+    ) -> Result<Option<WeedEvent>, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        r.get().primary::<WeedEvent>(MessageId::from(message_id))
+    }
+
+    /// Events in a guild (or outside of any guild when `guild_id` is `None`) within `range`,
+    /// oldest first.
+    pub fn guild_events(
+        &self,
+        guild_id: Option<serenity::all::GuildId>,
+        range: impl RangeBounds<DateTime<Utc>>,
+    ) -> Result<Vec<WeedEvent>, db_type::Error> {
+        let guild_id = guild_id.map_or(0, |guild_id| guild_id.get());
+        let Some((start, end)) = time_bounds(range) else {
+            return Ok(Vec::new());
+        };
+
+        let r = self.0.r_transaction()?;
+        r.scan()
+            .secondary::<WeedEvent>(WeedEventKey::guild_key)?
+            .range((guild_id, start)..=(guild_id, end))?
+            .collect()
+    }
+
+    /// A user's events across every guild within `range`, oldest first.
+    pub fn user_events(
+        &self,
+        user_id: serenity::all::UserId,
+        range: impl RangeBounds<DateTime<Utc>>,
+    ) -> Result<Vec<WeedEvent>, db_type::Error> {
+        let user_id = user_id.get();
+        let Some((start, end)) = time_bounds(range) else {
+            return Ok(Vec::new());
+        };
+
+        let r = self.0.r_transaction()?;
+        r.scan()
+            .secondary::<WeedEvent>(WeedEventKey::user_key)?
+            .range((user_id, start)..=(user_id, end))?
+            .collect()
+    }
+
+    /// Every event within `range`, oldest first.
+    pub fn events(
+        &self,
+        range: impl RangeBounds<DateTime<Utc>>,
+    ) -> Result<Vec<WeedEvent>, db_type::Error> {
+        let Some((start, end)) = time_bounds(range) else {
+            return Ok(Vec::new());
+        };
+
+        let r = self.0.r_transaction()?;
+        r.scan()
+            .secondary::<WeedEvent>(WeedEventKey::time_key)?
+            .range(start..=end)?
+            .collect()
+    }
+
+    /// Every chain that was still running when it was last saved.
+    pub fn chain_states(&self) -> Result<Vec<ChainState>, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        r.scan().primary::<ChainState>()?.all()?.collect()
+    }
+
+    /// Removes chains whose window closed at or before `now`, and returns how many there were.
+    /// They ran until their window closed, so they are kept in the guild's chain history.
+    pub fn prune_chain_states(&self, now: DateTime<Utc>) -> Result<usize, db_type::Error> {
+        let rw = self.0.rw_transaction()?;
+        let expired = rw
+            .scan()
+            .primary::<ChainState>()?
+            .all()?
+            .filter(|state| state.as_ref().is_ok_and(|state| state.expires_at <= now))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let count = expired.len();
+        for state in expired {
+            rw.upsert(Chain::new(
+                state.message_id(),
+                state.guild_id(),
+                state.channel_id(),
+                state.window_start,
+                state.users(),
+                None,
+                state.expires_at,
+            ))?;
+            rw.remove(state)?;
+        }
+        rw.commit()?;
+        Ok(count)
+    }
+
+    /// Up to `limit` of the longest chains in a guild (or outside of any guild when `guild_id` is
+    /// `None`), longest first.
+    pub fn longest_chains(
+        &self,
+        guild_id: Option<serenity::all::GuildId>,
+        limit: usize,
+    ) -> Result<Vec<Chain>, db_type::Error> {
+        let guild_key = guild_key(guild_id);
+        let r = self.0.r_transaction()?;
+        r.scan()
+            .secondary::<Chain>(ChainKey::length_key)?
+            .range((guild_key, 1)..=(guild_key, u32::MAX))?
+            .rev()
+            .take(limit)
+            .collect()
+    }
+
+    /// The longest chain that has ended in `channel_id`, if any has.
+    pub fn longest_channel_chain(
+        &self,
+        guild_id: Option<serenity::all::GuildId>,
+        channel_id: serenity::all::ChannelId,
+    ) -> Result<Option<Chain>, db_type::Error> {
+        let guild_key = guild_key(guild_id);
+        let r = self.0.r_transaction()?;
+        for chain in r
+            .scan()
+            .secondary::<Chain>(ChainKey::length_key)?
+            .range((guild_key, 1)..=(guild_key, u32::MAX))?
+            .rev()
+        {
+            let chain = chain?;
+            if chain.channel_id() == channel_id {
+                return Ok(Some(chain));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Up to `limit` of the chains in a guild that ended most recently, latest first.
+    pub fn recent_chains(
+        &self,
+        guild_id: Option<serenity::all::GuildId>,
+        limit: usize,
+    ) -> Result<Vec<Chain>, db_type::Error> {
+        let guild_key = guild_key(guild_id);
+        let r = self.0.r_transaction()?;
+        r.scan()
+            .secondary::<Chain>(ChainKey::ended_key)?
+            .range((guild_key, 0)..=(guild_key, u64::MAX))?
+            .rev()
+            .take(limit)
+            .collect()
+    }
+
+    /// Whether an update for `message_id` has already been committed.
+    pub fn is_processed(
+        &self,
+        message_id: serenity::all::MessageId,
+    ) -> Result<bool, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        Ok(r.get()
+            .primary::<ProcessedMessage>(MessageId::from(message_id))?
+            .is_some())
+    }
+
+    /// Forgets messages sent before `before`, which Discord won't deliver again, and returns how
+    /// many there were.
+    pub fn prune_processed_messages(&self, before: DateTime<Utc>) -> Result<usize, db_type::Error> {
+        let rw = self.0.rw_transaction()?;
+        let old = rw
+            .scan()
+            .primary::<ProcessedMessage>()?
+            .all()?
+            .filter(|processed| {
+                processed
+                    .as_ref()
+                    .is_ok_and(|processed| *processed.message_id().created_at() < before)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let count = old.len();
+        for processed in old {
+            rw.remove(processed)?;
+        }
+        rw.commit()?;
+        Ok(count)
+    }
+}
+
+/// Inclusive millisecond bounds for scanning the event time keys, or `None` if `range` is empty.
+fn time_bounds(range: impl RangeBounds<DateTime<Utc>>) -> Option<(u64, u64)> {
+    use std::ops::Bound;
+
+    let start = match range.start_bound() {
+        Bound::Included(start) => time_key(*start),
+        Bound::Excluded(start) => time_key(*start).saturating_add(1),
+        Bound::Unbounded => 0,
+    };
+    let end = match range.end_bound() {
+        Bound::Included(end) => time_key(*end),
+        Bound::Excluded(end) => time_key(*end).saturating_sub(1),
+        Bound::Unbounded => u64::MAX,
+    };
+
+    (start <= end).then_some((start, end))
+}
+
+impl<'a> WeedTimeDatabase for WeedDatabase<'a> {}
+
+pub trait DbUpdate<T: WeedTimeDatabase> {
+    fn commit(&self, db: &T) -> Result<(), db_type::Error>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserStatsUpdate {
+    pub user_id: Option<serenity::all::UserId>,
+    pub guild_id: Option<serenity::all::GuildId>,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub chains_started: u32,
+    pub chains_broken: u32,
+    pub longest_chain: Option<u32>,
+    /// The day, in the guild's timezone, the user had a weed time on, which extends their streak.
+    pub weed_day: Option<NaiveDate>,
+}
+
+impl UserStatsUpdate {
+    pub fn new(user_id: serenity::all::UserId, guild_id: Option<serenity::all::GuildId>) -> Self {
+        Self {
+            user_id: Some(user_id),
+            guild_id,
+            ..Self::default()
+        }
+    }
+}
+
+impl UserStatsUpdate {
+    fn apply(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        let Some(user_id) = self.user_id else {
+            return Ok(());
+        };
+
+        let id = GuildUserId::new(self.guild_id, user_id);
+        let mut stats = rw
+            .get()
+            .primary::<UserStats>(id)?
+            .unwrap_or(UserStats::new(id));
+        let before = rank_values(&stats);
+
+        stats.weed_times = stats.weed_times.saturating_add(self.weed_times);
+        stats.weed_crimes = stats.weed_crimes.saturating_add(self.weed_crimes);
+        stats.chains_started = stats.chains_started.saturating_add(self.chains_started);
+        stats.chains_broken = stats.chains_broken.saturating_add(self.chains_broken);
+        if let Some(longest_chain) = self.longest_chain {
+            stats.longest_chain = stats.longest_chain.max(longest_chain);
+        }
+        if let Some(streak) = self
+            .weed_day
+            .and_then(|day| streak::extend(stats.current_streak, stats.last_weed_day, day))
+        {
+            stats.current_streak = streak;
+            stats.best_streak = stats.best_streak.max(streak);
+            stats.last_weed_day = self.weed_day;
+        }
+
+        recount(
+            rw,
+            Board::Guild(id.guild_key()),
+            before,
+            rank_values(&stats),
+        )?;
+        rw.upsert(stats)?;
+        self.apply_totals(rw)
+    }
+
+    /// Subtracts the counters, for a message that no longer counts.
+    fn revert(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        let Some(user_id) = self.user_id else {
+            return Ok(());
+        };
+
+        let id = GuildUserId::new(self.guild_id, user_id);
+        if let Some(mut stats) = rw.get().primary::<UserStats>(id)? {
+            let before = rank_values(&stats);
+            stats.weed_times = stats.weed_times.saturating_sub(self.weed_times);
+            stats.weed_crimes = stats.weed_crimes.saturating_sub(self.weed_crimes);
+            stats.chains_started = stats.chains_started.saturating_sub(self.chains_started);
+            stats.chains_broken = stats.chains_broken.saturating_sub(self.chains_broken);
+            recount(
+                rw,
+                Board::Guild(id.guild_key()),
+                before,
+                rank_values(&stats),
+            )?;
+            rw.upsert(stats)?;
+        }
+
+        if let Some(mut totals) = rw.get().primary::<UserTotals>(UserId::from(user_id))? {
+            let before = totals_rank_values(&totals);
+            totals.weed_times = totals.weed_times.saturating_sub(self.weed_times);
+            totals.weed_crimes = totals.weed_crimes.saturating_sub(self.weed_crimes);
+            totals.chains_started = totals.chains_started.saturating_sub(self.chains_started);
+            totals.chains_broken = totals.chains_broken.saturating_sub(self.chains_broken);
+            recount(rw, Board::Global, before, totals_rank_values(&totals))?;
+            rw.upsert(totals)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn apply_totals(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        let Some(user_id) = self.user_id else {
+            return Ok(());
+        };
+
+        let id = UserId::from(user_id);
+        let mut totals = rw
+            .get()
+            .primary::<UserTotals>(id)?
+            .unwrap_or(UserTotals::new(id));
+        let before = totals_rank_values(&totals);
+
+        totals.weed_times = totals.weed_times.saturating_add(self.weed_times);
+        totals.weed_crimes = totals.weed_crimes.saturating_add(self.weed_crimes);
+        totals.chains_started = totals.chains_started.saturating_add(self.chains_started);
+        totals.chains_broken = totals.chains_broken.saturating_add(self.chains_broken);
+        if let Some(longest_chain) = self.longest_chain {
+            totals.longest_chain = totals.longest_chain.max(longest_chain);
+        }
+
+        recount(rw, Board::Global, before, totals_rank_values(&totals))?;
+        rw.upsert(totals)?;
+        Ok(())
+    }
+}
+
+impl From<&UserStats> for UserStatsUpdate {
+    fn from(stats: &UserStats) -> Self {
+        Self {
+            user_id: Some(stats.id()),
+            guild_id: stats.guild_id(),
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+            longest_chain: Some(stats.longest_chain),
+            weed_day: None,
+        }
+    }
+}
+
+// Totals read like stats kept outside of any guild.
+impl From<UserTotals> for UserStats {
+    fn from(totals: UserTotals) -> Self {
+        Self {
+            weed_times: totals.weed_times,
+            weed_crimes: totals.weed_crimes,
+            chains_started: totals.chains_started,
+            chains_broken: totals.chains_broken,
+            longest_chain: totals.longest_chain,
+            ..UserStats::new(GuildUserId::new(None, totals.id()))
+        }
+    }
+}
+
+impl<'a> DbUpdate<WeedDatabase<'a>> for UserStatsUpdate {
+    fn commit(&self, db: &WeedDatabase) -> Result<(), db_type::Error> {
+        let rw = db.0.rw_transaction()?;
+        self.apply(&rw)?;
+        rw.commit()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuildStatsUpdate {
+    pub guild_id: Option<serenity::all::GuildId>,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub longest_chain: Option<u32>,
+    /// The day, in the guild's timezone, someone in the guild had a weed time on.
+    pub weed_day: Option<NaiveDate>,
+}
+
+impl GuildStatsUpdate {
+    pub fn new(guild_id: serenity::all::GuildId) -> Self {
+        Self {
+            guild_id: Some(guild_id),
+            ..Self::default()
+        }
+    }
+}
+
+impl GuildStatsUpdate {
+    fn apply(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        let Some(guild_id) = self.guild_id else {
+            return Ok(());
+        };
+
+        let mut stats = rw
+            .get()
+            .primary::<GuildStats>(GuildId::from(guild_id))?
+            .unwrap_or_else(|| GuildStats::new(GuildId::from(guild_id)));
+
+        stats.weed_times = stats.weed_times.saturating_add(self.weed_times);
+        stats.weed_crimes = stats.weed_crimes.saturating_add(self.weed_crimes);
+        if let Some(longest_chain) = self.longest_chain {
+            stats.longest_chain = stats.longest_chain.max(longest_chain);
+        }
+        if let Some(streak) = self
+            .weed_day
+            .and_then(|day| streak::extend(stats.current_streak, stats.last_weed_day, day))
+        {
+            stats.current_streak = streak;
+            stats.best_streak = stats.best_streak.max(streak);
+            stats.last_weed_day = self.weed_day;
+        }
+
+        rw.upsert(stats)?;
+        Ok(())
+    }
+}
+
+impl GuildStatsUpdate {
+    /// Subtracts the counters, for a message that no longer counts.
+    fn revert(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        let Some(guild_id) = self.guild_id else {
+            return Ok(());
+        };
+
+        if let Some(mut stats) = rw.get().primary::<GuildStats>(GuildId::from(guild_id))? {
+            stats.weed_times = stats.weed_times.saturating_sub(self.weed_times);
+            stats.weed_crimes = stats.weed_crimes.saturating_sub(self.weed_crimes);
+            rw.upsert(stats)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> DbUpdate<WeedDatabase<'a>> for GuildStatsUpdate {
+    fn commit(&self, db: &WeedDatabase) -> Result<(), db_type::Error> {
+        let rw = db.0.rw_transaction()?;
+        self.apply(&rw)?;
+        rw.commit()?;
+        Ok(())
+    }
+}
+
+/// Changes to a guild's settings. Adding a trigger or time the guild already has, or removing one
+/// it doesn't, does nothing.
+#[derive(Debug, Clone, Default)]
+pub struct GuildConfigUpdate {
+    pub guild_id: Option<serenity::all::GuildId>,
+    pub timezone: Option<chrono_tz::Tz>,
+    pub add_trigger: Option<Trigger>,
+    /// The pattern of the trigger to remove.
+    pub remove_trigger: Option<String>,
+    pub match_mode: Option<MatchMode>,
+    pub am: Option<bool>,
+    pub pm: Option<bool>,
+    pub grace_seconds: Option<u32>,
+    pub anywhere: Option<bool>,
+    pub add_time: Option<NaiveTime>,
+    pub remove_time: Option<NaiveTime>,
+    pub timezone_source: Option<TimezoneSource>,
+    pub response_mode: Option<ResponseMode>,
+    /// A locale in the form Discord names them, like `en-US`.
+    pub locale: Option<String>,
+    /// Watches this channel, or every channel in this category.
+    pub allow_channel: Option<serenity::all::ChannelId>,
+    /// Ignores this channel, or every channel in this category.
+    pub deny_channel: Option<serenity::all::ChannelId>,
+    /// Forgets whether this channel was allowed or denied.
+    pub clear_channel: Option<serenity::all::ChannelId>,
+}
+
+impl GuildConfigUpdate {
+    pub fn new(guild_id: serenity::all::GuildId) -> Self {
+        Self {
+            guild_id: Some(guild_id),
+            ..Self::default()
+        }
+    }
+}
+
+impl GuildConfigUpdate {
+    fn apply(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        let Some(guild_id) = self.guild_id else {
+            return Ok(());
+        };
+
+        let mut config = rw
+            .get()
+            .primary::<GuildConfig>(GuildId::from(guild_id))?
+            .unwrap_or_else(|| GuildConfig::new(guild_id));
+
+        if let Some(timezone) = self.timezone {
+            config.timezone = timezone;
+        }
+        if let Some(trigger) = &self.add_trigger
+            && !config.triggers.contains(trigger)
+        {
+            config.triggers.push(trigger.clone());
+        }
+        if let Some(pattern) = &self.remove_trigger {
+            config
+                .triggers
+                .retain(|trigger| &trigger.pattern != pattern);
+        }
+        if let Some(match_mode) = self.match_mode {
+            config.match_mode = match_mode;
+        }
+        if let Some(timezone_source) = self.timezone_source {
+            config.timezone_source = timezone_source;
+        }
+        if let Some(response_mode) = self.response_mode {
+            config.response_mode = response_mode;
+        }
+        if let Some(locale) = &self.locale {
+            config.locale = locale.clone();
+        }
+        if let Some(channel_id) = self.clear_channel {
+            config.channels.clear(channel_id);
+        }
+        if let Some(channel_id) = self.allow_channel {
+            config.channels.allow(channel_id);
+        }
+        if let Some(channel_id) = self.deny_channel {
+            config.channels.deny(channel_id);
+        }
+
+        let window = &mut config.window;
+        if let Some(am) = self.am {
+            window.am = am;
+        }
+        if let Some(pm) = self.pm {
+            window.pm = pm;
+        }
+        if let Some(grace_seconds) = self.grace_seconds {
+            window.grace_seconds = grace_seconds;
+        }
+        if let Some(anywhere) = self.anywhere {
+            window.anywhere = anywhere;
+        }
+        if let Some(time) = self.add_time
+            && !window.extra_times.contains(&time)
+        {
+            window.extra_times.push(time);
+            window.extra_times.sort();
+        }
+        if let Some(time) = self.remove_time {
+            window.extra_times.retain(|&extra_time| extra_time != time);
+        }
+
+        rw.upsert(config)?;
+        Ok(())
+    }
+}
+
+impl<'a> DbUpdate<WeedDatabase<'a>> for GuildConfigUpdate {
+    fn commit(&self, db: &WeedDatabase) -> Result<(), db_type::Error> {
+        let rw = db.0.rw_transaction()?;
+        self.apply(&rw)?;
+        rw.commit()?;
+        Ok(())
+    }
+}
+
+/// Changes to a user's settings.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserConfigUpdate {
+    pub user_id: Option<serenity::all::UserId>,
+    pub timezone: Option<chrono_tz::Tz>,
+    /// Forgets the user's timezone, so guilds go by their own again.
+    pub clear_timezone: bool,
+}
+
+impl UserConfigUpdate {
+    pub fn new(user_id: serenity::all::UserId) -> Self {
+        Self {
+            user_id: Some(user_id),
+            ..Self::default()
+        }
+    }
+}
+
+impl UserConfigUpdate {
+    fn apply(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        let Some(user_id) = self.user_id else {
+            return Ok(());
+        };
+
+        let mut config = rw
+            .get()
+            .primary::<UserConfig>(UserId::from(user_id))?
+            .unwrap_or_else(|| UserConfig::new(user_id));
+
+        if self.clear_timezone {
+            config.timezone = None;
+        }
+        if let Some(timezone) = self.timezone {
+            config.timezone = Some(timezone);
+        }
+
+        rw.upsert(config)?;
+        Ok(())
+    }
+}
+
+impl<'a> DbUpdate<WeedDatabase<'a>> for UserConfigUpdate {
+    fn commit(&self, db: &WeedDatabase) -> Result<(), db_type::Error> {
+        let rw = db.0.rw_transaction()?;
+        self.apply(&rw)?;
+        rw.commit()?;
+        Ok(())
+    }
+}
+
+/// What happened to the chain running in a channel.
+#[derive(Debug, Clone)]
+pub enum ChainUpdate {
+    /// The chain started or continued, and this is where it stands now.
+    Save(ChainState),
+    /// The chain in this channel was broken.
+    Clear(serenity::all::ChannelId),
+}
+
+impl ChainUpdate {
+    fn apply(&self, rw: &RwTransaction) -> Result<(), db_type::Error> {
+        match self {
+            ChainUpdate::Save(state) => {
+                rw.upsert(state.clone())?;
+            }
+            ChainUpdate::Clear(channel_id) => {
+                if let Some(state) = rw
+                    .get()
+                    .primary::<ChainState>(ChannelId::from(*channel_id))?
+                {
+                    rw.remove(state)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everything handling one message changes: the counters it bumps, the event it logs and the
+/// state of the channel's chain. It is committed in a single transaction, so either all of it is
+/// applied or none of it is.
+#[derive(Debug, Clone, Default)]
+pub struct MessageUpdate {
+    /// The message being handled. An update for a message that was already committed is skipped,
+    /// so a message Discord delivers twice is only counted once.
+    pub message_id: Option<serenity::all::MessageId>,
+    pub user: UserStatsUpdate,
+    pub guild: GuildStatsUpdate,
+    pub event: Option<WeedEvent>,
+    pub chain: Option<ChainUpdate>,
+    /// A chain the message ended, for the guild's chain history.
+    pub ended_chain: Option<Chain>,
+    /// Whether the message was edited after it was committed. An edited message is counted again,
+    /// and its event replaced, unless it was already counted as a weed time or a weed crime.
+    pub edited: bool,
+}
+
+/// Takes back what a deleted message was counted for, along with the event it logged. Longest
+/// chains and streaks are left alone, since what they were before can't be worked back out.
+#[derive(Debug, Clone, Default)]
+pub struct MessageRevert {
+    pub message_id: Option<serenity::all::MessageId>,
+    /// What the message added to its author's stats.
+    pub user: UserStatsUpdate,
+    /// What the message added to its guild's stats.
+    pub guild: GuildStatsUpdate,
+    /// Where the channel's chain stands without the message.
+    pub chain: Option<ChainUpdate>,
+}
+
+impl<'a> DbUpdate<WeedDatabase<'a>> for MessageRevert {
+    fn commit(&self, db: &WeedDatabase) -> Result<(), db_type::Error> {
+        let rw = db.0.rw_transaction()?;
+        self.user.revert(&rw)?;
+        self.guild.revert(&rw)?;
+        if let Some(message_id) = self.message_id
+            && let Some(event) = rw.get().primary::<WeedEvent>(MessageId::from(message_id))?
+        {
+            rw.remove(event)?;
+        }
+        if let Some(chain) = &self.chain {
+            chain.apply(&rw)?;
+        }
+        rw.commit()?;
+        Ok(())
+    }
+}
+
+impl<'a> DbUpdate<WeedDatabase<'a>> for MessageUpdate {
+    fn commit(&self, db: &WeedDatabase) -> Result<(), db_type::Error> {
+        let rw = db.0.rw_transaction()?;
+        if let Some(message_id) = self.message_id {
+            let message_id = MessageId::from(message_id);
+            if rw.get().primary::<ProcessedMessage>(message_id)?.is_some() {
+                let counted = rw
+                    .get()
+                    .primary::<WeedEvent>(message_id)?
+                    .is_some_and(|event| {
+                        matches!(
+                            event.kind,
+                            WeedEventKind::WeedTime | WeedEventKind::WeedCrime
+                        )
+                    });
+                if !self.edited || counted {
+                    return Ok(());
+                }
+            } else {
+                rw.insert(ProcessedMessage::new(message_id.get()))?;
+            }
+        }
+        self.user.apply(&rw)?;
+        self.guild.apply(&rw)?;
+        if let Some(event) = self.event.clone() {
+            if self.edited {
+                rw.upsert(event)?;
+            } else {
+                rw.insert(event)?;
+            }
+        }
+        if let Some(chain) = &self.chain {
+            chain.apply(&rw)?;
+        }
+        if let Some(ended_chain) = self.ended_chain.clone() {
+            rw.upsert(ended_chain)?;
+        }
+        rw.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::data::{
+        v1::{self, WeedEventKind},
+        v4::WeedWindow,
+    };
+
+    fn event(
+        kind: WeedEventKind,
+        guild_id: Option<u64>,
+        user_id: u64,
+        message_id: u64,
+        timestamp: DateTime<Utc>,
+    ) -> WeedEvent {
+        WeedEvent::new(
+            kind,
+            guild_id.map(serenity::all::GuildId::new),
+            serenity::all::ChannelId::new(1),
+            serenity::all::UserId::new(user_id),
+            serenity::all::MessageId::new(message_id),
+            timestamp,
+        )
+    }
+
+    #[test]
+    fn commits_user_stats_updates() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+        let guild_id = serenity::all::GuildId::new(420);
+
+        UserStatsUpdate {
+            user_id: Some(user_id),
+            guild_id: Some(guild_id),
+            weed_times: 2,
+            weed_crimes: 1,
+            chains_started: 1,
+            chains_broken: 1,
+            longest_chain: Some(2),
+            weed_day: None,
+        }
+        .commit(&db)?;
+
+        UserStatsUpdate {
+            user_id: Some(user_id),
+            guild_id: Some(guild_id),
+            weed_times: 1,
+            ..Default::default()
+        }
+        .commit(&db)?;
+
+        let stats = db.user_stats(Some(guild_id), user_id)?.unwrap();
+        assert_eq!(stats.weed_times, 3);
+        assert_eq!(stats.weed_crimes, 1);
+        assert_eq!(stats.chains_started, 1);
+        assert_eq!(stats.chains_broken, 1);
+        assert_eq!(stats.longest_chain, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_user_stats_per_guild() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+        let other_user_id = serenity::all::UserId::new(43);
+        let guild_id = serenity::all::GuildId::new(420);
+        let other_guild_id = serenity::all::GuildId::new(710);
+
+        UserStatsUpdate {
+            weed_times: 2,
+            ..UserStatsUpdate::new(user_id, Some(guild_id))
+        }
+        .commit(&db)?;
+        UserStatsUpdate {
+            weed_times: 1,
+            weed_crimes: 1,
+            ..UserStatsUpdate::new(user_id, Some(other_guild_id))
+        }
+        .commit(&db)?;
+        UserStatsUpdate {
+            chains_started: 1,
+            ..UserStatsUpdate::new(user_id, None)
+        }
+        .commit(&db)?;
+        UserStatsUpdate {
+            weed_times: 5,
+            ..UserStatsUpdate::new(other_user_id, Some(guild_id))
+        }
+        .commit(&db)?;
+
+        let stats = db.user_stats(Some(guild_id), user_id)?.unwrap();
+        assert_eq!(stats.weed_times, 2);
+        assert_eq!(stats.weed_crimes, 0);
+
+        let stats = db.user_stats(Some(other_guild_id), user_id)?.unwrap();
+        assert_eq!(stats.weed_times, 1);
+        assert_eq!(stats.weed_crimes, 1);
+
+        let total = db.user_totals(user_id)?.unwrap();
+        assert_eq!(total.guild_id(), None);
+        assert_eq!(total.weed_times, 3);
+        assert_eq!(total.weed_crimes, 1);
+        assert_eq!(total.chains_started, 1);
+
+        assert!(db.user_totals(serenity::all::UserId::new(44))?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_global_user_stats_to_no_guild() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+
+        let rw = db.0.rw_transaction()?;
+        rw.insert(v1::UserStats {
+            id: UserId::from(user_id),
+            weed_times: 4,
+            weed_crimes: 2,
+            chains_started: 0,
+            chains_broken: 1,
+        })?;
+        rw.commit()?;
+
+        db.migrate()?;
+
+        let stats = db.user_stats(None, user_id)?.unwrap();
+        assert_eq!(stats.weed_times, 4);
+        assert_eq!(stats.weed_crimes, 2);
+        assert_eq!(stats.chains_broken, 1);
+        assert_eq!(db.user_totals(user_id)?.unwrap().weed_times, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_streaks() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+        let guild_id = serenity::all::GuildId::new(420);
+        let other_guild_id = serenity::all::GuildId::new(710);
+        let day = |day| NaiveDate::from_ymd_opt(2025, 4, day).unwrap();
+
+        let weed_time = |guild_id, weed_day| MessageUpdate {
+            user: UserStatsUpdate {
+                weed_times: 1,
+                weed_day: Some(weed_day),
+                ..UserStatsUpdate::new(user_id, Some(guild_id))
+            },
+            guild: GuildStatsUpdate {
+                weed_times: 1,
+                weed_day: Some(weed_day),
+                ..GuildStatsUpdate::new(guild_id)
+            },
+            ..Default::default()
+        };
+
+        // AM and PM on the 18th, then the 19th and 20th.
+        for weed_day in [day(18), day(18), day(19), day(20)] {
+            weed_time(guild_id, weed_day).commit(&db)?;
+        }
+        let stats = db.user_stats(Some(guild_id), user_id)?.unwrap();
+        assert_eq!(stats.current_streak, 3);
+        assert_eq!(stats.best_streak, 3);
+        assert_eq!(stats.last_weed_day, Some(day(20)));
+        assert_eq!(stats.streak_on(day(21)), 3);
+        assert_eq!(stats.streak_on(day(22)), 0);
+
+        let guild = db.guild_stats(guild_id)?.unwrap();
+        assert_eq!(guild.current_streak, 3);
+        assert_eq!(guild.last_weed_day, Some(day(20)));
+
+        // Missing a day starts over, but keeps the best streak.
+        weed_time(guild_id, day(22)).commit(&db)?;
+        let stats = db.user_stats(Some(guild_id), user_id)?.unwrap();
+        assert_eq!(stats.current_streak, 1);
+        assert_eq!(stats.best_streak, 3);
+
+        // Streaks are kept per guild.
+        weed_time(other_guild_id, day(22)).commit(&db)?;
+        let stats = db.user_stats(Some(other_guild_id), user_id)?.unwrap();
+        assert_eq!(stats.current_streak, 1);
+        assert_eq!(db.user_totals(user_id)?.unwrap().best_streak, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn updates_guild_triggers() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.id(), guild_id);
+        assert_eq!(config.triggers, [Trigger::phrase("weed time")]);
+
+        let add = |trigger| GuildConfigUpdate {
+            add_trigger: Some(trigger),
+            ..GuildConfigUpdate::new(guild_id)
+        };
+        add(Trigger::phrase("blaze it")).commit(&db)?;
+        add(Trigger::regex(r"4\s*20")).commit(&db)?;
+        add(Trigger::phrase("blaze it")).commit(&db)?;
+        assert_eq!(
+            db.guild_config(guild_id)?.triggers,
+            [
+                Trigger::phrase("weed time"),
+                Trigger::phrase("blaze it"),
+                Trigger::regex(r"4\s*20"),
+            ]
+        );
+
+        GuildConfigUpdate {
+            remove_trigger: Some("weed time".to_string()),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+        assert_eq!(
+            db.guild_config(guild_id)?.triggers,
+            [Trigger::phrase("blaze it"), Trigger::regex(r"4\s*20")]
+        );
+
+        // Other guilds keep the defaults.
+        let other_guild_id = serenity::all::GuildId::new(710);
+        assert_eq!(
+            db.guild_config(other_guild_id)?.triggers,
+            [Trigger::phrase("weed time")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn updates_guild_window() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        assert_eq!(db.guild_config(guild_id)?.window, WeedWindow::default());
+
+        let time = |hour, minute| NaiveTime::from_hms_opt(hour, minute, 0).unwrap();
+        let add = |time| GuildConfigUpdate {
+            add_time: Some(time),
+            ..GuildConfigUpdate::new(guild_id)
+        };
+        add(time(19, 10)).commit(&db)?;
+        add(time(7, 10)).commit(&db)?;
+        add(time(7, 10)).commit(&db)?;
+        GuildConfigUpdate {
+            am: Some(false),
+            grace_seconds: Some(30),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+        assert_eq!(
+            db.guild_config(guild_id)?.window,
+            WeedWindow {
+                am: false,
+                pm: true,
+                extra_times: vec![time(7, 10), time(19, 10)],
+                grace_seconds: 30,
+                anywhere: false,
+            }
+        );
+
+        GuildConfigUpdate {
+            remove_time: Some(time(19, 10)),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+        assert_eq!(db.guild_config(guild_id)?.window.extra_times, [time(7, 10)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn updates_user_timezone() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+
+        let config = db.user_config(user_id)?;
+        assert_eq!(config.id(), user_id);
+        assert_eq!(config.timezone, None);
+
+        UserConfigUpdate {
+            timezone: Some(chrono_tz::Tz::Asia__Kathmandu),
+            ..UserConfigUpdate::new(user_id)
+        }
+        .commit(&db)?;
+        assert_eq!(
+            db.user_config(user_id)?.timezone,
+            Some(chrono_tz::Tz::Asia__Kathmandu)
+        );
+        assert_eq!(
+            db.user_config(serenity::all::UserId::new(710))?.timezone,
+            None
+        );
+
+        UserConfigUpdate {
+            clear_timezone: true,
+            ..UserConfigUpdate::new(user_id)
+        }
+        .commit(&db)?;
+        assert_eq!(db.user_config(user_id)?.timezone, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn updates_guild_match_mode() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        assert_eq!(db.guild_config(guild_id)?.match_mode, MatchMode::Strict);
+
+        GuildConfigUpdate {
+            match_mode: Some(MatchMode::Lenient),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.match_mode, MatchMode::Lenient);
+        assert_eq!(config.triggers, [Trigger::phrase("weed time")]);
+
+        // Changing the triggers keeps the mode.
+        GuildConfigUpdate {
+            add_trigger: Some(Trigger::phrase("blaze it")),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+        assert_eq!(db.guild_config(guild_id)?.match_mode, MatchMode::Lenient);
+
+        GuildConfigUpdate {
+            timezone_source: Some(TimezoneSource::Either),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.timezone_source, TimezoneSource::Either);
+        assert_eq!(config.match_mode, MatchMode::Lenient);
+
+        Ok(())
+    }
+
+    #[test]
+    fn updates_guild_timezone() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.timezone, chrono_tz::Tz::America__New_York);
+        assert_eq!(config.response_mode, ResponseMode::Image);
+        assert_eq!(config.locale, "en-US");
+
+        GuildConfigUpdate {
+            timezone: Some(chrono_tz::Tz::Asia__Kathmandu),
+            response_mode: Some(ResponseMode::Text),
+            locale: Some("ne".to_string()),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+
+        // Recording stats leaves the settings alone.
+        GuildStatsUpdate {
+            weed_times: 1,
+            ..GuildStatsUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.timezone, chrono_tz::Tz::Asia__Kathmandu);
+        assert_eq!(config.response_mode, ResponseMode::Text);
+        assert_eq!(config.locale, "ne");
+        assert_eq!(db.guild_stats(guild_id)?.unwrap().weed_times, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn updates_guild_channels() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        let general = serenity::all::ChannelId::new(1);
+        let serious = serenity::all::ChannelId::new(2);
+        let category = serenity::all::ChannelId::new(3);
+        let thread = serenity::all::ChannelId::new(4);
+
+        // Every channel is watched until some are allowed.
+        let config = db.guild_config(guild_id)?;
+        assert!(config.channels.is_empty());
+        assert!(config.channels.allows(&[general]));
+
+        for update in [
+            GuildConfigUpdate {
+                allow_channel: Some(category),
+                ..GuildConfigUpdate::new(guild_id)
+            },
+            GuildConfigUpdate {
+                deny_channel: Some(serious),
+                ..GuildConfigUpdate::new(guild_id)
+            },
+        ] {
+            update.commit(&db)?;
+        }
+
+        let channels = db.guild_config(guild_id)?.channels;
+        assert_eq!(channels.allowed(), [category]);
+        assert_eq!(channels.denied(), [serious]);
+        assert!(channels.allows(&[general, category]));
+        assert!(channels.allows(&[thread, general, category]));
+        assert!(!channels.allows(&[serious, category]));
+        assert!(!channels.allows(&[thread, serious, category]));
+        assert!(!channels.allows(&[general]));
+
+        // Allowing a denied channel moves it over, and clearing it forgets it.
+        GuildConfigUpdate {
+            allow_channel: Some(serious),
+            ..GuildConfigUpdate::new(guild_id)
+        }
+        .commit(&db)?;
+        let channels = db.guild_config(guild_id)?.channels;
+        assert_eq!(channels.allowed(), [category, serious]);
+        assert!(channels.denied().is_empty());
+
+        for channel_id in [category, serious] {
+            GuildConfigUpdate {
+                clear_channel: Some(channel_id),
+                ..GuildConfigUpdate::new(guild_id)
+            }
+            .commit(&db)?;
+        }
+        assert!(db.guild_config(guild_id)?.channels.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn commits_guild_stats_updates() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+
+        GuildStatsUpdate {
+            guild_id: Some(guild_id),
+            weed_times: 1,
+            longest_chain: Some(3),
+            ..Default::default()
+        }
+        .commit(&db)?;
+
+        GuildStatsUpdate {
+            guild_id: Some(guild_id),
+            weed_crimes: 2,
+            longest_chain: Some(2),
+            ..Default::default()
+        }
+        .commit(&db)?;
+
+        let stats = db.guild_stats(guild_id)?.unwrap();
+        assert_eq!(stats.weed_times, 1);
+        assert_eq!(stats.weed_crimes, 2);
+        assert_eq!(stats.longest_chain, 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn logs_events_with_counter_updates() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+        let guild_id = serenity::all::GuildId::new(420);
+        let timestamp = Utc.with_ymd_and_hms(2025, 4, 20, 20, 20, 5).unwrap();
+
+        let mut weed_time = event(WeedEventKind::WeedTime, Some(420), 42, 1000, timestamp);
+        weed_time.chain_position = Some(1);
+        weed_time.set_reply_id(Some(serenity::all::MessageId::new(1001)));
+        MessageUpdate {
+            user: UserStatsUpdate {
+                weed_times: 1,
+                chains_started: 1,
+                ..UserStatsUpdate::new(user_id, Some(guild_id))
+            },
+            guild: GuildStatsUpdate {
+                weed_times: 1,
+                longest_chain: Some(1),
+                ..GuildStatsUpdate::new(guild_id)
+            },
+            event: Some(weed_time),
+            ..Default::default()
+        }
+        .commit(&db)?;
+
+        let events = db.guild_events(Some(guild_id), ..)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, WeedEventKind::WeedTime);
+        let message_id = serenity::all::MessageId::new(1000);
+        assert_eq!(db.event(message_id)?.unwrap().user_id(), user_id);
+        assert!(db.event(serenity::all::MessageId::new(1001))?.is_none());
+        assert_eq!(events[0].user_id(), user_id);
+        assert_eq!(events[0].message_id(), serenity::all::MessageId::new(1000));
+        assert_eq!(events[0].timestamp, timestamp);
+        assert_eq!(events[0].chain_position, Some(1));
+        assert_eq!(
+            events[0].reply_id(),
+            Some(serenity::all::MessageId::new(1001))
+        );
+        assert_eq!(
+            db.user_stats(Some(guild_id), user_id)?.unwrap().weed_times,
+            1
+        );
+        assert_eq!(db.guild_stats(guild_id)?.unwrap().weed_times, 1);
+
+        // Logging the same message twice fails without touching the user's counters.
+        let duplicate = MessageUpdate {
+            user: UserStatsUpdate {
+                weed_times: 1,
+                ..UserStatsUpdate::new(user_id, Some(guild_id))
+            },
+            event: Some(event(
+                WeedEventKind::WeedTime,
+                Some(420),
+                42,
+                1000,
+                timestamp,
+            )),
+            ..Default::default()
+        };
+        assert!(duplicate.commit(&db).is_err());
+        assert_eq!(
+            db.user_stats(Some(guild_id), user_id)?.unwrap().weed_times,
+            1
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn counts_each_message_once() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+        let guild_id = serenity::all::GuildId::new(420);
+        let timestamp = Utc.with_ymd_and_hms(2025, 4, 20, 20, 20, 5).unwrap();
+        // Discord ids carry the time they were made, here 2025-04-20 20:20:05 UTC.
+        let message_id = serenity::all::MessageId::new(
+            ((timestamp.timestamp_millis() - 1_420_070_400_000) as u64) << 22,
+        );
+
+        let update = MessageUpdate {
+            message_id: Some(message_id),
+            user: UserStatsUpdate {
+                weed_times: 1,
+                ..UserStatsUpdate::new(user_id, Some(guild_id))
+            },
+            guild: GuildStatsUpdate {
+                weed_times: 1,
+                ..GuildStatsUpdate::new(guild_id)
+            },
+            event: Some(event(
+                WeedEventKind::WeedTime,
+                Some(420),
+                42,
+                message_id.get(),
+                timestamp,
+            )),
+            ..Default::default()
+        };
+        assert!(!db.is_processed(message_id)?);
+        update.commit(&db)?;
+        assert!(db.is_processed(message_id)?);
+
+        // A replay of the same message is skipped rather than failing on its event.
+        update.commit(&db)?;
+        assert_eq!(
+            db.user_stats(Some(guild_id), user_id)?.unwrap().weed_times,
+            1
+        );
+        assert_eq!(db.user_totals(user_id)?.unwrap().weed_times, 1);
+        assert_eq!(db.guild_stats(guild_id)?.unwrap().weed_times, 1);
+        assert_eq!(db.guild_events(Some(guild_id), ..)?.len(), 1);
+
+        // So is an edit of a message that was counted as a weed time.
+        MessageUpdate {
+            edited: true,
+            ..update.clone()
+        }
+        .commit(&db)?;
+        assert_eq!(db.user_totals(user_id)?.unwrap().weed_times, 1);
+
+        // A message that only broke a chain is counted again once it is edited into a crime.
+        let broken = serenity::all::MessageId::new(message_id.get() + 1);
+        let broke_chain = MessageUpdate {
+            message_id: Some(broken),
+            event: Some(event(
+                WeedEventKind::BrokenChain,
+                Some(420),
+                42,
+                broken.get(),
+                timestamp,
+            )),
+            ..Default::default()
+        };
+        broke_chain.commit(&db)?;
+        let crime = MessageUpdate {
+            message_id: Some(broken),
+            user: UserStatsUpdate {
+                weed_crimes: 1,
+                ..UserStatsUpdate::new(user_id, Some(guild_id))
+            },
+            event: Some(event(
+                WeedEventKind::WeedCrime,
+                Some(420),
+                42,
+                broken.get(),
+                timestamp,
+            )),
+            ..Default::default()
+        };
+        crime.commit(&db)?;
+        assert_eq!(db.event(broken)?.unwrap().kind, WeedEventKind::BrokenChain);
+        let edited = MessageUpdate {
+            edited: true,
+            ..crime
+        };
+        edited.commit(&db)?;
+        edited.commit(&db)?;
+        assert_eq!(db.event(broken)?.unwrap().kind, WeedEventKind::WeedCrime);
+        assert_eq!(
+            db.user_stats(Some(guild_id), user_id)?.unwrap().weed_crimes,
+            1
+        );
+        assert_eq!(db.guild_events(Some(guild_id), ..)?.len(), 2);
+
+        assert_eq!(db.prune_processed_messages(timestamp)?, 0);
+        assert_eq!(
+            db.prune_processed_messages(timestamp + chrono::TimeDelta::seconds(1))?,
+            2
+        );
+        assert!(!db.is_processed(message_id)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reverts_deleted_messages() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let user_id = serenity::all::UserId::new(42);
+        let guild_id = serenity::all::GuildId::new(420);
+        let channel_id = serenity::all::ChannelId::new(1);
+        let timestamp = Utc.with_ymd_and_hms(2025, 4, 20, 20, 20, 5).unwrap();
+
+        let user = UserStatsUpdate {
+            weed_times: 1,
+            chains_started: 1,
+            longest_chain: Some(1),
+            ..UserStatsUpdate::new(user_id, Some(guild_id))
+        };
+        let guild = GuildStatsUpdate {
+            weed_times: 1,
+            longest_chain: Some(1),
+            ..GuildStatsUpdate::new(guild_id)
+        };
+        let chain = ChainState::new(
+            channel_id,
+            Some(guild_id),
+            serenity::all::MessageId::new(2000),
+            [user_id],
+            1,
+            timestamp - chrono::TimeDelta::seconds(5),
+            timestamp + chrono::TimeDelta::seconds(55),
+        );
+        MessageUpdate {
+            message_id: Some(serenity::all::MessageId::new(1000)),
+            user,
+            guild,
+            event: Some(event(
+                WeedEventKind::WeedTime,
+                Some(420),
+                42,
+                1000,
+                timestamp,
+            )),
+            chain: Some(ChainUpdate::Save(chain)),
+            ..Default::default()
+        }
+        .commit(&db)?;
+
+        MessageRevert {
+            message_id: Some(serenity::all::MessageId::new(1000)),
+            user,
+            guild,
+            chain: Some(ChainUpdate::Clear(channel_id)),
+        }
+        .commit(&db)?;
+
+        let stats = db.user_stats(Some(guild_id), user_id)?.unwrap();
+        assert_eq!(stats.weed_times, 0);
+        assert_eq!(stats.chains_started, 0);
+        // The longest chain can't be worked back out.
+        assert_eq!(stats.longest_chain, 1);
+        assert_eq!(db.user_totals(user_id)?.unwrap().weed_times, 0);
+        assert_eq!(db.guild_stats(guild_id)?.unwrap().weed_times, 0);
+        assert!(db.guild_events(Some(guild_id), ..)?.is_empty());
+        assert!(db.chain_states()?.is_empty());
+
+        // Taking back more than was counted stops at zero.
+        MessageRevert {
+            user,
+            guild,
+            ..Default::default()
+        }
+        .commit(&db)?;
+        assert_eq!(
+            db.user_stats(Some(guild_id), user_id)?.unwrap().weed_times,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn queries_events_by_guild_user_and_time() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let at = |hour, minute| Utc.with_ymd_and_hms(2025, 4, 20, hour, minute, 0).unwrap();
+
+        for event in [
+            event(WeedEventKind::WeedCrime, Some(420), 42, 1, at(12, 0)),
+            event(WeedEventKind::WeedTime, Some(420), 42, 2, at(16, 20)),
+            event(WeedEventKind::WeedTime, Some(420), 43, 3, at(16, 20)),
+            event(WeedEventKind::BrokenChain, Some(710), 42, 4, at(16, 21)),
+            event(WeedEventKind::WeedTime, None, 42, 5, at(16, 22)),
+        ] {
+            MessageUpdate {
+                event: Some(event),
+                ..Default::default()
+            }
+            .commit(&db)?;
+        }
+
+        let message_ids = |events: Vec<WeedEvent>| {
+            events
+                .iter()
+                .map(|event| event.message_id().get())
+                .collect::<Vec<_>>()
+        };
+
+        let guild_id = serenity::all::GuildId::new(420);
+        assert_eq!(message_ids(db.guild_events(Some(guild_id), ..)?), [1, 2, 3]);
+        assert_eq!(
+            message_ids(db.guild_events(Some(guild_id), at(16, 0)..)?),
+            [2, 3]
+        );
+        assert_eq!(message_ids(db.guild_events(None, ..)?), [5]);
+
+        let user_id = serenity::all::UserId::new(42);
+        assert_eq!(message_ids(db.user_events(user_id, ..)?), [1, 2, 4, 5]);
+        assert_eq!(
+            message_ids(db.user_events(user_id, at(16, 20)..at(16, 22))?),
+            [2, 4]
+        );
+
+        assert_eq!(message_ids(db.events(at(16, 20)..=at(16, 21))?), [2, 3, 4]);
+        assert!(db.events(at(16, 21)..at(16, 21))?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn saves_clears_and_prunes_chain_states() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let at = |hour, minute| Utc.with_ymd_and_hms(2025, 4, 20, hour, minute, 0).unwrap();
+        let channel_id = serenity::all::ChannelId::new(1);
+        let other_channel_id = serenity::all::ChannelId::new(2);
+        let users = [
+            serenity::all::UserId::new(42),
+            serenity::all::UserId::new(43),
+        ];
+
+        let chain = |channel_id, message_id, count, expires_at| {
+            let mut state = ChainState::new(
+                channel_id,
+                Some(serenity::all::GuildId::new(420)),
+                serenity::all::MessageId::new(message_id),
+                users.into_iter().take(count as usize),
+                count,
+                expires_at - chrono::TimeDelta::minutes(1),
+                expires_at,
+            );
+            state.server_record = Some(5);
+            MessageUpdate {
+                chain: Some(ChainUpdate::Save(state)),
+                ..Default::default()
+            }
+        };
+
+        chain(channel_id, 1000, 1, at(16, 21)).commit(&db)?;
+        chain(channel_id, 1001, 2, at(16, 21)).commit(&db)?;
+        chain(other_channel_id, 1002, 1, at(4, 21)).commit(&db)?;
+
+        let states = db.chain_states()?;
+        assert_eq!(states.len(), 2);
+        assert_eq!(states[0].channel_id(), channel_id);
+        assert_eq!(states[0].message_id(), serenity::all::MessageId::new(1001));
+        assert_eq!(states[0].users(), users);
+        assert_eq!(states[0].count, 2);
+        assert_eq!(states[0].server_record, Some(5));
+
+        assert_eq!(db.prune_chain_states(at(16, 20))?, 1);
+        let states = db.chain_states()?;
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].channel_id(), channel_id);
+
+        // The pruned chain ran until its window closed.
+        let history = db.recent_chains(Some(serenity::all::GuildId::new(420)), 10)?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].channel_id(), other_channel_id);
+        assert_eq!(history[0].message_id(), serenity::all::MessageId::new(1002));
+        assert_eq!(history[0].length, 1);
+        assert_eq!(history[0].broken_by(), None);
+        assert_eq!(history[0].window_start, at(4, 20));
+        assert_eq!(history[0].ended_at, at(4, 21));
+
+        MessageUpdate {
+            chain: Some(ChainUpdate::Clear(channel_id)),
+            ..Default::default()
+        }
+        .commit(&db)?;
+        assert!(db.chain_states()?.is_empty());
+
+        // Clearing a channel without a chain is fine.
+        MessageUpdate {
+            chain: Some(ChainUpdate::Clear(other_channel_id)),
+            ..Default::default()
+        }
+        .commit(&db)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_chain_history() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        let channel_id = serenity::all::ChannelId::new(1);
+        let at = |day, hour| Utc.with_ymd_and_hms(2025, 4, day, hour, 20, 0).unwrap();
+        let user = serenity::all::UserId::new;
+
+        let ended = |message_id, guild_id, day, length: u64, broken_by| MessageUpdate {
+            ended_chain: Some(Chain::new(
+                serenity::all::MessageId::new(message_id),
+                guild_id,
+                channel_id,
+                at(day, 20),
+                (1..=length).map(user),
+                broken_by,
+                at(day, 20) + chrono::TimeDelta::seconds(30),
+            )),
+            ..Default::default()
+        };
+        ended(1000, Some(guild_id), 18, 3, Some(user(1))).commit(&db)?;
+        ended(1001, Some(guild_id), 19, 5, None).commit(&db)?;
+        ended(1002, Some(guild_id), 20, 2, Some(user(9))).commit(&db)?;
+        ended(1003, Some(guild_id), 17, 5, None).commit(&db)?;
+        ended(1004, None, 21, 7, None).commit(&db)?;
+
+        let ids = |chains: Vec<Chain>| {
+            chains
+                .iter()
+                .map(|chain| chain.message_id().get())
+                .collect::<Vec<_>>()
+        };
+        let longest = db.longest_chains(Some(guild_id), 3)?;
+        assert_eq!(
+            longest.iter().map(|c| c.length).collect::<Vec<_>>(),
+            [5, 5, 3]
+        );
+        assert_eq!(
+            ids(db.recent_chains(Some(guild_id), 3)?),
+            [1002, 1001, 1000]
+        );
+        assert_eq!(ids(db.recent_chains(None, 10)?), [1004]);
+
+        let latest = &db.recent_chains(Some(guild_id), 1)?[0];
+        assert_eq!(latest.users(), [user(1), user(2)]);
+        assert_eq!(latest.broken_by(), Some(user(9)));
+        assert_eq!(latest.channel_id(), channel_id);
+        assert_eq!(latest.guild_id(), Some(guild_id));
+
+        let other_channel = serenity::all::ChannelId::new(2);
+        MessageUpdate {
+            ended_chain: Some(Chain::new(
+                serenity::all::MessageId::new(1005),
+                Some(guild_id),
+                other_channel,
+                at(22, 20),
+                (1..=4).map(user),
+                None,
+                at(22, 21),
+            )),
+            ..Default::default()
+        }
+        .commit(&db)?;
+        let longest_in = |channel_id| {
+            db.longest_channel_chain(Some(guild_id), channel_id)
+                .map(|chain| chain.map(|chain| chain.length))
+        };
+        assert_eq!(longest_in(channel_id)?, Some(5));
+        assert_eq!(longest_in(other_channel)?, Some(4));
+        assert_eq!(longest_in(serenity::all::ChannelId::new(3))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn imports_split_databases() -> Result<(), db_type::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let user_db_path = dir.path().join("user-stats.db");
+        let guild_db_path = dir.path().join("guild-stats.db");
+        let user_id = serenity::all::UserId::new(42);
+        let guild_id = serenity::all::GuildId::new(420);
+
+        {
+            let user_db = Builder::new().create(&crate::USER_MODELS, &user_db_path)?;
+            let rw = user_db.rw_transaction()?;
+            rw.insert(v1::UserStats {
+                id: UserId::from(user_id),
+                weed_times: 3,
+                weed_crimes: 1,
+                chains_started: 2,
+                chains_broken: 0,
+            })?;
+            rw.commit()?;
+
+            let guild_db = Builder::new().create(&crate::GUILD_MODELS, &guild_db_path)?;
+            let rw = guild_db.rw_transaction()?;
+            rw.insert(v1::GuildStats {
+                id: GuildId::from(guild_id),
+                timezone: chrono_tz::Tz::Europe__London,
+                weed_times: 3,
+                weed_crimes: 1,
+                longest_chain: 2,
+            })?;
+            rw.commit()?;
+        }
+
+        let db = WeedDatabase::create(dir.path().join("weedtime.db"))?;
+        db.import_split_databases(&user_db_path, &guild_db_path)?;
+
+        let stats = db.user_totals(user_id)?.unwrap();
+        assert_eq!(stats.weed_times, 3);
+        assert_eq!(stats.chains_started, 2);
+
+        let stats = db.guild_stats(guild_id)?.unwrap();
+        assert_eq!(stats.longest_chain, 2);
+        assert_eq!(
+            db.guild_config(guild_id)?.timezone,
+            chrono_tz::Tz::Europe__London
+        );
+
+        // Missing files are skipped.
+        let db = WeedDatabase::create(dir.path().join("empty.db"))?;
+        db.import_split_databases(dir.path().join("missing"), dir.path().join("missing"))?;
+        assert!(db.user_totals(user_id)?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,558 @@
+//! Ranking users by one of their counters, within a guild or across all of them.
+//!
+//! Each ranked counter has a `(guild, value)` secondary key on [`UserStats`] and a `value` key on
+//! [`UserTotals`], so a leaderboard only reads the index entries it ranks, and only loads the
+//! rows on the page being asked for.
+//!
+//! How many users have each value is kept in [`RankCount`]s, so finding a rank, or where a page
+//! starts, adds up one count per distinct value above it instead of going through every user
+//! above. Only users tied on the value a page starts at are skipped one by one.
+
+use native_db::{db_type, transaction::RwTransaction};
+
+use super::{
+    RankCount, UserStats, UserTotals, WeedDatabase,
+    v1::{GuildUserId, UserId, UserTotalsKey, guild_key},
+    v5::UserStatsKey,
+};
+
+/// A counter users can be ranked by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaderboardMetric {
+    WeedTimes,
+    WeedCrimes,
+    ChainsStarted,
+    ChainsBroken,
+    LongestChain,
+}
+
+impl LeaderboardMetric {
+    const ALL: [LeaderboardMetric; 5] = [
+        LeaderboardMetric::WeedTimes,
+        LeaderboardMetric::WeedCrimes,
+        LeaderboardMetric::ChainsStarted,
+        LeaderboardMetric::ChainsBroken,
+        LeaderboardMetric::LongestChain,
+    ];
+
+    pub fn value(self, stats: &UserStats) -> u32 {
+        match self {
+            LeaderboardMetric::WeedTimes => stats.weed_times,
+            LeaderboardMetric::WeedCrimes => stats.weed_crimes,
+            LeaderboardMetric::ChainsStarted => stats.chains_started,
+            LeaderboardMetric::ChainsBroken => stats.chains_broken,
+            LeaderboardMetric::LongestChain => stats.longest_chain,
+        }
+    }
+
+    fn totals_value(self, totals: &UserTotals) -> u32 {
+        match self {
+            LeaderboardMetric::WeedTimes => totals.weed_times,
+            LeaderboardMetric::WeedCrimes => totals.weed_crimes,
+            LeaderboardMetric::ChainsStarted => totals.chains_started,
+            LeaderboardMetric::ChainsBroken => totals.chains_broken,
+            LeaderboardMetric::LongestChain => totals.longest_chain,
+        }
+    }
+
+    fn key(self) -> UserStatsKey {
+        match self {
+            LeaderboardMetric::WeedTimes => UserStatsKey::weed_times_key,
+            LeaderboardMetric::WeedCrimes => UserStatsKey::weed_crimes_key,
+            LeaderboardMetric::ChainsStarted => UserStatsKey::chains_started_key,
+            LeaderboardMetric::ChainsBroken => UserStatsKey::chains_broken_key,
+            LeaderboardMetric::LongestChain => UserStatsKey::longest_chain_key,
+        }
+    }
+
+    /// What the metric is stored as in rank counts. Stored counts depend on it, so it can't change.
+    fn id(self) -> u8 {
+        match self {
+            LeaderboardMetric::WeedTimes => 0,
+            LeaderboardMetric::WeedCrimes => 1,
+            LeaderboardMetric::ChainsStarted => 2,
+            LeaderboardMetric::ChainsBroken => 3,
+            LeaderboardMetric::LongestChain => 4,
+        }
+    }
+
+    fn totals_key(self) -> UserTotalsKey {
+        match self {
+            LeaderboardMetric::WeedTimes => UserTotalsKey::weed_times_key,
+            LeaderboardMetric::WeedCrimes => UserTotalsKey::weed_crimes_key,
+            LeaderboardMetric::ChainsStarted => UserTotalsKey::chains_started_key,
+            LeaderboardMetric::ChainsBroken => UserTotalsKey::chains_broken_key,
+            LeaderboardMetric::LongestChain => UserTotalsKey::longest_chain_key,
+        }
+    }
+}
+
+/// A leaderboard users are counted on: a guild's (see [`guild_key`]), or the one across every
+/// guild.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Board {
+    Guild(u64),
+    Global,
+}
+
+impl Board {
+    /// The key of the count of users with `value` on the board. Counts sort by metric, then board,
+    /// then value, so the counts above a value are a range.
+    fn key(self, metric: LeaderboardMetric, value: u32) -> (u8, u8, u64, u32) {
+        match self {
+            Board::Guild(guild_key) => (metric.id(), 0, guild_key, value),
+            Board::Global => (metric.id(), 1, 0, value),
+        }
+    }
+}
+
+/// The value of every metric, in the order of [`LeaderboardMetric::ALL`], to tell which rank
+/// counts an update moves a user between.
+pub(crate) fn rank_values(stats: &UserStats) -> [u32; 5] {
+    LeaderboardMetric::ALL.map(|metric| metric.value(stats))
+}
+
+pub(crate) fn totals_rank_values(totals: &UserTotals) -> [u32; 5] {
+    LeaderboardMetric::ALL.map(|metric| metric.totals_value(totals))
+}
+
+/// Moves a user on `board` from the counts of the values they had `before` to the ones they have
+/// `after`. Values of 0 aren't ranked, so they aren't counted.
+pub(crate) fn recount(
+    rw: &RwTransaction,
+    board: Board,
+    before: [u32; 5],
+    after: [u32; 5],
+) -> Result<(), db_type::Error> {
+    for (metric, (before, after)) in LeaderboardMetric::ALL
+        .into_iter()
+        .zip(before.into_iter().zip(after))
+    {
+        if before == after {
+            continue;
+        }
+        if before > 0 {
+            add_to_count(rw, board.key(metric, before), -1)?;
+        }
+        if after > 0 {
+            add_to_count(rw, board.key(metric, after), 1)?;
+        }
+    }
+    Ok(())
+}
+
+fn add_to_count(
+    rw: &RwTransaction,
+    key: (u8, u8, u64, u32),
+    users: i32,
+) -> Result<(), db_type::Error> {
+    let count = rw.get().primary::<RankCount>(key)?;
+    let before = count.as_ref().map_or(0, |count| count.users);
+    match before.saturating_add_signed(users) {
+        // Values nobody has any more are dropped, so they don't have to be added up.
+        0 => {
+            if let Some(count) = count {
+                rw.remove(count)?;
+            }
+        }
+        users => {
+            rw.upsert(RankCount { key, users })?;
+        }
+    }
+    Ok(())
+}
+
+/// Counts every user again from their stats and totals, for databases that have stats from before
+/// counts were kept, or whose stats were written without them.
+pub(crate) fn rebuild_rank_counts(rw: &RwTransaction) -> Result<(), db_type::Error> {
+    let counts = rw
+        .scan()
+        .primary::<RankCount>()?
+        .all()?
+        .collect::<Result<Vec<_>, _>>()?;
+    for count in counts {
+        rw.remove(count)?;
+    }
+
+    let stats = rw
+        .scan()
+        .primary::<UserStats>()?
+        .all()?
+        .collect::<Result<Vec<_>, _>>()?;
+    for stats in &stats {
+        let board = Board::Guild(guild_key(stats.guild_id()));
+        recount(rw, board, [0; 5], rank_values(stats))?;
+    }
+
+    let totals = rw
+        .scan()
+        .primary::<UserTotals>()?
+        .all()?
+        .collect::<Result<Vec<_>, _>>()?;
+    for totals in &totals {
+        recount(rw, Board::Global, [0; 5], totals_rank_values(totals))?;
+    }
+
+    Ok(())
+}
+
+/// Where a page of a leaderboard starts: the value of its first row, how many users with that
+/// value come before it, and how many users have a higher one.
+struct PageStart {
+    value: u32,
+    ties_before: usize,
+    above: u32,
+}
+
+/// A user's place on a leaderboard. Users with the same value share a rank, and the rank after
+/// them skips the places they took up.
+#[derive(Debug)]
+pub struct LeaderboardEntry {
+    pub rank: u32,
+    pub stats: UserStats,
+}
+
+impl<'a> WeedDatabase<'a> {
+    /// Up to `limit` users with the highest `metric` in a guild (or outside of any guild when
+    /// `guild_id` is `None`), skipping the first `offset`. Users with a value of 0 aren't ranked.
+    pub fn top_n(
+        &self,
+        metric: LeaderboardMetric,
+        guild_id: Option<serenity::all::GuildId>,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<LeaderboardEntry>, db_type::Error> {
+        let guild_key = guild_key(guild_id);
+        let Some(start) = self.page_start(metric, Board::Guild(guild_key), offset)? else {
+            return Ok(Vec::new());
+        };
+        let r = self.0.r_transaction()?;
+
+        let rows = r
+            .scan()
+            .secondary::<UserStats>(metric.key())?
+            .range((guild_key, 1)..=(guild_key, start.value))?
+            .rev()
+            .skip(start.ties_before)
+            .take(limit)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ranked(metric, rows, offset, start.above))
+    }
+
+    /// Like [`WeedDatabase::top_n`], but ranking users by their totals across every guild.
+    pub fn top_n_global(
+        &self,
+        metric: LeaderboardMetric,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<LeaderboardEntry>, db_type::Error> {
+        let Some(start) = self.page_start(metric, Board::Global, offset)? else {
+            return Ok(Vec::new());
+        };
+        let r = self.0.r_transaction()?;
+
+        let rows = r
+            .scan()
+            .secondary::<UserTotals>(metric.totals_key())?
+            .range(1..=start.value)?
+            .rev()
+            .skip(start.ties_before)
+            .take(limit)
+            .map(|totals| totals.map(UserStats::from))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ranked(metric, rows, offset, start.above))
+    }
+
+    /// Where a user stands on a guild's leaderboard, or `None` if they have no stats there or
+    /// their value is 0.
+    pub fn rank(
+        &self,
+        metric: LeaderboardMetric,
+        guild_id: Option<serenity::all::GuildId>,
+        user_id: serenity::all::UserId,
+    ) -> Result<Option<LeaderboardEntry>, db_type::Error> {
+        let stats = {
+            let r = self.0.r_transaction()?;
+            r.get()
+                .primary::<UserStats>(GuildUserId::new(guild_id, user_id))?
+        };
+        let Some(stats) = stats.filter(|stats| metric.value(stats) > 0) else {
+            return Ok(None);
+        };
+
+        let board = Board::Guild(guild_key(guild_id));
+        let rank = self.count_above(metric, board, metric.value(&stats))? + 1;
+        Ok(Some(LeaderboardEntry { rank, stats }))
+    }
+
+    /// Where a user stands on the leaderboard across every guild, or `None` if they have no
+    /// stats or their total is 0.
+    pub fn global_rank(
+        &self,
+        metric: LeaderboardMetric,
+        user_id: serenity::all::UserId,
+    ) -> Result<Option<LeaderboardEntry>, db_type::Error> {
+        let stats = {
+            let r = self.0.r_transaction()?;
+            r.get()
+                .primary::<UserTotals>(UserId::from(user_id))?
+                .map(UserStats::from)
+        };
+        let Some(stats) = stats.filter(|stats| metric.value(stats) > 0) else {
+            return Ok(None);
+        };
+
+        let rank = self.count_above(metric, Board::Global, metric.value(&stats))? + 1;
+        Ok(Some(LeaderboardEntry { rank, stats }))
+    }
+
+    /// The number of users on `board` with a higher `metric` than `value`.
+    fn count_above(
+        &self,
+        metric: LeaderboardMetric,
+        board: Board,
+        value: u32,
+    ) -> Result<u32, db_type::Error> {
+        let Some(above) = value.checked_add(1) else {
+            return Ok(0);
+        };
+
+        let r = self.0.r_transaction()?;
+        let mut users = 0;
+        for count in r
+            .scan()
+            .primary::<RankCount>()?
+            .range(board.key(metric, above)..=board.key(metric, u32::MAX))?
+        {
+            users += count?.users;
+        }
+        Ok(users)
+    }
+
+    /// Finds row `offset` of `board` from the counts, highest value first, or `None` if the board
+    /// has no more rows than that.
+    fn page_start(
+        &self,
+        metric: LeaderboardMetric,
+        board: Board,
+        offset: usize,
+    ) -> Result<Option<PageStart>, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        let mut above = 0;
+        for count in r
+            .scan()
+            .primary::<RankCount>()?
+            .range(board.key(metric, 1)..=board.key(metric, u32::MAX))?
+            .rev()
+        {
+            let count = count?;
+            let ties_before = offset - above as usize;
+            if ties_before < count.users as usize {
+                return Ok(Some(PageStart {
+                    value: count.key.3,
+                    ties_before,
+                    above,
+                }));
+            }
+            above += count.users;
+        }
+        Ok(None)
+    }
+}
+
+/// Ranks a page of rows that starts `offset` rows into a leaderboard, highest first, with `above`
+/// users ranked ahead of its first row's value. The page may start partway through a tie.
+fn ranked(
+    metric: LeaderboardMetric,
+    rows: Vec<UserStats>,
+    offset: usize,
+    above: u32,
+) -> Vec<LeaderboardEntry> {
+    let mut rank = above + 1;
+    let mut previous_value = rows.first().map(|first| metric.value(first));
+
+    let mut entries = Vec::with_capacity(rows.len());
+    for (index, stats) in rows.into_iter().enumerate() {
+        let value = metric.value(&stats);
+        if Some(value) != previous_value {
+            rank = (offset + index + 1) as u32;
+            previous_value = Some(value);
+        }
+        entries.push(LeaderboardEntry { rank, stats });
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use native_db::db_type;
+
+    use super::*;
+    use crate::data::{DbUpdate, MessageRevert, UserStatsUpdate};
+
+    fn ranks(entries: &[LeaderboardEntry]) -> Vec<(u64, u32)> {
+        entries
+            .iter()
+            .map(|entry| (entry.stats.id().get(), entry.rank))
+            .collect()
+    }
+
+    #[test]
+    fn ranks_users_within_a_guild() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        let other_guild_id = serenity::all::GuildId::new(710);
+
+        for (guild_id, user_id, weed_times, weed_crimes) in [
+            (Some(guild_id), 1, 5, 0),
+            (Some(guild_id), 2, 9, 1),
+            (Some(guild_id), 3, 5, 0),
+            (Some(guild_id), 4, 2, 3),
+            (Some(guild_id), 5, 0, 2),
+            (Some(other_guild_id), 6, 100, 0),
+            (None, 7, 50, 0),
+        ] {
+            UserStatsUpdate {
+                weed_times,
+                weed_crimes,
+                ..UserStatsUpdate::new(serenity::all::UserId::new(user_id), guild_id)
+            }
+            .commit(&db)?;
+        }
+
+        let metric = LeaderboardMetric::WeedTimes;
+        let top = db.top_n(metric, Some(guild_id), 10, 0)?;
+        assert_eq!(ranks(&top), [(2, 1), (3, 2), (1, 2), (4, 4)]);
+
+        // A page starting in the middle of a tie keeps the tie's rank.
+        let page = db.top_n(metric, Some(guild_id), 2, 2)?;
+        assert_eq!(ranks(&page), [(1, 2), (4, 4)]);
+        assert!(db.top_n(metric, Some(guild_id), 10, 4)?.is_empty());
+
+        assert_eq!(ranks(&db.top_n(metric, None, 10, 0)?), [(7, 1)]);
+
+        let crimes = db.top_n(LeaderboardMetric::WeedCrimes, Some(guild_id), 10, 0)?;
+        assert_eq!(ranks(&crimes), [(4, 1), (5, 2), (2, 3)]);
+
+        let rank = |user_id| db.rank(metric, Some(guild_id), serenity::all::UserId::new(user_id));
+        assert_eq!(rank(2)?.unwrap().rank, 1);
+        assert_eq!(rank(1)?.unwrap().rank, 2);
+        assert_eq!(rank(4)?.unwrap().rank, 4);
+        assert!(rank(5)?.is_none());
+        assert!(rank(6)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn ranks_users_across_guilds() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        let other_guild_id = serenity::all::GuildId::new(710);
+
+        for (guild_id, user_id, chains_broken) in [
+            (Some(guild_id), 1, 3),
+            (Some(other_guild_id), 1, 2),
+            (Some(guild_id), 2, 4),
+            (None, 3, 6),
+            (Some(other_guild_id), 4, 0),
+        ] {
+            UserStatsUpdate {
+                chains_broken,
+                ..UserStatsUpdate::new(serenity::all::UserId::new(user_id), guild_id)
+            }
+            .commit(&db)?;
+        }
+
+        let metric = LeaderboardMetric::ChainsBroken;
+        let top = db.top_n_global(metric, 10, 0)?;
+        assert_eq!(ranks(&top), [(3, 1), (1, 2), (2, 3)]);
+        assert_eq!(top[1].stats.chains_broken, 5);
+        assert_eq!(top[1].stats.guild_id(), None);
+        assert_eq!(ranks(&db.top_n_global(metric, 1, 1)?), [(1, 2)]);
+
+        let rank = |user_id| db.global_rank(metric, serenity::all::UserId::new(user_id));
+        assert_eq!(rank(1)?.unwrap().rank, 2);
+        assert_eq!(rank(2)?.unwrap().rank, 3);
+        assert!(rank(4)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn reranks_users_after_updates() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        let update = |user_id, longest_chain| UserStatsUpdate {
+            longest_chain: Some(longest_chain),
+            ..UserStatsUpdate::new(serenity::all::UserId::new(user_id), Some(guild_id))
+        };
+
+        update(1, 3).commit(&db)?;
+        update(2, 2).commit(&db)?;
+        update(2, 4).commit(&db)?;
+        // A shorter chain doesn't lower the longest one.
+        update(1, 1).commit(&db)?;
+
+        let metric = LeaderboardMetric::LongestChain;
+        assert_eq!(
+            ranks(&db.top_n(metric, Some(guild_id), 10, 0)?),
+            [(2, 1), (1, 2)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn reranks_users_after_reverts() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+        let weed_times = |user_id, weed_times| UserStatsUpdate {
+            weed_times,
+            ..UserStatsUpdate::new(serenity::all::UserId::new(user_id), Some(guild_id))
+        };
+
+        weed_times(1, 3).commit(&db)?;
+        weed_times(2, 2).commit(&db)?;
+        weed_times(3, 2).commit(&db)?;
+        MessageRevert {
+            user: weed_times(1, 2),
+            ..Default::default()
+        }
+        .commit(&db)?;
+
+        let metric = LeaderboardMetric::WeedTimes;
+        let top = db.top_n(metric, Some(guild_id), 10, 0)?;
+        assert_eq!(ranks(&top), [(3, 1), (2, 1), (1, 3)]);
+        assert_eq!(
+            ranks(&db.top_n(metric, Some(guild_id), 10, 1)?),
+            [(2, 1), (1, 3)]
+        );
+        assert_eq!(ranks(&db.top_n_global(metric, 10, 2)?), [(1, 3)]);
+        let rank = |user_id| db.rank(metric, Some(guild_id), serenity::all::UserId::new(user_id));
+        assert_eq!(rank(1)?.unwrap().rank, 3);
+
+        // Nobody has 3 any more, so it isn't counted.
+        let r = db.0.r_transaction()?;
+        let values = r
+            .scan()
+            .primary::<RankCount>()?
+            .all()?
+            .map(|count| count.map(|count| (count.key, count.users)))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            values,
+            [
+                ((0, 0, 420, 1), 1),
+                ((0, 0, 420, 2), 2),
+                ((0, 1, 0, 1), 1),
+                ((0, 1, 0, 2), 2)
+            ]
+        );
+
+        Ok(())
+    }
+}
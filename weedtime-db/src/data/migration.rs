@@ -0,0 +1,351 @@
+//! Upgrading databases written by older versions of the bot.
+//!
+//! Models are versioned with `native_model`. When a model changes shape, its old definition
+//! stays in the `vN` module it was written in and the new one goes in the module for its new
+//! version, with `From` conversions both ways (see [`v5::UserStats`](super::v5::UserStats)). Both versions are
+//! registered in `crate::MODELS`, the alias in [`data`](super) is pointed at the new one, and the
+//! model is added to [`WeedDatabase::needs_migration`] and [`migrate_models`] below.
+
+use std::{
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use native_db::{db_type, transaction::RwTransaction};
+
+use super::{
+    ChainState, GuildConfig, GuildStats, RankCount, UserStats, UserStatsUpdate, UserTotals,
+    WeedDatabase, WeedEvent,
+    leaderboard::{rebuild_rank_counts, totals_rank_values},
+    v1, v2, v3, v4, v5, v6, v7,
+};
+
+impl<'a> WeedDatabase<'a> {
+    /// Whether any model still has rows stored under an older version.
+    pub fn needs_migration(&self) -> Result<bool, db_type::Error> {
+        let r = self.0.r_transaction()?;
+        let old_user_stats = r.len().primary::<v1::UserStats>()?
+            + r.len().primary::<v2::UserStats>()?
+            + r.len().primary::<v3::UserStats>()?
+            + r.len().primary::<v4::UserStats>()?;
+        let old_guild_stats =
+            r.len().primary::<v1::GuildStats>()? + r.len().primary::<v2::GuildStats>()?;
+        let old_guild_configs = r.len().primary::<v1::GuildConfig>()?
+            + r.len().primary::<v2::GuildConfig>()?
+            + r.len().primary::<v3::GuildConfig>()?
+            + r.len().primary::<v4::GuildConfig>()?
+            + r.len().primary::<v5::GuildConfig>()?
+            + r.len().primary::<v6::GuildConfig>()?
+            + r.len().primary::<v7::GuildConfig>()?;
+        let old_chain_states =
+            r.len().primary::<v1::ChainState>()? + r.len().primary::<v2::ChainState>()?;
+        let old_events = r.len().primary::<v1::WeedEvent>()?;
+        let missing_totals =
+            r.len().primary::<UserTotals>()? == 0 && r.len().primary::<UserStats>()? > 0;
+        let missing_rank_counts = r.len().primary::<RankCount>()? == 0
+            && r.scan()
+                .primary::<UserTotals>()?
+                .all()?
+                .any(|totals| totals.is_ok_and(|totals| totals_rank_values(&totals) != [0; 5]));
+
+        Ok(old_user_stats > 0
+            || old_guild_stats > 0
+            || old_guild_configs > 0
+            || old_chain_states > 0
+            || old_events > 0
+            || missing_totals
+            || missing_rank_counts)
+    }
+
+    /// Upgrades every model to its latest version, in one transaction.
+    pub fn migrate(&self) -> Result<(), db_type::Error> {
+        let rw = self.0.rw_transaction()?;
+        migrate_models(&rw)?;
+        rw.commit()
+    }
+}
+
+pub(crate) fn migrate_models(rw: &RwTransaction) -> Result<(), db_type::Error> {
+    // Timezones used to be kept with the stats, which no longer have them once migrated.
+    let mut timezones = Vec::new();
+    for stats in rw.scan().primary::<v1::GuildStats>()?.all()? {
+        let stats = stats?;
+        timezones.push((stats.id, stats.timezone));
+    }
+    for stats in rw.scan().primary::<v2::GuildStats>()?.all()? {
+        let stats = stats?;
+        timezones.push((stats.id, stats.timezone));
+    }
+
+    rw.migrate::<UserStats>()?;
+    rw.migrate::<GuildStats>()?;
+    rw.migrate::<GuildConfig>()?;
+    rw.migrate::<ChainState>()?;
+    rw.migrate::<WeedEvent>()?;
+
+    for (guild_id, timezone) in timezones {
+        let mut config = rw
+            .get()
+            .primary::<GuildConfig>(guild_id)?
+            .unwrap_or_else(|| GuildConfig::new(guild_id.get()));
+        config.timezone = timezone;
+        rw.upsert(config)?;
+    }
+
+    // Totals started being kept after there were already stats to add up.
+    if rw.len().primary::<UserTotals>()? == 0 {
+        let stats = rw
+            .scan()
+            .primary::<UserStats>()?
+            .all()?
+            .collect::<Result<Vec<_>, _>>()?;
+        for stats in &stats {
+            UserStatsUpdate::from(stats).apply_totals(rw)?;
+        }
+    }
+
+    // Migrated and imported stats are written without their rank counts, which started being kept
+    // after there were already stats to count.
+    rebuild_rank_counts(rw)?;
+
+    Ok(())
+}
+
+/// Copies the database file at `path` to a timestamped file next to it, so it can be restored
+/// if upgrading it goes wrong. The database should be closed while it is copied.
+pub fn backup(path: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let path = path.as_ref();
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    let mut backup_path = OsString::from(path.as_os_str());
+    backup_path.push(format!(".{timestamp}.bak"));
+    let backup_path = PathBuf::from(backup_path);
+
+    fs::copy(path, &backup_path)?;
+    Ok(backup_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use native_db::{Builder, Models};
+
+    use super::*;
+    use crate::data::{
+        LeaderboardMetric,
+        v1::{GuildId, UserId},
+        v8,
+    };
+
+    /// Writes a database the way the bot did before user stats were kept per guild.
+    fn create_v1_fixture(path: &Path) -> Result<(), db_type::Error> {
+        let mut models = Models::new();
+        models.define::<v1::UserStats>()?;
+        models.define::<v1::GuildStats>()?;
+        models.define::<v1::WeedEvent>()?;
+        let db = Builder::new().create(&models, path)?;
+
+        let rw = db.rw_transaction()?;
+        rw.insert(v1::UserStats {
+            id: UserId::from(serenity::all::UserId::new(42)),
+            weed_times: 7,
+            weed_crimes: 2,
+            chains_started: 3,
+            chains_broken: 1,
+        })?;
+        rw.insert(v1::GuildStats {
+            id: GuildId::from(serenity::all::GuildId::new(420)),
+            timezone: chrono_tz::Tz::Asia__Kathmandu,
+            weed_times: 7,
+            weed_crimes: 2,
+            longest_chain: 4,
+        })?;
+        let mut event = v1::WeedEvent::new(
+            v1::WeedEventKind::WeedTime,
+            Some(serenity::all::GuildId::new(420)),
+            serenity::all::ChannelId::new(1),
+            serenity::all::UserId::new(42),
+            serenity::all::MessageId::new(1000),
+            chrono::DateTime::UNIX_EPOCH,
+        );
+        event.chain_position = Some(2);
+        rw.insert(event)?;
+        rw.commit()
+    }
+
+    #[test]
+    fn migrates_v1_database_to_latest() -> Result<(), db_type::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("weedtime.db");
+        create_v1_fixture(&path)?;
+
+        let db = WeedDatabase::open(&path)?;
+        assert!(db.needs_migration()?);
+        db.migrate()?;
+        assert!(!db.needs_migration()?);
+        drop(db);
+
+        let db = WeedDatabase::open(&path)?;
+        assert!(!db.needs_migration()?);
+
+        let user_id = serenity::all::UserId::new(42);
+        let stats = db.user_stats(None, user_id)?.unwrap();
+        assert_eq!(stats.id(), user_id);
+        assert_eq!(stats.guild_id(), None);
+        assert_eq!(stats.weed_times, 7);
+        assert_eq!(stats.weed_crimes, 2);
+        assert_eq!(stats.chains_started, 3);
+        assert_eq!(stats.chains_broken, 1);
+        assert_eq!(db.user_totals(user_id)?.unwrap().weed_times, 7);
+
+        // The leaderboard indexes are built for the migrated rows.
+        let top = db.top_n(LeaderboardMetric::WeedTimes, None, 10, 0)?;
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].stats.id(), user_id);
+
+        let guild_id = serenity::all::GuildId::new(420);
+        let stats = db.guild_stats(guild_id)?.unwrap();
+        assert_eq!(stats.longest_chain, 4);
+        // The timezone moved to the guild's config.
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.timezone, chrono_tz::Tz::Asia__Kathmandu);
+        assert_eq!(config.triggers, v1::Trigger::defaults());
+
+        let event = db.event(serenity::all::MessageId::new(1000))?.unwrap();
+        assert_eq!(event.chain_position, Some(2));
+        assert_eq!(event.reply_id(), None);
+        assert_eq!(db.guild_events(Some(guild_id), ..)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_guild_config_to_latest() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+
+        let rw = db.0.rw_transaction()?;
+        rw.insert(v1::GuildConfig {
+            id: GuildId::from(guild_id),
+            triggers: vec![v1::Trigger::phrase("blaze it")],
+        })?;
+        rw.commit()?;
+
+        assert!(db.needs_migration()?);
+        db.migrate()?;
+        assert!(!db.needs_migration()?);
+
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.triggers, [v1::Trigger::phrase("blaze it")]);
+        assert_eq!(config.match_mode, v2::MatchMode::Strict);
+        assert_eq!(config.locale, v8::DEFAULT_LOCALE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn moves_guild_timezone_to_config() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let guild_id = serenity::all::GuildId::new(420);
+
+        let rw = db.0.rw_transaction()?;
+        rw.insert(v2::GuildStats {
+            id: GuildId::from(guild_id),
+            timezone: chrono_tz::Tz::Europe__Berlin,
+            weed_times: 5,
+            weed_crimes: 0,
+            longest_chain: 3,
+            current_streak: 2,
+            best_streak: 2,
+            last_weed_day: None,
+        })?;
+        rw.insert(v5::GuildConfig {
+            match_mode: v2::MatchMode::Lenient,
+            ..v5::GuildConfig::new(guild_id)
+        })?;
+        rw.commit()?;
+
+        assert!(db.needs_migration()?);
+        db.migrate()?;
+        assert!(!db.needs_migration()?);
+
+        let stats = db.guild_stats(guild_id)?.unwrap();
+        assert_eq!(stats.weed_times, 5);
+        assert_eq!(stats.best_streak, 2);
+        let config = db.guild_config(guild_id)?;
+        assert_eq!(config.timezone, chrono_tz::Tz::Europe__Berlin);
+        assert_eq!(config.match_mode, v2::MatchMode::Lenient);
+
+        Ok(())
+    }
+
+    #[test]
+    fn migrates_running_chains() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        let expires_at = chrono::Utc::now() + chrono::TimeDelta::seconds(30);
+
+        let rw = db.0.rw_transaction()?;
+        rw.insert(v1::ChainState::new(
+            serenity::all::ChannelId::new(1),
+            Some(serenity::all::GuildId::new(420)),
+            serenity::all::MessageId::new(1000),
+            [serenity::all::UserId::new(42)],
+            1,
+            expires_at,
+        ))?;
+        rw.commit()?;
+
+        assert!(db.needs_migration()?);
+        db.migrate()?;
+        assert!(!db.needs_migration()?);
+
+        let states = db.chain_states()?;
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].users(), [serenity::all::UserId::new(42)]);
+        assert_eq!(states[0].expires_at, expires_at);
+        assert_eq!(
+            states[0].window_start,
+            expires_at - chrono::TimeDelta::minutes(1)
+        );
+        assert_eq!(states[0].server_record, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn new_database_needs_no_migration() -> Result<(), db_type::Error> {
+        let db = WeedDatabase::create_in_memory()?;
+        assert!(!db.needs_migration()?);
+        db.migrate()?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn backs_up_database_file() -> Result<(), db_type::Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("weedtime.db");
+        create_v1_fixture(&path)?;
+
+        let backup_path = backup(&path).unwrap();
+        assert_ne!(backup_path, path);
+        assert!(
+            backup_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("weedtime.db.")
+        );
+
+        WeedDatabase::open(&path)?.migrate()?;
+
+        // The backup still holds the data as it was before the migration.
+        let backup = WeedDatabase::open(&backup_path)?;
+        assert!(backup.needs_migration()?);
+
+        Ok(())
+    }
+}
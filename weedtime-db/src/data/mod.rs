@@ -0,0 +1,34 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use native_db::{Key, ToKey, native_db};
+use native_model::{Model, native_model};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub use database::*;
+pub use leaderboard::*;
+pub use migration::backup;
+pub use window::WindowMatch;
+
+pub type UserStats = v5::UserStats;
+pub type GuildStats = v3::GuildStats;
+pub type WeedEvent = v2::WeedEvent;
+pub type ChainState = v3::ChainState;
+pub type UserTotals = v1::UserTotals;
+pub type GuildConfig = v8::GuildConfig;
+pub type UserConfig = v1::UserConfig;
+pub type ProcessedMessage = v1::ProcessedMessage;
+pub type Chain = v1::Chain;
+pub type RankCount = v1::RankCount;
+
+mod database;
+mod leaderboard;
+mod migration;
+mod streak;
+pub mod v1;
+pub mod v2;
+pub mod v3;
+pub mod v4;
+pub mod v5;
+pub mod v6;
+pub mod v7;
+pub mod v8;
+mod window;
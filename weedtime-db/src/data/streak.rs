@@ -0,0 +1,49 @@
+//! Counting days in a row something happened on.
+
+use chrono::NaiveDate;
+
+/// A streak of `current` days ending on `last_day`, after it also happened on `day`. Returns
+/// `None` if that doesn't change the streak: `day` was already counted, or came before it.
+pub(crate) fn extend(current: u32, last_day: Option<NaiveDate>, day: NaiveDate) -> Option<u32> {
+    match last_day {
+        Some(last_day) if day <= last_day => None,
+        Some(last_day) if last_day.succ_opt() == Some(day) => Some(current.saturating_add(1)),
+        _ => Some(1),
+    }
+}
+
+/// A streak of `current` days ending on `last_day`, as it stands on `today`. It is still alive
+/// until a whole day has been missed.
+pub(crate) fn current_on(current: u32, last_day: Option<NaiveDate>, today: NaiveDate) -> u32 {
+    match last_day {
+        Some(last_day) if last_day == today || last_day.succ_opt() == Some(today) => current,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2025, 4, day).unwrap()
+    }
+
+    #[test]
+    fn extends_streaks() {
+        assert_eq!(extend(0, None, day(20)), Some(1));
+        assert_eq!(extend(1, Some(day(19)), day(20)), Some(2));
+        // The AM and PM 4:20 on the same day only count once.
+        assert_eq!(extend(2, Some(day(20)), day(20)), None);
+        assert_eq!(extend(5, Some(day(17)), day(20)), Some(1));
+        assert_eq!(extend(5, Some(day(21)), day(20)), None);
+    }
+
+    #[test]
+    fn streaks_run_out_after_a_missed_day() {
+        assert_eq!(current_on(3, Some(day(20)), day(20)), 3);
+        assert_eq!(current_on(3, Some(day(20)), day(21)), 3);
+        assert_eq!(current_on(3, Some(day(20)), day(22)), 0);
+        assert_eq!(current_on(0, None, day(20)), 0);
+    }
+}
@@ -0,0 +1,654 @@
+use super::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UserId(serenity::all::UserId);
+
+impl Serialize for UserId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u64(self.0.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for UserId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Self(serenity::all::UserId::new(u64::deserialize(
+            deserializer,
+        )?)))
+    }
+}
+
+impl UserId {
+    pub fn get(&self) -> serenity::all::UserId {
+        self.0
+    }
+}
+
+impl ToKey for UserId {
+    fn to_key(&self) -> Key {
+        self.0.get().to_key()
+    }
+
+    fn key_names() -> Vec<String> {
+        vec!["UserId".to_string()]
+    }
+}
+
+impl From<serenity::all::UserId> for UserId {
+    fn from(value: serenity::all::UserId) -> Self {
+        UserId(value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 1, version = 1)]
+#[native_db]
+pub struct UserStats {
+    #[primary_key]
+    pub(crate) id: UserId,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub chains_started: u32,
+    pub chains_broken: u32,
+}
+
+impl UserStats {
+    pub fn id(&self) -> serenity::all::UserId {
+        self.id.get()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GuildId(serenity::all::GuildId);
+
+impl Serialize for GuildId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u64(self.0.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for GuildId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Self(serenity::all::GuildId::new(u64::deserialize(
+            deserializer,
+        )?)))
+    }
+}
+
+impl GuildId {
+    pub fn get(&self) -> serenity::all::GuildId {
+        self.0
+    }
+}
+
+impl ToKey for GuildId {
+    fn to_key(&self) -> Key {
+        self.0.get().to_key()
+    }
+
+    fn key_names() -> Vec<String> {
+        vec!["GuildId".to_string()]
+    }
+}
+
+impl From<serenity::all::GuildId> for GuildId {
+    fn from(value: serenity::all::GuildId) -> Self {
+        GuildId(value)
+    }
+}
+
+/// Key for records that belong to a user within a guild. Records without a guild (direct
+/// messages, or stats from before they were tracked per guild) use `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct GuildUserId {
+    guild_id: Option<GuildId>,
+    user_id: UserId,
+}
+
+impl GuildUserId {
+    pub fn new(guild_id: Option<serenity::all::GuildId>, user_id: serenity::all::UserId) -> Self {
+        Self {
+            guild_id: guild_id.map(GuildId::from),
+            user_id: UserId::from(user_id),
+        }
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.guild_id.map(|guild_id| guild_id.get())
+    }
+
+    pub fn user_id(&self) -> serenity::all::UserId {
+        self.user_id.get()
+    }
+
+    /// The guild id as it appears in keys. Discord ids are never 0, so it is free to stand in
+    /// for "no guild".
+    pub(crate) fn guild_key(&self) -> u64 {
+        guild_key(self.guild_id())
+    }
+}
+
+pub(crate) fn guild_key(guild_id: Option<serenity::all::GuildId>) -> u64 {
+    guild_id.map_or(0, |guild_id| guild_id.get())
+}
+
+impl ToKey for GuildUserId {
+    fn to_key(&self) -> Key {
+        (self.guild_key(), self.user_id.get().get()).to_key()
+    }
+
+    fn key_names() -> Vec<String> {
+        vec!["GuildUserId".to_string()]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 2, version = 1)]
+#[native_db]
+pub struct GuildStats {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub timezone: chrono_tz::Tz,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub longest_chain: u32,
+}
+
+impl GuildStats {
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelId(serenity::all::ChannelId);
+
+impl Serialize for ChannelId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u64(self.0.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for ChannelId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Self(serenity::all::ChannelId::new(u64::deserialize(
+            deserializer,
+        )?)))
+    }
+}
+
+impl ChannelId {
+    pub fn get(&self) -> serenity::all::ChannelId {
+        self.0
+    }
+}
+
+impl ToKey for ChannelId {
+    fn to_key(&self) -> Key {
+        self.0.get().to_key()
+    }
+
+    fn key_names() -> Vec<String> {
+        vec!["ChannelId".to_string()]
+    }
+}
+
+impl From<serenity::all::ChannelId> for ChannelId {
+    fn from(value: serenity::all::ChannelId) -> Self {
+        ChannelId(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MessageId(serenity::all::MessageId);
+
+impl Serialize for MessageId {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_u64(self.0.get())
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Self(serenity::all::MessageId::new(u64::deserialize(
+            deserializer,
+        )?)))
+    }
+}
+
+impl MessageId {
+    pub fn get(&self) -> serenity::all::MessageId {
+        self.0
+    }
+}
+
+impl ToKey for MessageId {
+    fn to_key(&self) -> Key {
+        self.0.get().to_key()
+    }
+
+    fn key_names() -> Vec<String> {
+        vec!["MessageId".to_string()]
+    }
+}
+
+impl From<serenity::all::MessageId> for MessageId {
+    fn from(value: serenity::all::MessageId) -> Self {
+        MessageId(value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeedEventKind {
+    WeedTime,
+    WeedCrime,
+    BrokenChain,
+}
+
+/// One entry in the append-only log of everything the bot has counted, keyed by the message that
+/// caused it.
+///
+/// The secondary keys pair an id with the event time in milliseconds, so a guild's or user's
+/// history can be read back in order with a range scan.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 3, version = 1)]
+#[native_db(
+    secondary_key(guild_key -> (u64, u64)),
+    secondary_key(user_key -> (u64, u64)),
+    secondary_key(time_key -> u64),
+)]
+pub struct WeedEvent {
+    #[primary_key]
+    pub(crate) message_id: MessageId,
+    pub kind: WeedEventKind,
+    pub(crate) guild_id: Option<GuildId>,
+    pub(crate) channel_id: ChannelId,
+    pub(crate) user_id: UserId,
+    pub timestamp: DateTime<Utc>,
+    /// Position of this message in its chain, for events that are part of one.
+    pub chain_position: Option<u32>,
+}
+
+impl WeedEvent {
+    pub fn new(
+        kind: WeedEventKind,
+        guild_id: Option<serenity::all::GuildId>,
+        channel_id: serenity::all::ChannelId,
+        user_id: serenity::all::UserId,
+        message_id: serenity::all::MessageId,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            message_id: MessageId::from(message_id),
+            kind,
+            guild_id: guild_id.map(GuildId::from),
+            channel_id: ChannelId::from(channel_id),
+            user_id: UserId::from(user_id),
+            timestamp,
+            chain_position: None,
+        }
+    }
+
+    pub fn message_id(&self) -> serenity::all::MessageId {
+        self.message_id.get()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.guild_id.map(|guild_id| guild_id.get())
+    }
+
+    pub fn channel_id(&self) -> serenity::all::ChannelId {
+        self.channel_id.get()
+    }
+
+    pub fn user_id(&self) -> serenity::all::UserId {
+        self.user_id.get()
+    }
+
+    fn guild_key(&self) -> (u64, u64) {
+        (guild_key(self.guild_id()), time_key(self.timestamp))
+    }
+
+    fn user_key(&self) -> (u64, u64) {
+        (self.user_id.get().get(), time_key(self.timestamp))
+    }
+
+    fn time_key(&self) -> u64 {
+        time_key(self.timestamp)
+    }
+}
+
+pub(crate) fn time_key(timestamp: DateTime<Utc>) -> u64 {
+    timestamp.timestamp_millis().try_into().unwrap_or(0)
+}
+
+/// A weed time chain that is still running in a channel, so it survives the bot restarting
+/// partway through the 4:20 window.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 4, version = 1)]
+#[native_db]
+pub struct ChainState {
+    #[primary_key]
+    pub(crate) channel_id: ChannelId,
+    pub(crate) guild_id: Option<GuildId>,
+    /// The bot's latest reply in the chain, which is edited when the chain continues.
+    pub(crate) message_id: MessageId,
+    pub(crate) users: Vec<UserId>,
+    pub count: u32,
+    /// When the window the chain is running in closes. The chain can't continue after this.
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ChainState {
+    pub fn new(
+        channel_id: serenity::all::ChannelId,
+        guild_id: Option<serenity::all::GuildId>,
+        message_id: serenity::all::MessageId,
+        users: impl IntoIterator<Item = serenity::all::UserId>,
+        count: u32,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            channel_id: ChannelId::from(channel_id),
+            guild_id: guild_id.map(GuildId::from),
+            message_id: MessageId::from(message_id),
+            users: users.into_iter().map(UserId::from).collect(),
+            count,
+            expires_at,
+        }
+    }
+
+    pub fn channel_id(&self) -> serenity::all::ChannelId {
+        self.channel_id.get()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.guild_id.map(|guild_id| guild_id.get())
+    }
+
+    pub fn message_id(&self) -> serenity::all::MessageId {
+        self.message_id.get()
+    }
+
+    pub fn users(&self) -> Vec<serenity::all::UserId> {
+        self.users.iter().map(UserId::get).collect()
+    }
+}
+
+/// A user's stats summed over every guild, kept up to date alongside their per-guild stats so
+/// users can be ranked across guilds without adding them up first.
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 5, version = 1)]
+#[native_db(
+    secondary_key(weed_times_key -> u32),
+    secondary_key(weed_crimes_key -> u32),
+    secondary_key(chains_started_key -> u32),
+    secondary_key(chains_broken_key -> u32),
+    secondary_key(longest_chain_key -> u32),
+)]
+pub struct UserTotals {
+    #[primary_key]
+    pub(crate) id: UserId,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub chains_started: u32,
+    pub chains_broken: u32,
+    pub longest_chain: u32,
+}
+
+impl UserTotals {
+    pub(crate) fn new(id: UserId) -> Self {
+        Self {
+            id,
+            weed_times: 0,
+            weed_crimes: 0,
+            chains_started: 0,
+            chains_broken: 0,
+            longest_chain: 0,
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::UserId {
+        self.id.get()
+    }
+
+    fn weed_times_key(&self) -> u32 {
+        self.weed_times
+    }
+
+    fn weed_crimes_key(&self) -> u32 {
+        self.weed_crimes
+    }
+
+    fn chains_started_key(&self) -> u32 {
+        self.chains_started
+    }
+
+    fn chains_broken_key(&self) -> u32 {
+        self.chains_broken
+    }
+
+    fn longest_chain_key(&self) -> u32 {
+        self.longest_chain
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerKind {
+    /// Matches the phrase as whole words, ignoring case.
+    Phrase,
+    /// Matches a regular expression anywhere in the message, ignoring case.
+    Regex,
+}
+
+/// Something a message can say to count as a weed time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Trigger {
+    pub kind: TriggerKind,
+    pub pattern: String,
+}
+
+impl Trigger {
+    pub fn phrase(pattern: impl Into<String>) -> Self {
+        Self {
+            kind: TriggerKind::Phrase,
+            pattern: pattern.into(),
+        }
+    }
+
+    pub fn regex(pattern: impl Into<String>) -> Self {
+        Self {
+            kind: TriggerKind::Regex,
+            pattern: pattern.into(),
+        }
+    }
+
+    /// The triggers a guild has until it changes them.
+    pub fn defaults() -> Vec<Self> {
+        vec![Self::phrase("weed time")]
+    }
+}
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 1)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub triggers: Vec<Trigger>,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            triggers: Trigger::defaults(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+/// Settings a user has chosen for themselves, which follow them into every guild.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 7, version = 1)]
+#[native_db]
+pub struct UserConfig {
+    #[primary_key]
+    pub(crate) id: UserId,
+    /// The timezone the user lives in, for guilds that go by their members' timezones.
+    pub timezone: Option<chrono_tz::Tz>,
+}
+
+impl UserConfig {
+    /// The settings a user has until they change any of them.
+    pub fn new(id: serenity::all::UserId) -> Self {
+        Self {
+            id: UserId::from(id),
+            timezone: None,
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::UserId {
+        self.id.get()
+    }
+}
+
+/// A message whose update has been committed, so it isn't counted again if Discord delivers it
+/// twice. When it was sent can be read off its id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 8, version = 1)]
+#[native_db]
+pub struct ProcessedMessage {
+    #[primary_key]
+    message_id: MessageId,
+}
+
+impl ProcessedMessage {
+    pub(crate) fn new(message_id: serenity::all::MessageId) -> Self {
+        Self {
+            message_id: MessageId::from(message_id),
+        }
+    }
+
+    pub fn message_id(&self) -> serenity::all::MessageId {
+        self.message_id.get()
+    }
+}
+
+/// A chain that has ended, kept so a guild can look back on its longest and latest ones.
+///
+/// Like the event keys, the secondary keys pair the guild with what chains are sorted by, so a
+/// guild's chains can be read back in order with a range scan.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 9, version = 1)]
+#[native_db(
+    secondary_key(length_key -> (u64, u32)),
+    secondary_key(ended_key -> (u64, u64)),
+)]
+pub struct Chain {
+    /// The bot's last answer in the chain, which shows how long it got.
+    #[primary_key]
+    message_id: MessageId,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    /// When the window the chain ran in opened.
+    pub window_start: DateTime<Utc>,
+    /// Everyone in the chain, in the order they joined it.
+    users: Vec<UserId>,
+    pub length: u32,
+    /// Whoever broke the chain, or `None` if it lasted until its window closed.
+    broken_by: Option<UserId>,
+    pub ended_at: DateTime<Utc>,
+}
+
+impl Chain {
+    pub fn new(
+        message_id: serenity::all::MessageId,
+        guild_id: Option<serenity::all::GuildId>,
+        channel_id: serenity::all::ChannelId,
+        window_start: DateTime<Utc>,
+        users: impl IntoIterator<Item = serenity::all::UserId>,
+        broken_by: Option<serenity::all::UserId>,
+        ended_at: DateTime<Utc>,
+    ) -> Self {
+        let users: Vec<_> = users.into_iter().map(UserId::from).collect();
+        Self {
+            message_id: MessageId::from(message_id),
+            guild_id: guild_id.map(GuildId::from),
+            channel_id: ChannelId::from(channel_id),
+            window_start,
+            length: users.len() as u32,
+            users,
+            broken_by: broken_by.map(UserId::from),
+            ended_at,
+        }
+    }
+
+    pub fn message_id(&self) -> serenity::all::MessageId {
+        self.message_id.get()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.guild_id.map(|guild_id| guild_id.get())
+    }
+
+    pub fn channel_id(&self) -> serenity::all::ChannelId {
+        self.channel_id.get()
+    }
+
+    pub fn users(&self) -> Vec<serenity::all::UserId> {
+        self.users.iter().map(UserId::get).collect()
+    }
+
+    pub fn broken_by(&self) -> Option<serenity::all::UserId> {
+        self.broken_by.map(|user_id| user_id.get())
+    }
+
+    fn length_key(&self) -> (u64, u32) {
+        (guild_key(self.guild_id()), self.length)
+    }
+
+    fn ended_key(&self) -> (u64, u64) {
+        (guild_key(self.guild_id()), time_key(self.ended_at))
+    }
+}
+
+/// How many users have a value on one leaderboard. A rank is found by adding up the counts of the
+/// values above it, rather than going through every user above.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 10, version = 1)]
+#[native_db]
+pub struct RankCount {
+    /// The metric, the leaderboard and the value, see `leaderboard::Board::key`.
+    #[primary_key]
+    pub(crate) key: (u8, u8, u64, u32),
+    pub(crate) users: u32,
+}
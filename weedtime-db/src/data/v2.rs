@@ -0,0 +1,370 @@
+use super::{
+    streak,
+    v1::{ChannelId, GuildId, GuildUserId, MessageId, Trigger, UserId},
+    *,
+};
+
+/// A user's stats within a single guild.
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 1, version = 2, from = v1::UserStats)]
+#[native_db(secondary_key(user_key -> UserId))]
+pub struct UserStats {
+    #[primary_key]
+    pub(crate) id: GuildUserId,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub chains_started: u32,
+    pub chains_broken: u32,
+}
+
+impl UserStats {
+    pub fn id(&self) -> serenity::all::UserId {
+        self.id.user_id()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.id.guild_id()
+    }
+
+    fn user_key(&self) -> UserId {
+        UserId::from(self.id.user_id())
+    }
+}
+
+// Stats recorded before they were tracked per guild can't be attributed to one, so they are
+// kept under no guild and only show up in a user's global totals.
+impl From<v1::UserStats> for UserStats {
+    fn from(stats: v1::UserStats) -> Self {
+        Self {
+            id: GuildUserId::new(None, stats.id.get()),
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+        }
+    }
+}
+
+impl From<UserStats> for v1::UserStats {
+    fn from(stats: UserStats) -> Self {
+        Self {
+            id: UserId::from(stats.id.user_id()),
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 2, version = 2, from = v1::GuildStats)]
+#[native_db]
+pub struct GuildStats {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub timezone: chrono_tz::Tz,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub longest_chain: u32,
+    /// Days in a row, up to `last_weed_day`, someone in the guild has had a weed time.
+    pub current_streak: u32,
+    pub best_streak: u32,
+    /// The last day someone in the guild had a weed time, in the guild's timezone.
+    pub last_weed_day: Option<NaiveDate>,
+}
+
+impl GuildStats {
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+
+    /// The guild's streak as it stands on `today`, which is 0 if it missed yesterday.
+    pub fn streak_on(&self, today: NaiveDate) -> u32 {
+        streak::current_on(self.current_streak, self.last_weed_day, today)
+    }
+}
+
+// Streaks weren't tracked before, so older stats start without one.
+impl From<v1::GuildStats> for GuildStats {
+    fn from(stats: v1::GuildStats) -> Self {
+        Self {
+            id: stats.id,
+            timezone: stats.timezone,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            longest_chain: stats.longest_chain,
+            current_streak: 0,
+            best_streak: 0,
+            last_weed_day: None,
+        }
+    }
+}
+
+impl From<GuildStats> for v1::GuildStats {
+    fn from(stats: GuildStats) -> Self {
+        Self {
+            id: stats.id,
+            timezone: stats.timezone,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            longest_chain: stats.longest_chain,
+        }
+    }
+}
+
+/// How closely a message has to match a guild's triggers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchMode {
+    /// Only looks past how text is displayed, like fullwidth or bold Unicode letters.
+    #[default]
+    Strict,
+    /// Also sees through markdown, lookalike letters, leetspeak and words that are spaced out
+    /// or run together.
+    Lenient,
+}
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 2, from = v1::GuildConfig)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub triggers: Vec<Trigger>,
+    pub match_mode: MatchMode,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            triggers: Trigger::defaults(),
+            match_mode: MatchMode::default(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+impl From<v1::GuildConfig> for GuildConfig {
+    fn from(config: v1::GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: MatchMode::default(),
+        }
+    }
+}
+
+impl From<GuildConfig> for v1::GuildConfig {
+    fn from(config: GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+        }
+    }
+}
+
+/// A weed time chain that is still running in a channel, so it survives the bot restarting
+/// partway through the 4:20 window.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 4, version = 2, from = v1::ChainState)]
+#[native_db]
+pub struct ChainState {
+    #[primary_key]
+    pub(crate) channel_id: ChannelId,
+    pub(crate) guild_id: Option<GuildId>,
+    /// The bot's latest reply in the chain, which is edited when the chain continues.
+    pub(crate) message_id: MessageId,
+    pub(crate) users: Vec<UserId>,
+    pub count: u32,
+    /// When the window the chain is running in opened.
+    pub window_start: DateTime<Utc>,
+    /// When the window the chain is running in closes. The chain can't continue after this.
+    pub expires_at: DateTime<Utc>,
+}
+
+impl ChainState {
+    pub fn new(
+        channel_id: serenity::all::ChannelId,
+        guild_id: Option<serenity::all::GuildId>,
+        message_id: serenity::all::MessageId,
+        users: impl IntoIterator<Item = serenity::all::UserId>,
+        count: u32,
+        window_start: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            channel_id: ChannelId::from(channel_id),
+            guild_id: guild_id.map(GuildId::from),
+            message_id: MessageId::from(message_id),
+            users: users.into_iter().map(UserId::from).collect(),
+            count,
+            window_start,
+            expires_at,
+        }
+    }
+
+    pub fn channel_id(&self) -> serenity::all::ChannelId {
+        self.channel_id.get()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.guild_id.map(|guild_id| guild_id.get())
+    }
+
+    pub fn message_id(&self) -> serenity::all::MessageId {
+        self.message_id.get()
+    }
+
+    pub fn users(&self) -> Vec<serenity::all::UserId> {
+        self.users.iter().map(UserId::get).collect()
+    }
+}
+
+// Chains saved before the start of their window was kept are assumed to have run in one without
+// a grace period.
+impl From<v1::ChainState> for ChainState {
+    fn from(state: v1::ChainState) -> Self {
+        Self {
+            channel_id: state.channel_id,
+            guild_id: state.guild_id,
+            message_id: state.message_id,
+            users: state.users,
+            count: state.count,
+            window_start: state.expires_at - chrono::TimeDelta::minutes(1),
+            expires_at: state.expires_at,
+        }
+    }
+}
+
+impl From<ChainState> for v1::ChainState {
+    fn from(state: ChainState) -> Self {
+        Self {
+            channel_id: state.channel_id,
+            guild_id: state.guild_id,
+            message_id: state.message_id,
+            users: state.users,
+            count: state.count,
+            expires_at: state.expires_at,
+        }
+    }
+}
+
+/// One entry in the append-only log of everything the bot has counted, keyed by the message that
+/// caused it.
+///
+/// The secondary keys pair an id with the event time in milliseconds, so a guild's or user's
+/// history can be read back in order with a range scan.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 3, version = 2, from = v1::WeedEvent)]
+#[native_db(
+    secondary_key(guild_key -> (u64, u64)),
+    secondary_key(user_key -> (u64, u64)),
+    secondary_key(time_key -> u64),
+)]
+pub struct WeedEvent {
+    #[primary_key]
+    message_id: MessageId,
+    pub kind: v1::WeedEventKind,
+    guild_id: Option<GuildId>,
+    channel_id: ChannelId,
+    user_id: UserId,
+    pub timestamp: DateTime<Utc>,
+    /// Position of this message in its chain, for events that are part of one.
+    pub chain_position: Option<u32>,
+    /// The bot's answer to the message, so it can be taken down if the message is deleted.
+    reply_id: Option<MessageId>,
+}
+
+impl WeedEvent {
+    pub fn new(
+        kind: v1::WeedEventKind,
+        guild_id: Option<serenity::all::GuildId>,
+        channel_id: serenity::all::ChannelId,
+        user_id: serenity::all::UserId,
+        message_id: serenity::all::MessageId,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            message_id: MessageId::from(message_id),
+            kind,
+            guild_id: guild_id.map(GuildId::from),
+            channel_id: ChannelId::from(channel_id),
+            user_id: UserId::from(user_id),
+            timestamp,
+            chain_position: None,
+            reply_id: None,
+        }
+    }
+
+    pub fn message_id(&self) -> serenity::all::MessageId {
+        self.message_id.get()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.guild_id.map(|guild_id| guild_id.get())
+    }
+
+    pub fn channel_id(&self) -> serenity::all::ChannelId {
+        self.channel_id.get()
+    }
+
+    pub fn user_id(&self) -> serenity::all::UserId {
+        self.user_id.get()
+    }
+
+    pub fn reply_id(&self) -> Option<serenity::all::MessageId> {
+        self.reply_id.map(|reply_id| reply_id.get())
+    }
+
+    pub fn set_reply_id(&mut self, reply_id: Option<serenity::all::MessageId>) {
+        self.reply_id = reply_id.map(MessageId::from);
+    }
+
+    fn guild_key(&self) -> (u64, u64) {
+        (v1::guild_key(self.guild_id()), v1::time_key(self.timestamp))
+    }
+
+    fn user_key(&self) -> (u64, u64) {
+        (self.user_id.get().get(), v1::time_key(self.timestamp))
+    }
+
+    fn time_key(&self) -> u64 {
+        v1::time_key(self.timestamp)
+    }
+}
+
+// Which answer went with an event wasn't kept before.
+impl From<v1::WeedEvent> for WeedEvent {
+    fn from(event: v1::WeedEvent) -> Self {
+        Self {
+            message_id: event.message_id,
+            kind: event.kind,
+            guild_id: event.guild_id,
+            channel_id: event.channel_id,
+            user_id: event.user_id,
+            timestamp: event.timestamp,
+            chain_position: event.chain_position,
+            reply_id: None,
+        }
+    }
+}
+
+impl From<WeedEvent> for v1::WeedEvent {
+    fn from(event: WeedEvent) -> Self {
+        Self {
+            message_id: event.message_id,
+            kind: event.kind,
+            guild_id: event.guild_id,
+            channel_id: event.channel_id,
+            user_id: event.user_id,
+            timestamp: event.timestamp,
+            chain_position: event.chain_position,
+        }
+    }
+}
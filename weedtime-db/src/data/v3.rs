@@ -0,0 +1,320 @@
+use super::{
+    streak,
+    v1::{ChannelId, GuildId, GuildUserId, MessageId, Trigger, UserId},
+    v2::MatchMode,
+    *,
+};
+
+/// A user's stats within a single guild.
+///
+/// Every counter has a secondary key of the guild and the counter's value, so a guild's
+/// leaderboard for it can be read in order with a range scan.
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 1, version = 3, from = v2::UserStats)]
+#[native_db(
+    secondary_key(user_key -> UserId),
+    secondary_key(weed_times_key -> (u64, u32)),
+    secondary_key(weed_crimes_key -> (u64, u32)),
+    secondary_key(chains_started_key -> (u64, u32)),
+    secondary_key(longest_chain_key -> (u64, u32)),
+)]
+pub struct UserStats {
+    #[primary_key]
+    pub(crate) id: GuildUserId,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub chains_started: u32,
+    pub chains_broken: u32,
+    /// The longest chain the user has been part of.
+    pub longest_chain: u32,
+}
+
+impl UserStats {
+    pub fn id(&self) -> serenity::all::UserId {
+        self.id.user_id()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.id.guild_id()
+    }
+
+    fn user_key(&self) -> UserId {
+        UserId::from(self.id.user_id())
+    }
+
+    fn weed_times_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.weed_times)
+    }
+
+    fn weed_crimes_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.weed_crimes)
+    }
+
+    fn chains_started_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.chains_started)
+    }
+
+    fn longest_chain_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.longest_chain)
+    }
+}
+
+// Chain lengths weren't recorded per user before, so older stats start without one.
+impl From<v2::UserStats> for UserStats {
+    fn from(stats: v2::UserStats) -> Self {
+        Self {
+            id: stats.id,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+            longest_chain: 0,
+        }
+    }
+}
+
+impl From<UserStats> for v2::UserStats {
+    fn from(stats: UserStats) -> Self {
+        Self {
+            id: stats.id,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+        }
+    }
+}
+
+/// A guild's stats. Its timezone is a setting, so it is kept in [`v6::GuildConfig`] now.
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 2, version = 3, from = v2::GuildStats)]
+#[native_db]
+pub struct GuildStats {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub longest_chain: u32,
+    /// Days in a row, up to `last_weed_day`, someone in the guild has had a weed time.
+    pub current_streak: u32,
+    pub best_streak: u32,
+    /// The last day someone in the guild had a weed time, in the guild's timezone.
+    pub last_weed_day: Option<NaiveDate>,
+}
+
+impl GuildStats {
+    pub(crate) fn new(id: GuildId) -> Self {
+        Self {
+            id,
+            weed_times: 0,
+            weed_crimes: 0,
+            longest_chain: 0,
+            current_streak: 0,
+            best_streak: 0,
+            last_weed_day: None,
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+
+    /// The guild's streak as it stands on `today`, which is 0 if it missed yesterday.
+    pub fn streak_on(&self, today: NaiveDate) -> u32 {
+        streak::current_on(self.current_streak, self.last_weed_day, today)
+    }
+}
+
+// The timezone is carried over to the guild's config by the migration, before the stats are
+// converted.
+impl From<v2::GuildStats> for GuildStats {
+    fn from(stats: v2::GuildStats) -> Self {
+        Self {
+            id: stats.id,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            longest_chain: stats.longest_chain,
+            current_streak: stats.current_streak,
+            best_streak: stats.best_streak,
+            last_weed_day: stats.last_weed_day,
+        }
+    }
+}
+
+impl From<GuildStats> for v2::GuildStats {
+    fn from(stats: GuildStats) -> Self {
+        Self {
+            id: stats.id,
+            timezone: v6::DEFAULT_TIMEZONE,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            longest_chain: stats.longest_chain,
+            current_streak: stats.current_streak,
+            best_streak: stats.best_streak,
+            last_weed_day: stats.last_weed_day,
+        }
+    }
+}
+
+/// When messages count as a weed time in a guild. Each time of day opens a window for its whole
+/// minute, widened by the grace period on both sides.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WeedWindow {
+    /// Whether 4:20 AM counts.
+    pub am: bool,
+    /// Whether 4:20 PM counts.
+    pub pm: bool,
+    /// Other times of day that count, in the guild's timezone.
+    pub extra_times: Vec<NaiveTime>,
+    pub grace_seconds: u32,
+}
+
+impl Default for WeedWindow {
+    fn default() -> Self {
+        Self {
+            am: true,
+            pm: true,
+            extra_times: Vec::new(),
+            grace_seconds: 0,
+        }
+    }
+}
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 3, from = v2::GuildConfig)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub triggers: Vec<Trigger>,
+    pub match_mode: MatchMode,
+    pub window: WeedWindow,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            triggers: Trigger::defaults(),
+            match_mode: MatchMode::default(),
+            window: WeedWindow::default(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+impl From<v2::GuildConfig> for GuildConfig {
+    fn from(config: v2::GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: WeedWindow::default(),
+        }
+    }
+}
+
+impl From<GuildConfig> for v2::GuildConfig {
+    fn from(config: GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+        }
+    }
+}
+
+/// A weed time chain that is still running in a channel, so it survives the bot restarting
+/// partway through the 4:20 window.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 4, version = 3, from = v2::ChainState)]
+#[native_db]
+pub struct ChainState {
+    #[primary_key]
+    channel_id: ChannelId,
+    guild_id: Option<GuildId>,
+    /// The bot's latest reply in the chain, which is edited when the chain continues.
+    message_id: MessageId,
+    users: Vec<UserId>,
+    pub count: u32,
+    /// When the window the chain is running in opened.
+    pub window_start: DateTime<Utc>,
+    /// When the window the chain is running in closes. The chain can't continue after this.
+    pub expires_at: DateTime<Utc>,
+    /// The guild's longest chain from before this one started, which it has to beat to be the
+    /// new server record. `None` if it isn't known, like for chains saved before it was kept.
+    pub server_record: Option<u32>,
+}
+
+impl ChainState {
+    pub fn new(
+        channel_id: serenity::all::ChannelId,
+        guild_id: Option<serenity::all::GuildId>,
+        message_id: serenity::all::MessageId,
+        users: impl IntoIterator<Item = serenity::all::UserId>,
+        count: u32,
+        window_start: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            channel_id: ChannelId::from(channel_id),
+            guild_id: guild_id.map(GuildId::from),
+            message_id: MessageId::from(message_id),
+            users: users.into_iter().map(UserId::from).collect(),
+            count,
+            window_start,
+            expires_at,
+            server_record: None,
+        }
+    }
+
+    pub fn channel_id(&self) -> serenity::all::ChannelId {
+        self.channel_id.get()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.guild_id.map(|guild_id| guild_id.get())
+    }
+
+    pub fn message_id(&self) -> serenity::all::MessageId {
+        self.message_id.get()
+    }
+
+    pub fn users(&self) -> Vec<serenity::all::UserId> {
+        self.users.iter().map(UserId::get).collect()
+    }
+}
+
+impl From<v2::ChainState> for ChainState {
+    fn from(state: v2::ChainState) -> Self {
+        Self {
+            channel_id: state.channel_id,
+            guild_id: state.guild_id,
+            message_id: state.message_id,
+            users: state.users,
+            count: state.count,
+            window_start: state.window_start,
+            expires_at: state.expires_at,
+            server_record: None,
+        }
+    }
+}
+
+impl From<ChainState> for v2::ChainState {
+    fn from(state: ChainState) -> Self {
+        Self {
+            channel_id: state.channel_id,
+            guild_id: state.guild_id,
+            message_id: state.message_id,
+            users: state.users,
+            count: state.count,
+            window_start: state.window_start,
+            expires_at: state.expires_at,
+        }
+    }
+}
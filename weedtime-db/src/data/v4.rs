@@ -0,0 +1,191 @@
+use super::{
+    v1::{GuildId, GuildUserId, Trigger, UserId},
+    v2::MatchMode,
+    *,
+};
+
+/// A user's stats within a single guild.
+///
+/// Every counter has a secondary key of the guild and the counter's value, so a guild's
+/// leaderboard for it can be read in order with a range scan.
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 1, version = 4, from = v3::UserStats)]
+#[native_db(
+    secondary_key(user_key -> UserId),
+    secondary_key(weed_times_key -> (u64, u32)),
+    secondary_key(weed_crimes_key -> (u64, u32)),
+    secondary_key(chains_started_key -> (u64, u32)),
+    secondary_key(chains_broken_key -> (u64, u32)),
+    secondary_key(longest_chain_key -> (u64, u32)),
+)]
+pub struct UserStats {
+    #[primary_key]
+    pub(crate) id: GuildUserId,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub chains_started: u32,
+    pub chains_broken: u32,
+    /// The longest chain the user has been part of.
+    pub longest_chain: u32,
+}
+
+impl UserStats {
+    pub fn id(&self) -> serenity::all::UserId {
+        self.id.user_id()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.id.guild_id()
+    }
+
+    fn user_key(&self) -> UserId {
+        UserId::from(self.id.user_id())
+    }
+
+    fn weed_times_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.weed_times)
+    }
+
+    fn weed_crimes_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.weed_crimes)
+    }
+
+    fn chains_started_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.chains_started)
+    }
+
+    fn chains_broken_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.chains_broken)
+    }
+
+    fn longest_chain_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.longest_chain)
+    }
+}
+
+impl From<v3::UserStats> for UserStats {
+    fn from(stats: v3::UserStats) -> Self {
+        Self {
+            id: stats.id,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+            longest_chain: stats.longest_chain,
+        }
+    }
+}
+
+impl From<UserStats> for v3::UserStats {
+    fn from(stats: UserStats) -> Self {
+        Self {
+            id: stats.id,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+            longest_chain: stats.longest_chain,
+        }
+    }
+}
+
+/// When messages count as a weed time in a guild. Each time of day opens a window for its whole
+/// minute, widened by the grace period on both sides.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WeedWindow {
+    /// Whether 4:20 AM counts.
+    pub am: bool,
+    /// Whether 4:20 PM counts.
+    pub pm: bool,
+    /// Other times of day that count, in the guild's timezone.
+    pub extra_times: Vec<NaiveTime>,
+    pub grace_seconds: u32,
+    /// Whether it's enough for one of the times to have come round anywhere in the world,
+    /// rather than in the guild's timezone.
+    pub anywhere: bool,
+}
+
+impl Default for WeedWindow {
+    fn default() -> Self {
+        Self {
+            am: true,
+            pm: true,
+            extra_times: Vec::new(),
+            grace_seconds: 0,
+            anywhere: false,
+        }
+    }
+}
+
+impl From<v3::WeedWindow> for WeedWindow {
+    fn from(window: v3::WeedWindow) -> Self {
+        Self {
+            am: window.am,
+            pm: window.pm,
+            extra_times: window.extra_times,
+            grace_seconds: window.grace_seconds,
+            anywhere: false,
+        }
+    }
+}
+
+impl From<WeedWindow> for v3::WeedWindow {
+    fn from(window: WeedWindow) -> Self {
+        Self {
+            am: window.am,
+            pm: window.pm,
+            extra_times: window.extra_times,
+            grace_seconds: window.grace_seconds,
+        }
+    }
+}
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 4, from = v3::GuildConfig)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub triggers: Vec<Trigger>,
+    pub match_mode: MatchMode,
+    pub window: WeedWindow,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            triggers: Trigger::defaults(),
+            match_mode: MatchMode::default(),
+            window: WeedWindow::default(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+impl From<v3::GuildConfig> for GuildConfig {
+    fn from(config: v3::GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window.into(),
+        }
+    }
+}
+
+impl From<GuildConfig> for v3::GuildConfig {
+    fn from(config: GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window.into(),
+        }
+    }
+}
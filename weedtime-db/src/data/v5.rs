@@ -0,0 +1,184 @@
+use super::{
+    streak,
+    v1::{GuildId, GuildUserId, Trigger, UserId},
+    v2::MatchMode,
+    v4::WeedWindow,
+    *,
+};
+
+/// A user's stats within a single guild.
+///
+/// Every counter has a secondary key of the guild and the counter's value, so a guild's
+/// leaderboard for it can be read in order with a range scan.
+#[derive(Serialize, Deserialize, Debug)]
+#[native_model(id = 1, version = 5, from = v4::UserStats)]
+#[native_db(
+    secondary_key(user_key -> UserId),
+    secondary_key(weed_times_key -> (u64, u32)),
+    secondary_key(weed_crimes_key -> (u64, u32)),
+    secondary_key(chains_started_key -> (u64, u32)),
+    secondary_key(chains_broken_key -> (u64, u32)),
+    secondary_key(longest_chain_key -> (u64, u32)),
+)]
+pub struct UserStats {
+    #[primary_key]
+    pub(crate) id: GuildUserId,
+    pub weed_times: u32,
+    pub weed_crimes: u32,
+    pub chains_started: u32,
+    pub chains_broken: u32,
+    /// The longest chain the user has been part of.
+    pub longest_chain: u32,
+    /// Days in a row, up to `last_weed_day`, the user has had a weed time.
+    pub current_streak: u32,
+    pub best_streak: u32,
+    /// The last day the user had a weed time, in the guild's timezone.
+    pub last_weed_day: Option<NaiveDate>,
+}
+
+impl UserStats {
+    pub(crate) fn new(id: GuildUserId) -> Self {
+        Self {
+            id,
+            weed_times: 0,
+            weed_crimes: 0,
+            chains_started: 0,
+            chains_broken: 0,
+            longest_chain: 0,
+            current_streak: 0,
+            best_streak: 0,
+            last_weed_day: None,
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::UserId {
+        self.id.user_id()
+    }
+
+    pub fn guild_id(&self) -> Option<serenity::all::GuildId> {
+        self.id.guild_id()
+    }
+
+    /// The user's streak as it stands on `today`, which is 0 if they missed yesterday.
+    pub fn streak_on(&self, today: NaiveDate) -> u32 {
+        streak::current_on(self.current_streak, self.last_weed_day, today)
+    }
+
+    fn user_key(&self) -> UserId {
+        UserId::from(self.id.user_id())
+    }
+
+    fn weed_times_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.weed_times)
+    }
+
+    fn weed_crimes_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.weed_crimes)
+    }
+
+    fn chains_started_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.chains_started)
+    }
+
+    fn chains_broken_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.chains_broken)
+    }
+
+    fn longest_chain_key(&self) -> (u64, u32) {
+        (self.id.guild_key(), self.longest_chain)
+    }
+}
+
+// Streaks weren't tracked before, so older stats start without one.
+impl From<v4::UserStats> for UserStats {
+    fn from(stats: v4::UserStats) -> Self {
+        Self {
+            id: stats.id,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+            longest_chain: stats.longest_chain,
+            current_streak: 0,
+            best_streak: 0,
+            last_weed_day: None,
+        }
+    }
+}
+
+impl From<UserStats> for v4::UserStats {
+    fn from(stats: UserStats) -> Self {
+        Self {
+            id: stats.id,
+            weed_times: stats.weed_times,
+            weed_crimes: stats.weed_crimes,
+            chains_started: stats.chains_started,
+            chains_broken: stats.chains_broken,
+            longest_chain: stats.longest_chain,
+        }
+    }
+}
+
+/// Whose timezone decides whether a message was sent at weed time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TimezoneSource {
+    #[default]
+    Guild,
+    /// The timezone the author has set for themselves, or the guild's if they haven't.
+    Author,
+    /// Either the guild's or the author's.
+    Either,
+}
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 5, from = v4::GuildConfig)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub triggers: Vec<Trigger>,
+    pub match_mode: MatchMode,
+    pub window: WeedWindow,
+    pub timezone_source: TimezoneSource,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            triggers: Trigger::defaults(),
+            match_mode: MatchMode::default(),
+            window: WeedWindow::default(),
+            timezone_source: TimezoneSource::default(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+impl From<v4::GuildConfig> for GuildConfig {
+    fn from(config: v4::GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+            timezone_source: TimezoneSource::default(),
+        }
+    }
+}
+
+impl From<GuildConfig> for v4::GuildConfig {
+    fn from(config: GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use chrono_tz::Tz;
+
+use super::{
+    v1::{GuildId, Trigger},
+    v2::MatchMode,
+    v4::WeedWindow,
+    v5::TimezoneSource,
+    *,
+};
+
+/// The timezone a guild goes by until it picks one.
+pub const DEFAULT_TIMEZONE: Tz = Tz::America__New_York;
+
+/// How the bot answers a weed time or weed crime.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResponseMode {
+    /// A message with the weed time or jail picture attached.
+    #[default]
+    Image,
+    /// Just the message, for servers that don't want pictures posted.
+    Text,
+}
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 6, from = v5::GuildConfig)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub timezone: Tz,
+    pub triggers: Vec<Trigger>,
+    pub match_mode: MatchMode,
+    pub window: WeedWindow,
+    pub timezone_source: TimezoneSource,
+    pub response_mode: ResponseMode,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            timezone: DEFAULT_TIMEZONE,
+            triggers: Trigger::defaults(),
+            match_mode: MatchMode::default(),
+            window: WeedWindow::default(),
+            timezone_source: TimezoneSource::default(),
+            response_mode: ResponseMode::default(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+// The timezone used to be kept in the guild's stats. The migration copies it over after
+// converting the config.
+impl From<v5::GuildConfig> for GuildConfig {
+    fn from(config: v5::GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            timezone: DEFAULT_TIMEZONE,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+            timezone_source: config.timezone_source,
+            response_mode: ResponseMode::default(),
+        }
+    }
+}
+
+impl From<GuildConfig> for v5::GuildConfig {
+    fn from(config: GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+            timezone_source: config.timezone_source,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use chrono_tz::Tz;
+
+use super::{
+    v1::{ChannelId, GuildId, Trigger},
+    v2::MatchMode,
+    v4::WeedWindow,
+    v5::TimezoneSource,
+    v6::{DEFAULT_TIMEZONE, ResponseMode},
+    *,
+};
+
+/// Which of a guild's channels the bot watches. Categories can be allowed or denied like any
+/// other channel, which covers every channel in them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ChannelFilter {
+    allowed: Vec<ChannelId>,
+    denied: Vec<ChannelId>,
+}
+
+impl ChannelFilter {
+    /// Whether the guild watches every channel.
+    pub fn is_empty(&self) -> bool {
+        self.allowed.is_empty() && self.denied.is_empty()
+    }
+
+    pub fn allowed(&self) -> Vec<serenity::all::ChannelId> {
+        self.allowed.iter().map(ChannelId::get).collect()
+    }
+
+    pub fn denied(&self) -> Vec<serenity::all::ChannelId> {
+        self.denied.iter().map(ChannelId::get).collect()
+    }
+
+    /// Whether messages sent in `channels[0]` count. The rest of `channels` are the ones it sits
+    /// in, closest first: a thread's parent channel, then that channel's category.
+    ///
+    /// The closest channel with a rule decides, so a channel can be denied inside an allowed
+    /// category. Channels without one are watched unless the guild has allowed any.
+    pub fn allows(&self, channels: &[serenity::all::ChannelId]) -> bool {
+        let has = |list: &[ChannelId], channel| list.iter().any(|other| other.get() == channel);
+
+        channels
+            .iter()
+            .find_map(|&channel| {
+                if has(&self.denied, channel) {
+                    Some(false)
+                } else if has(&self.allowed, channel) {
+                    Some(true)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or(self.allowed.is_empty())
+    }
+
+    pub(crate) fn allow(&mut self, channel_id: serenity::all::ChannelId) {
+        self.clear(channel_id);
+        self.allowed.push(ChannelId::from(channel_id));
+    }
+
+    pub(crate) fn deny(&mut self, channel_id: serenity::all::ChannelId) {
+        self.clear(channel_id);
+        self.denied.push(ChannelId::from(channel_id));
+    }
+
+    pub(crate) fn clear(&mut self, channel_id: serenity::all::ChannelId) {
+        self.allowed.retain(|channel| channel.get() != channel_id);
+        self.denied.retain(|channel| channel.get() != channel_id);
+    }
+}
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 7, from = v6::GuildConfig)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub timezone: Tz,
+    pub triggers: Vec<Trigger>,
+    pub match_mode: MatchMode,
+    pub window: WeedWindow,
+    pub timezone_source: TimezoneSource,
+    pub response_mode: ResponseMode,
+    pub channels: ChannelFilter,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            timezone: DEFAULT_TIMEZONE,
+            triggers: Trigger::defaults(),
+            match_mode: MatchMode::default(),
+            window: WeedWindow::default(),
+            timezone_source: TimezoneSource::default(),
+            response_mode: ResponseMode::default(),
+            channels: ChannelFilter::default(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+impl From<v6::GuildConfig> for GuildConfig {
+    fn from(config: v6::GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            timezone: config.timezone,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+            timezone_source: config.timezone_source,
+            response_mode: config.response_mode,
+            channels: ChannelFilter::default(),
+        }
+    }
+}
+
+impl From<GuildConfig> for v6::GuildConfig {
+    fn from(config: GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            timezone: config.timezone,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+            timezone_source: config.timezone_source,
+            response_mode: config.response_mode,
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use chrono_tz::Tz;
+
+use super::{
+    v1::{GuildId, Trigger},
+    v2::MatchMode,
+    v4::WeedWindow,
+    v5::TimezoneSource,
+    v6::{DEFAULT_TIMEZONE, ResponseMode},
+    v7::ChannelFilter,
+    *,
+};
+
+/// The locale a guild goes by until it picks one, in the form Discord names locales.
+pub const DEFAULT_LOCALE: &str = "en-US";
+
+/// Settings a guild has chosen for the bot, as opposed to the stats it has racked up.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[native_model(id = 6, version = 8, from = v7::GuildConfig)]
+#[native_db]
+pub struct GuildConfig {
+    #[primary_key]
+    pub(crate) id: GuildId,
+    pub timezone: Tz,
+    pub triggers: Vec<Trigger>,
+    pub match_mode: MatchMode,
+    pub window: WeedWindow,
+    pub timezone_source: TimezoneSource,
+    pub response_mode: ResponseMode,
+    pub channels: ChannelFilter,
+    pub locale: String,
+}
+
+impl GuildConfig {
+    /// The settings a guild has until it changes any of them.
+    pub fn new(id: serenity::all::GuildId) -> Self {
+        Self {
+            id: GuildId::from(id),
+            timezone: DEFAULT_TIMEZONE,
+            triggers: Trigger::defaults(),
+            match_mode: MatchMode::default(),
+            window: WeedWindow::default(),
+            timezone_source: TimezoneSource::default(),
+            response_mode: ResponseMode::default(),
+            channels: ChannelFilter::default(),
+            locale: DEFAULT_LOCALE.to_string(),
+        }
+    }
+
+    pub fn id(&self) -> serenity::all::GuildId {
+        self.id.get()
+    }
+}
+
+impl From<v7::GuildConfig> for GuildConfig {
+    fn from(config: v7::GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            timezone: config.timezone,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+            timezone_source: config.timezone_source,
+            response_mode: config.response_mode,
+            channels: config.channels,
+            locale: DEFAULT_LOCALE.to_string(),
+        }
+    }
+}
+
+impl From<GuildConfig> for v7::GuildConfig {
+    fn from(config: GuildConfig) -> Self {
+        Self {
+            id: config.id,
+            timezone: config.timezone,
+            triggers: config.triggers,
+            match_mode: config.match_mode,
+            window: config.window,
+            timezone_source: config.timezone_source,
+            response_mode: config.response_mode,
+            channels: config.channels,
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! Working out whether a message falls in one of a guild's weed time windows.
+
+use chrono::{DateTime, NaiveTime, TimeDelta, TimeZone, Utc};
+use chrono_tz::{TZ_VARIANTS, Tz};
+
+use super::v4::WeedWindow;
+
+/// The window a message fell in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowMatch {
+    /// Where it was weed time. This is one of the timezones that were asked about unless the
+    /// guild counts weed time anywhere and it wasn't weed time in any of them.
+    pub timezone: Tz,
+    /// The time of day that opened the window, in `timezone`.
+    pub time: NaiveTime,
+    /// When the window opens, including its grace period.
+    pub start: DateTime<Utc>,
+    /// When the window closes. A chain can't continue after this.
+    pub end: DateTime<Utc>,
+}
+
+impl WeedWindow {
+    /// Every time of day that opens a window, in the guild's timezone.
+    pub fn times(&self) -> Vec<NaiveTime> {
+        let am = NaiveTime::from_hms_opt(4, 20, 0).filter(|_| self.am);
+        let pm = NaiveTime::from_hms_opt(16, 20, 0).filter(|_| self.pm);
+
+        am.into_iter()
+            .chain(pm)
+            .chain(self.extra_times.iter().copied())
+            .collect()
+    }
+
+    /// The window `timestamp` falls in, trying each of `timezones` in order, or `None` if it
+    /// isn't in one. When the guild counts weed time anywhere, every other timezone is tried
+    /// after them, in the order of [`TZ_VARIANTS`].
+    pub fn find(&self, timestamp: DateTime<Utc>, timezones: &[Tz]) -> Option<WindowMatch> {
+        let others = TZ_VARIANTS
+            .iter()
+            .copied()
+            .filter(|_| self.anywhere)
+            .filter(|other| !timezones.contains(other));
+
+        timezones
+            .iter()
+            .copied()
+            .chain(others)
+            .find_map(|timezone| self.find_in(&timestamp.with_timezone(&timezone)))
+    }
+
+    /// The window `timestamp` falls in, in its own timezone. If windows overlap, the one that
+    /// stays open longest wins.
+    fn find_in(&self, timestamp: &DateTime<Tz>) -> Option<WindowMatch> {
+        let grace = TimeDelta::seconds(self.grace_seconds.into());
+        let timezone = timestamp.timezone();
+        let date = timestamp.date_naive();
+        let timestamp = timestamp.with_timezone(&Utc);
+
+        // The grace period can carry a window over midnight, so the days either side are
+        // checked too.
+        [date.pred_opt(), Some(date), date.succ_opt()]
+            .into_iter()
+            .flatten()
+            .flat_map(|date| self.times().into_iter().map(move |time| (date, time)))
+            // Times skipped by a DST change don't happen that day.
+            .filter_map(|(date, time)| {
+                let start = timezone
+                    .from_local_datetime(&date.and_time(time))
+                    .earliest()?
+                    .with_timezone(&Utc);
+                let end = start + TimeDelta::minutes(1) + grace;
+                (start - grace <= timestamp && timestamp < end).then_some(WindowMatch {
+                    timezone,
+                    time,
+                    start: start - grace,
+                    end,
+                })
+            })
+            .max_by_key(|window| window.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Timelike;
+
+    use super::*;
+
+    const TIMEZONE: Tz = Tz::America__New_York;
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        TIMEZONE
+            .with_ymd_and_hms(2025, 4, 20, hour, minute, second)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    fn utc(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2025, 1, 15, hour, minute, 0).unwrap()
+    }
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn window_end(window: &WeedWindow, timestamp: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        window
+            .find(timestamp, &[TIMEZONE])
+            .map(|window_match| window_match.end)
+    }
+
+    #[test]
+    fn opens_for_the_whole_minute() {
+        let window = WeedWindow::default();
+
+        for (timestamp, expected) in [
+            (at(4, 19, 59), None),
+            (at(4, 20, 0), Some(at(4, 21, 0))),
+            (at(4, 20, 59), Some(at(4, 21, 0))),
+            (at(4, 21, 0), None),
+            (at(16, 20, 30), Some(at(16, 21, 0))),
+            (at(7, 10, 0), None),
+        ] {
+            assert_eq!(window_end(&window, timestamp), expected, "{timestamp}");
+        }
+    }
+
+    #[test]
+    fn only_counts_chosen_times() {
+        let window = WeedWindow {
+            am: false,
+            extra_times: vec![time(7, 10)],
+            ..WeedWindow::default()
+        };
+
+        assert_eq!(window.times(), [time(16, 20), time(7, 10)]);
+        assert!(window_end(&window, at(4, 20, 0)).is_none());
+        assert!(window_end(&window, at(16, 20, 0)).is_some());
+        assert_eq!(
+            window
+                .find(at(7, 10, 0), &[TIMEZONE])
+                .map(|window| window.time),
+            Some(time(7, 10))
+        );
+        assert!(window_end(&window, at(19, 10, 0)).is_none());
+    }
+
+    #[test]
+    fn widens_by_the_grace_period() {
+        let window = WeedWindow {
+            grace_seconds: 30,
+            ..WeedWindow::default()
+        };
+        let end = Some(at(4, 21, 30));
+
+        assert_eq!(window_end(&window, at(4, 19, 29)), None);
+        assert_eq!(window_end(&window, at(4, 19, 30)), end);
+        assert_eq!(window_end(&window, at(4, 21, 29)), end);
+        assert_eq!(window_end(&window, at(4, 21, 30)), None);
+    }
+
+    #[test]
+    fn grace_period_carries_over_midnight() {
+        let window = WeedWindow {
+            am: false,
+            pm: false,
+            extra_times: vec![time(0, 0)],
+            grace_seconds: 60,
+            anywhere: false,
+        };
+
+        let midnight = TIMEZONE
+            .with_ymd_and_hms(2025, 4, 21, 0, 0, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            window_end(&window, at(23, 59, 30)),
+            Some(midnight + TimeDelta::minutes(2))
+        );
+    }
+
+    #[test]
+    fn only_counts_other_timezones_anywhere() {
+        let window = WeedWindow::default();
+        // 4:20 AM in Kathmandu.
+        assert_eq!(window.find(utc(22, 35), &[TIMEZONE]), None);
+
+        let window = WeedWindow {
+            anywhere: true,
+            ..WeedWindow::default()
+        };
+        let window_match = window.find(utc(22, 35), &[TIMEZONE]).unwrap();
+        assert_eq!(window_match.timezone, Tz::Asia__Kathmandu);
+        assert_eq!(window_match.end, utc(22, 36));
+
+        // The guild's own timezone comes first.
+        let window_match = window.find(at(4, 20, 0), &[TIMEZONE]).unwrap();
+        assert_eq!(window_match.timezone, TIMEZONE);
+    }
+
+    #[test]
+    fn tries_timezones_in_order() {
+        let window = WeedWindow::default();
+        let timezones = [TIMEZONE, Tz::Asia__Kathmandu];
+
+        let window_match = window.find(utc(22, 35), &timezones).unwrap();
+        assert_eq!(window_match.timezone, Tz::Asia__Kathmandu);
+        assert_eq!(window_match.end, utc(22, 36));
+        let window_match = window.find(at(16, 20, 0), &timezones).unwrap();
+        assert_eq!(window_match.timezone, TIMEZONE);
+        assert_eq!(window.find(utc(22, 35), &[TIMEZONE]), None);
+        assert_eq!(window.find(utc(22, 35), &[]), None);
+    }
+
+    #[test]
+    fn finds_half_and_quarter_hour_offsets() {
+        let window = WeedWindow {
+            anywhere: true,
+            ..WeedWindow::default()
+        };
+
+        for (timestamp, offset) in [
+            // India is 5:30 ahead.
+            (utc(22, 50), TimeDelta::minutes(5 * 60 + 30)),
+            // Newfoundland is 3:30 behind.
+            (utc(7, 50), TimeDelta::minutes(-(3 * 60 + 30))),
+            // Nepal is 5:45 ahead.
+            (utc(10, 35), TimeDelta::minutes(5 * 60 + 45)),
+            // Eucla is 8:45 ahead.
+            (utc(19, 35), TimeDelta::minutes(8 * 60 + 45)),
+            // The Chatham Islands are 13:45 ahead while they are on summer time.
+            (utc(14, 35), TimeDelta::minutes(13 * 60 + 45)),
+        ] {
+            let window_match = window.find(timestamp, &[TIMEZONE]).unwrap();
+            let local = timestamp.with_timezone(&window_match.timezone);
+            assert_eq!(
+                (local.hour12().1, local.minute()),
+                (4, 20),
+                "{timestamp} in {}",
+                window_match.timezone
+            );
+            assert_eq!(
+                local.naive_local() - timestamp.naive_utc(),
+                offset,
+                "{timestamp}"
+            );
+        }
+
+        // Every timezone is a whole number of quarter hours off, so none are at 4:20 at :07.
+        assert_eq!(window.find(utc(12, 7), &[TIMEZONE]), None);
+    }
+}